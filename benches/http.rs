@@ -0,0 +1,166 @@
+//! Round trips through `serve_connection` over in-memory streams, from
+//! handing over a fresh connection to reading the last byte of the
+//! response. Run with `cargo bench`.
+//!
+//! Criterion reports the time per request and requests per second; the
+//! p50 and p99 latencies of every request sent are printed after each
+//! group.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use socket::{
+    serve_connection, Connection, HTTPCodes, MiddlewareStack, Response, Router, ServerConfig,
+};
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::runtime::Runtime;
+
+const KEEP_ALIVE: &[u8] = b"GET / HTTP/1.1\r\nHost: bench\r\n\r\n";
+const CLOSE: &[u8] = b"GET / HTTP/1.1\r\nHost: bench\r\nConnection: close\r\n\r\n";
+
+struct App {
+    router: Arc<Router>,
+    middleware: MiddlewareStack,
+    config: Arc<ServerConfig>,
+}
+
+impl App {
+    fn new() -> App {
+        let mut router = Router::new();
+        router
+            .get("/", |_request| async {
+                Response::new(HTTPCodes::OK).body("Hello world")
+            })
+            .unwrap();
+        App {
+            router: Arc::new(router),
+            middleware: MiddlewareStack::new(),
+            // One connection carries every keep-alive request.
+            config: Arc::new(ServerConfig {
+                keep_alive_max: usize::MAX,
+                ..ServerConfig::default()
+            }),
+        }
+    }
+
+    /// Serves a new connection in its own task and returns the client end.
+    fn connect(&self) -> DuplexStream {
+        let (client, server) = duplex(64 * 1024);
+        let router = self.router.clone();
+        let middleware = self.middleware.clone();
+        let config = self.config.clone();
+        tokio::spawn(async move {
+            serve_connection(Connection::new(server), &router, &middleware, &config).await;
+        });
+        client
+    }
+}
+
+/// Reads one response with a `Content-Length` body.
+async fn read_response(client: &mut DuplexStream, buffer: &mut Vec<u8>) {
+    buffer.clear();
+    let mut chunk = [0; 4096];
+    loop {
+        let read = client.read(&mut chunk).await.unwrap();
+        assert!(read > 0, "connection closed mid-response");
+        buffer.extend_from_slice(&chunk[..read]);
+        let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") else {
+            continue;
+        };
+        let head = std::str::from_utf8(&buffer[..end]).unwrap();
+        let length: usize = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        if buffer.len() >= end + 4 + length {
+            return;
+        }
+    }
+}
+
+/// Requests recorded across every iteration of one benchmark.
+#[derive(Default)]
+struct Latencies(Mutex<Vec<Duration>>);
+
+impl Latencies {
+    fn record(&self, latencies: Vec<Duration>) {
+        self.0.lock().unwrap().extend(latencies);
+    }
+
+    fn report(&self, name: &str) {
+        let mut latencies = self.0.lock().unwrap();
+        latencies.sort();
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        println!(
+            "{name}: p50 {:?}, p99 {:?} over {} requests",
+            percentile(50),
+            percentile(99),
+            latencies.len()
+        );
+    }
+}
+
+fn keep_alive(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let app = App::new();
+    let latencies = Latencies::default();
+    let mut group = c.benchmark_group("round_trip");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("keep_alive", |b| {
+        b.to_async(&runtime).iter_custom(|iters| {
+            let mut client = app.connect();
+            let latencies = &latencies;
+            async move {
+                let mut buffer = Vec::new();
+                let mut recorded = Vec::with_capacity(iters as usize);
+                for _ in 0..iters {
+                    let started = Instant::now();
+                    client.write_all(KEEP_ALIVE).await.unwrap();
+                    read_response(&mut client, &mut buffer).await;
+                    recorded.push(started.elapsed());
+                }
+                let total = recorded.iter().sum();
+                latencies.record(recorded);
+                total
+            }
+        });
+    });
+    group.finish();
+    latencies.report("round_trip/keep_alive");
+}
+
+fn close(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let app = App::new();
+    let latencies = Latencies::default();
+    let mut group = c.benchmark_group("round_trip");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("close", |b| {
+        b.to_async(&runtime).iter_custom(|iters| {
+            let app = &app;
+            let latencies = &latencies;
+            async move {
+                let mut buffer = Vec::new();
+                let mut recorded = Vec::with_capacity(iters as usize);
+                for _ in 0..iters {
+                    let started = Instant::now();
+                    let mut client = app.connect();
+                    client.write_all(CLOSE).await.unwrap();
+                    read_response(&mut client, &mut buffer).await;
+                    recorded.push(started.elapsed());
+                }
+                let total = recorded.iter().sum();
+                latencies.record(recorded);
+                total
+            }
+        });
+    });
+    group.finish();
+    latencies.report("round_trip/close");
+}
+
+criterion_group!(benches, keep_alive, close);
+criterion_main!(benches);
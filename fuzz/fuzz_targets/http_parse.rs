@@ -0,0 +1,84 @@
+//! Feeds arbitrary bytes to a connection as if a client had sent them,
+//! then closes the client's write half. Whatever the input, serving it
+//! must end without a panic. Run with `cargo +nightly fuzz run http_parse`.
+
+#![no_main]
+
+use std::sync::{Arc, OnceLock};
+
+use libfuzzer_sys::fuzz_target;
+use socket::{
+    serve_connection, Connection, HTTPCodes, MiddlewareStack, Request, Response, Router,
+    ServerConfig,
+};
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::Runtime;
+
+struct App {
+    runtime: Runtime,
+    router: Arc<Router>,
+    middleware: MiddlewareStack,
+    config: ServerConfig,
+}
+
+fn app() -> &'static App {
+    static APP: OnceLock<App> = OnceLock::new();
+    APP.get_or_init(|| {
+        let mut router = Router::new();
+        router
+            .get("/", |_request| async {
+                Response::new(HTTPCodes::OK).body("ok")
+            })
+            .unwrap()
+            .post("/echo", |mut request: Request| async move {
+                match request.body.read_to_end().await {
+                    Ok(body) => Response::new(HTTPCodes::OK).body(body),
+                    Err(_) => Response::new(HTTPCodes::BadRequest),
+                }
+            })
+            .unwrap()
+            .get("/users/:id", |request: Request| async move {
+                Response::new(HTTPCodes::OK).body(request.params["id"].clone())
+            })
+            .unwrap();
+        App {
+            runtime: tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap(),
+            router: Arc::new(router),
+            middleware: MiddlewareStack::new(),
+            config: ServerConfig {
+                trace_enabled: true,
+                max_body_size: 64 * 1024,
+                ..ServerConfig::default()
+            },
+        }
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let app = app();
+    app.runtime.block_on(async {
+        let (mut client, server) = duplex(64 * 1024);
+        let serving = serve_connection(
+            Connection::new(server),
+            &app.router,
+            &app.middleware,
+            &app.config,
+        );
+        let sending = async move {
+            // Responses are drained alongside, so a full pipe cannot stall
+            // either side.
+            let (mut read, mut write) = tokio::io::split(&mut client);
+            let writing = async {
+                let _ = write.write_all(data).await;
+                let _ = write.shutdown().await;
+            };
+            let mut responses = Vec::new();
+            let reading = read.read_to_end(&mut responses);
+            let _ = tokio::join!(writing, reading);
+        };
+        tokio::join!(serving, sending);
+    });
+});
@@ -0,0 +1,40 @@
+use std::net::IpAddr;
+
+use ipnetwork::IpNetwork;
+
+/// Connection-level IP filter, checked before anything is read from the
+/// client.
+///
+/// `deny` always wins. An empty `allow` list admits every address that is
+/// not denied; otherwise only addresses inside one of its ranges are
+/// admitted.
+#[derive(Debug, Clone, Default)]
+pub struct AccessControl {
+    pub allow: Vec<IpNetwork>,
+    pub deny: Vec<IpNetwork>,
+}
+
+impl AccessControl {
+    pub fn new() -> AccessControl {
+        AccessControl::default()
+    }
+
+    pub fn allow(mut self, network: IpNetwork) -> Self {
+        self.allow.push(network);
+        self
+    }
+
+    pub fn deny(mut self, network: IpNetwork) -> Self {
+        self.deny.push(network);
+        self
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        // IPv4 clients on a dual-stack socket show up as `::ffff:a.b.c.d`.
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|network| network.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|network| network.contains(ip))
+    }
+}
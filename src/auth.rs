@@ -0,0 +1,89 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::codes::HTTPCodes;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::BoxFuture;
+
+/// Why a bearer token was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BearerError {
+    /// Malformed, revoked or otherwise not accepted.
+    Invalid,
+    /// Well-formed but past its expiry.
+    Expired,
+}
+
+impl BearerError {
+    fn description(self) -> &'static str {
+        match self {
+            BearerError::Invalid => "The access token is invalid",
+            BearerError::Expired => "The access token expired",
+        }
+    }
+}
+
+type Validator = dyn Fn(&str) -> Result<(), BearerError> + Send + Sync;
+
+/// Rejects requests without a valid `Authorization: Bearer` token with
+/// `401 Unauthorized`. A missing token gets a bare `Bearer` challenge; a
+/// rejected one gets `error="invalid_token"` together with an
+/// `error_description` telling expired tokens apart from invalid ones.
+#[derive(Clone)]
+pub struct BearerAuthMiddleware {
+    validate: Arc<Validator>,
+}
+
+impl BearerAuthMiddleware {
+    /// Accepts tokens for which `validate` returns `true`; any other token
+    /// is `BearerError::Invalid`.
+    pub fn new<F>(validate: F) -> BearerAuthMiddleware
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        BearerAuthMiddleware::with_errors(move |token| {
+            if validate(token) {
+                Ok(())
+            } else {
+                Err(BearerError::Invalid)
+            }
+        })
+    }
+
+    /// Like `new`, but `validate` says why a token was rejected.
+    pub fn with_errors<F>(validate: F) -> BearerAuthMiddleware
+    where
+        F: Fn(&str) -> Result<(), BearerError> + Send + Sync + 'static,
+    {
+        BearerAuthMiddleware {
+            validate: Arc::new(validate),
+        }
+    }
+}
+
+impl fmt::Debug for BearerAuthMiddleware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BearerAuthMiddleware")
+    }
+}
+
+fn unauthorized(challenge: &str) -> Response {
+    Response::new(HTTPCodes::Unauthorized).header("WWW-Authenticate", challenge)
+}
+
+impl Middleware for BearerAuthMiddleware {
+    fn handle(&self, request: Request, next: Next) -> BoxFuture<Response> {
+        let checked = request.bearer_token().map(|token| (self.validate)(token));
+        let response = match checked {
+            Some(Ok(())) => return Box::pin(next.run(request)),
+            None => unauthorized("Bearer"),
+            Some(Err(err)) => unauthorized(&format!(
+                "Bearer error=\"invalid_token\", error_description=\"{}\"",
+                err.description()
+            )),
+        };
+        Box::pin(async move { response })
+    }
+}
@@ -0,0 +1,406 @@
+use std::fmt;
+use std::mem;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use bytes::Bytes;
+use futures_core::Stream;
+
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+use tokio::sync::Mutex;
+use tokio::time::{timeout_at, Instant};
+
+use crate::connection::{read_line_within, MAX_HEAD_LINE};
+use crate::error::HTTPError;
+use crate::router::BoxFuture;
+use crate::server::ServerConfig;
+
+/// Buffered read half of a connection.
+pub(crate) type Reader = BufReader<Box<dyn AsyncRead + Send + Unpin>>;
+
+/// Upper bound for a single `read_chunk` result.
+const READ_SIZE: usize = 8192;
+
+/// How the end of the body is determined.
+pub(crate) enum Framing {
+    /// `Content-Length` body with this many bytes left.
+    Length(usize),
+    /// Chunked body, expecting a chunk size line next.
+    ChunkSize,
+    /// Chunked body, inside a chunk with this many bytes left.
+    ChunkData(usize),
+    Done,
+}
+
+/// Body still on the wire. Owns the connection's reader while the handler
+/// runs; the connection takes it back through `BodyHandle::finish`.
+pub(crate) struct Streaming {
+    reader: Option<Reader>,
+    framing: Framing,
+    deadline: Instant,
+    /// Bytes received so far, checked against `limit` so that chunked
+    /// bodies are bounded too.
+    received: usize,
+    limit: usize,
+    /// Bounds for the trailers of a chunked body: total bytes and lines,
+    /// from `max_head_size` and `max_header_count`.
+    trailer_size: usize,
+    trailer_count: usize,
+    exceeded: bool,
+}
+
+impl Streaming {
+    async fn next(&mut self) -> Result<Option<Vec<u8>>, HTTPError> {
+        if self.exceeded {
+            return Err(HTTPError::BodyTooLarge);
+        }
+        let Some(reader) = self.reader.as_mut() else {
+            return Ok(None);
+        };
+        let trailers = (self.trailer_size, self.trailer_count);
+        let piece = timeout_at(
+            self.deadline,
+            next_piece(reader, &mut self.framing, trailers),
+        )
+        .await
+        .map_err(|_| HTTPError::Timeout)
+        .and_then(|piece| piece);
+        // The framing can no longer be trusted, so the reader is given up
+        // and the connection closed rather than drained.
+        let piece = piece.inspect_err(|err| {
+            self.reader = None;
+            self.exceeded = matches!(err, HTTPError::BodyTooLarge);
+        })?;
+
+        if let Some(data) = &piece {
+            self.received += data.len();
+            if self.received > self.limit {
+                self.exceeded = true;
+                return Err(HTTPError::BodyTooLarge);
+            }
+        }
+        Ok(piece)
+    }
+}
+
+/// Reads the next piece of the body. Chunk size lines and terminators are
+/// bounded by `MAX_HEAD_LINE`, and the trailers by `(size, count)`; too
+/// many trailers fail with `BodyTooLarge`.
+async fn next_piece(
+    reader: &mut Reader,
+    framing: &mut Framing,
+    (mut trailer_size, mut trailer_count): (usize, usize),
+) -> Result<Option<Vec<u8>>, HTTPError> {
+    let chunk_error = || HTTPError::ParsingError {
+        header: "chunked body".to_string(),
+    };
+    loop {
+        match *framing {
+            Framing::Done | Framing::Length(0) => {
+                *framing = Framing::Done;
+                return Ok(None);
+            }
+            Framing::Length(remaining) => {
+                let data = read_some(reader, remaining).await?;
+                *framing = Framing::Length(remaining - data.len());
+                return Ok(Some(data));
+            }
+            Framing::ChunkSize => {
+                let line = read_chunk_line(reader).await?;
+                // Chunk extensions after `;` are ignored.
+                let size = line.split(';').next().unwrap_or_default().trim();
+                // `from_str_radix` would also take a leading `+`.
+                if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err(chunk_error());
+                }
+                let size = usize::from_str_radix(size, 16).map_err(|_| chunk_error())?;
+                if size > 0 {
+                    *framing = Framing::ChunkData(size);
+                    continue;
+                }
+                // Trailers are read and discarded.
+                loop {
+                    let line = read_line_within(reader, &mut trailer_size, MAX_HEAD_LINE)
+                        .await
+                        .map_err(|err| match err {
+                            HTTPError::HeadTooLarge => HTTPError::BodyTooLarge,
+                            err => err,
+                        })?
+                        .ok_or_else(chunk_error)?;
+                    if line.is_empty() {
+                        break;
+                    }
+                    if trailer_count == 0 {
+                        return Err(HTTPError::BodyTooLarge);
+                    }
+                    trailer_count -= 1;
+                }
+                *framing = Framing::Done;
+                return Ok(None);
+            }
+            Framing::ChunkData(remaining) => {
+                let data = read_some(reader, remaining).await?;
+                let left = remaining - data.len();
+                if left == 0 {
+                    if !read_chunk_line(reader).await?.is_empty() {
+                        return Err(chunk_error());
+                    }
+                    *framing = Framing::ChunkSize;
+                } else {
+                    *framing = Framing::ChunkData(left);
+                }
+                return Ok(Some(data));
+            }
+        }
+    }
+}
+
+/// A chunk size line or the empty line ending a chunk. One over
+/// `MAX_HEAD_LINE` is not a valid chunk line either way.
+async fn read_chunk_line(reader: &mut Reader) -> Result<String, HTTPError> {
+    let mut budget = MAX_HEAD_LINE;
+    match read_line_within(reader, &mut budget, MAX_HEAD_LINE).await {
+        Ok(Some(line)) => Ok(line),
+        Ok(None) | Err(HTTPError::HeadTooLarge) => Err(HTTPError::ParsingError {
+            header: "chunked body".to_string(),
+        }),
+        Err(err) => Err(err),
+    }
+}
+
+async fn read_some(reader: &mut Reader, max: usize) -> Result<Vec<u8>, HTTPError> {
+    let mut buffer = vec![0; max.min(READ_SIZE)];
+    let read = reader.read(&mut buffer).await?;
+    if read == 0 {
+        return Err(HTTPError::ParsingError {
+            header: "truncated body".to_string(),
+        });
+    }
+    buffer.truncate(read);
+    Ok(buffer)
+}
+
+/// Connection side of a streaming body.
+pub(crate) struct BodyHandle(Arc<Mutex<Streaming>>);
+
+impl BodyHandle {
+    /// Whether the body grew past the size limit while being read.
+    pub(crate) async fn exceeded(&self) -> bool {
+        self.0.lock().await.exceeded
+    }
+
+    /// Discards whatever the handler left unread and hands the reader back.
+    /// Returns `None` when the body could not be drained, in which case the
+    /// connection must be closed.
+    pub(crate) async fn finish(self) -> Option<Reader> {
+        let mut streaming = self.0.lock().await;
+        loop {
+            match streaming.next().await {
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(_) => return None,
+            }
+        }
+        streaming.reader.take()
+    }
+}
+
+enum Source {
+    Buffered(Vec<u8>),
+    Streaming(Arc<Mutex<Streaming>>),
+    #[cfg(feature = "http2")]
+    H2 {
+        stream: h2::RecvStream,
+        deadline: Instant,
+        received: usize,
+        limit: usize,
+    },
+}
+
+/// Request body. Bytes are only read from the connection when the handler
+/// asks for them, so large bodies are not buffered unless `read_to_end` is
+/// called.
+pub struct Body {
+    source: Source,
+}
+
+impl Body {
+    pub fn empty() -> Body {
+        Body::from(Vec::new())
+    }
+
+    pub(crate) fn streaming(
+        reader: Reader,
+        framing: Framing,
+        deadline: Instant,
+        config: &ServerConfig,
+    ) -> (Body, BodyHandle) {
+        let streaming = Arc::new(Mutex::new(Streaming {
+            reader: Some(reader),
+            framing,
+            deadline,
+            received: 0,
+            limit: config.max_body_size,
+            trailer_size: config.max_head_size,
+            trailer_count: config.max_header_count,
+            exceeded: false,
+        }));
+        let body = Body {
+            source: Source::Streaming(streaming.clone()),
+        };
+        (body, BodyHandle(streaming))
+    }
+
+    #[cfg(feature = "http2")]
+    pub(crate) fn h2(stream: h2::RecvStream, deadline: Instant, limit: usize) -> Body {
+        Body {
+            source: Source::H2 {
+                stream,
+                deadline,
+                received: 0,
+                limit,
+            },
+        }
+    }
+
+    /// A copy of a body held in memory, all of its bytes. A body still on
+    /// the connection can only be read once, so it gives `None`; see
+    /// `Request::buffered_clone`.
+    pub fn try_clone(&self) -> Option<Body> {
+        match &self.source {
+            Source::Buffered(data) => Some(Body::from(data.clone())),
+            _ => None,
+        }
+    }
+
+    /// Returns the next piece of the body as it arrives, or `None` once the
+    /// body is complete.
+    pub async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, HTTPError> {
+        match &mut self.source {
+            Source::Buffered(data) if data.is_empty() => Ok(None),
+            Source::Buffered(data) => Ok(Some(mem::take(data))),
+            Source::Streaming(streaming) => streaming.lock().await.next().await,
+            #[cfg(feature = "http2")]
+            Source::H2 {
+                stream,
+                deadline,
+                received,
+                limit,
+            } => {
+                let data = match timeout_at(*deadline, stream.data()).await {
+                    Err(_) => return Err(HTTPError::Timeout),
+                    Ok(None) => return Ok(None),
+                    Ok(Some(data)) => {
+                        data.map_err(|err| HTTPError::Io(std::io::Error::other(err)))?
+                    }
+                };
+                // Let the peer send more now that this frame is consumed.
+                let _ = stream.flow_control().release_capacity(data.len());
+                *received += data.len();
+                if *received > *limit {
+                    return Err(HTTPError::BodyTooLarge);
+                }
+                Ok(Some(data.to_vec()))
+            }
+        }
+    }
+
+    /// Takes the body, leaving an empty one, as a `Stream` of its pieces
+    /// as they arrive. Nothing is buffered beyond the piece being read.
+    pub fn stream(&mut self) -> BodyStream {
+        BodyStream {
+            body: Some(mem::replace(self, Body::empty())),
+            reading: None,
+        }
+    }
+
+    /// Reads the remaining body into memory, as `Bytes`.
+    pub async fn collect(&mut self) -> Result<Bytes, HTTPError> {
+        self.read_to_end().await.map(Bytes::from)
+    }
+
+    /// The bytes of a body held in memory; `None` while it is still on
+    /// the connection.
+    pub(crate) fn buffered(&self) -> Option<&[u8]> {
+        match &self.source {
+            Source::Buffered(data) => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Reads the remaining body into memory.
+    pub async fn read_to_end(&mut self) -> Result<Vec<u8>, HTTPError> {
+        let mut body = Vec::new();
+        while let Some(chunk) = self.read_chunk().await? {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(data: Vec<u8>) -> Body {
+        Body {
+            source: Source::Buffered(data),
+        }
+    }
+}
+
+/// A request body read piece by piece, from `Body::stream`. Ends after
+/// the last piece or the first error.
+pub struct BodyStream {
+    body: Option<Body>,
+    /// The body while its next piece is being read.
+    reading: Option<BoxFuture<(Body, Piece)>>,
+}
+
+/// Outcome of one `Body::read_chunk`.
+type Piece = Result<Option<Vec<u8>>, HTTPError>;
+
+impl Stream for BodyStream {
+    type Item = Result<Bytes, HTTPError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let reading = match &mut this.reading {
+            Some(reading) => reading,
+            None => {
+                let Some(mut body) = this.body.take() else {
+                    return Poll::Ready(None);
+                };
+                this.reading.insert(Box::pin(async move {
+                    let piece = body.read_chunk().await;
+                    (body, piece)
+                }))
+            }
+        };
+        let (body, piece) = ready!(reading.as_mut().poll(cx));
+        this.reading = None;
+        Poll::Ready(match piece {
+            Ok(Some(piece)) => {
+                this.body = Some(body);
+                Some(Ok(Bytes::from(piece)))
+            }
+            Ok(None) => None,
+            Err(err) => Some(Err(err)),
+        })
+    }
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BodyStream")
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            Source::Buffered(data) => write!(f, "Body({} bytes)", data.len()),
+            Source::Streaming(_) => f.write_str("Body(streaming)"),
+            #[cfg(feature = "http2")]
+            Source::H2 { .. } => f.write_str("Body(h2)"),
+        }
+    }
+}
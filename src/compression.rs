@@ -0,0 +1,202 @@
+use std::io::{self, Read, Write};
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+
+use crate::body::Body;
+use crate::error::HTTPError;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::BoxFuture;
+
+/// Compresses response bodies with gzip or deflate, whichever the client
+/// prefers in `Accept-Encoding`. Streamed bodies, bodies smaller than
+/// `threshold` and content that is already compressed are sent as-is.
+#[derive(Debug, Clone)]
+pub struct CompressionMiddleware {
+    /// Smallest body, in bytes, worth compressing.
+    pub threshold: usize,
+}
+
+impl Default for CompressionMiddleware {
+    fn default() -> Self {
+        CompressionMiddleware { threshold: 1024 }
+    }
+}
+
+impl CompressionMiddleware {
+    pub fn new() -> CompressionMiddleware {
+        CompressionMiddleware::default()
+    }
+
+    pub fn threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    fn compressible(&self, response: &Response) -> bool {
+        response.chunked.is_none()
+            && response.body.len() >= self.threshold
+            && !response.headers.contains("Content-Encoding")
+            && !response
+                .headers
+                .get("Content-Type")
+                .is_some_and(is_compressed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Encoding::Deflate => {
+                // HTTP's "deflate" is the zlib format.
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    fn decompress(self, data: &[u8], limit: usize) -> Result<Vec<u8>, HTTPError> {
+        let mut decoded = Vec::new();
+        // Reading one byte past the limit tells a body that is exactly
+        // `limit` long apart from one that is longer.
+        let read = match self {
+            Encoding::Gzip => GzDecoder::new(data)
+                .take(limit as u64 + 1)
+                .read_to_end(&mut decoded),
+            Encoding::Deflate => ZlibDecoder::new(data)
+                .take(limit as u64 + 1)
+                .read_to_end(&mut decoded),
+        };
+        read.map_err(|_| HTTPError::ParsingError {
+            header: "Content-Encoding".to_string(),
+        })?;
+        if decoded.len() > limit {
+            return Err(HTTPError::BodyTooLarge);
+        }
+        Ok(decoded)
+    }
+}
+
+/// Replaces a gzip or deflate encoded request body with the decoded bytes
+/// and drops `Content-Encoding`, so handlers always see the raw data.
+/// Other encodings are left for the handler. Malformed data is a
+/// `ParsingError`, and a body that decodes to more than `limit` bytes is
+/// `BodyTooLarge`.
+pub(crate) async fn decompress_request(
+    request: &mut Request,
+    limit: usize,
+) -> Result<(), HTTPError> {
+    let encoding = match request.headers.get("Content-Encoding").map(str::trim) {
+        Some(encoding) if encoding.eq_ignore_ascii_case("gzip") => Encoding::Gzip,
+        Some(encoding) if encoding.eq_ignore_ascii_case("x-gzip") => Encoding::Gzip,
+        Some(encoding) if encoding.eq_ignore_ascii_case("deflate") => Encoding::Deflate,
+        _ => return Ok(()),
+    };
+
+    let compressed = request.body.read_to_end().await?;
+    let decoded = encoding.decompress(&compressed, limit)?;
+    request.headers.remove("Content-Encoding");
+    request
+        .headers
+        .set("Content-Length", &decoded.len().to_string());
+    request.body = Body::from(decoded);
+    Ok(())
+}
+
+/// Picks the supported encoding with the highest `q` value in
+/// `accept_encoding`, as given by `Headers::with_quality`, preferring gzip
+/// on ties. `*` stands for any encoding not listed explicitly. `None`
+/// when neither is acceptable or `identity` is preferred over both.
+fn negotiate(accept_encoding: &[(String, f32)]) -> Option<Encoding> {
+    let quality = |names: &[&str]| {
+        accept_encoding
+            .iter()
+            .find(|(coding, _)| names.iter().any(|name| coding.eq_ignore_ascii_case(name)))
+            .map(|(_, quality)| *quality)
+    };
+    let any = quality(&["*"]);
+    let gzip = quality(&["gzip", "x-gzip"]).or(any).unwrap_or(0.0);
+    let deflate = quality(&["deflate"]).or(any).unwrap_or(0.0);
+    let identity = quality(&["identity"]).or(any).unwrap_or(0.0);
+
+    let (encoding, best) = if gzip >= deflate {
+        (Encoding::Gzip, gzip)
+    } else {
+        (Encoding::Deflate, deflate)
+    };
+    (best > 0.0 && best >= identity).then_some(encoding)
+}
+
+/// Formats that gain nothing from another round of compression.
+fn is_compressed(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let media = essence.split('/').next().unwrap_or_default();
+    match media {
+        "image" => essence != "image/svg+xml" && essence != "image/bmp",
+        "audio" | "video" => true,
+        "font" => essence == "font/woff" || essence == "font/woff2",
+        _ => matches!(
+            essence.as_str(),
+            "application/zip"
+                | "application/gzip"
+                | "application/x-gzip"
+                | "application/x-bzip2"
+                | "application/x-7z-compressed"
+                | "application/x-rar-compressed"
+                | "application/zstd"
+                | "application/pdf"
+                | "application/wasm"
+        ),
+    }
+}
+
+impl Middleware for CompressionMiddleware {
+    fn handle(&self, request: Request, next: Next) -> BoxFuture<Response> {
+        let encoding = negotiate(&request.headers.with_quality("Accept-Encoding"));
+        let config = self.clone();
+        Box::pin(async move {
+            let mut response = next.run(request).await;
+            if !config.compressible(&response) {
+                return response;
+            }
+
+            // The body now depends on the request's Accept-Encoding.
+            response.vary(&["Accept-Encoding"]);
+
+            if let Some(encoding) = encoding {
+                if let Ok(compressed) = encoding.compress(&response.body) {
+                    response.body = compressed;
+                    response.headers.set("Content-Encoding", encoding.name());
+                }
+            }
+            response
+        })
+    }
+}
@@ -0,0 +1,775 @@
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::sync::watch;
+use tokio::time::{timeout, timeout_at, Instant};
+use tracing::{field, info_span, warn, Instrument, Span};
+
+use crate::body::{Body, Framing, Reader};
+use crate::codes::HTTPCodes;
+#[cfg(feature = "compression")]
+use crate::compression::decompress_request;
+use crate::error::HTTPError;
+use crate::etag::{conditional, Preconditions};
+use crate::expect::{check_expectation, Expectation};
+use crate::headers::Headers;
+use crate::method::HttpMethod;
+use crate::metrics::Metrics;
+use crate::middleware::MiddlewareStack;
+use crate::pipeline::serve_pipelined;
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::Router;
+use crate::server::ServerConfig;
+use crate::tunnel::connect_tunnel;
+
+/// A client connection split into its buffered read half and its write
+/// half. While a handler runs, the read half is lent to the request body.
+pub struct Connection {
+    pub(crate) reader: Option<Reader>,
+    pub(crate) writer: Box<dyn AsyncWrite + Send + Unpin>,
+    /// Flips to `true` when the server starts shutting down.
+    pub(crate) shutdown: Option<watch::Receiver<bool>>,
+    pub(crate) peer_addr: Option<SocketAddr>,
+    /// Client reported by a proxy in front; falls back to `peer_addr`.
+    pub(crate) remote_addr: Option<SocketAddr>,
+    /// Whether the stream is a TLS session.
+    pub(crate) tls: bool,
+    pub(crate) metrics: Option<Arc<dyn Metrics>>,
+    /// Requests handled so far, checked against `keep_alive.keepalive_max`.
+    served: usize,
+    /// Set from the server config when the first request arrives.
+    keep_alive: Option<KeepAlivePolicy>,
+    /// The last request line was malformed; the rest of its head is still
+    /// unread, see `resync`.
+    bad_request_line: bool,
+}
+
+impl Connection {
+    /// Wraps any byte stream, e.g. a `TcpStream` or a TLS stream over one.
+    pub fn new<S>(stream: S) -> Connection
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (read, write) = tokio::io::split(stream);
+        let read: Box<dyn AsyncRead + Send + Unpin> = Box::new(read);
+        Connection {
+            reader: Some(BufReader::new(read)),
+            writer: Box::new(write),
+            shutdown: None,
+            peer_addr: None,
+            remote_addr: None,
+            tls: false,
+            metrics: None,
+            served: 0,
+            bad_request_line: false,
+            keep_alive: None,
+        }
+    }
+
+    /// Records the client's address, exposed as `Request::peer_addr` and,
+    /// unless `with_remote_addr` says otherwise, `Request::remote_addr`.
+    pub fn with_peer_addr(mut self, peer_addr: SocketAddr) -> Connection {
+        self.peer_addr = Some(peer_addr);
+        self
+    }
+
+    /// Records the originating client reported by a proxy, exposed as
+    /// `Request::remote_addr`.
+    pub(crate) fn with_remote_addr(mut self, remote_addr: SocketAddr) -> Connection {
+        self.remote_addr = Some(remote_addr);
+        self
+    }
+
+    /// Makes the connection close after the current response once
+    /// `shutdown` turns `true`.
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Connection {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Marks the connection as running over TLS, so its requests report
+    /// the `https` scheme.
+    pub fn with_tls(mut self) -> Connection {
+        self.tls = true;
+        self
+    }
+
+    /// Reports this connection and its requests to `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Connection {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    fn shutting_down(&self) -> bool {
+        shutting_down(self.shutdown.as_ref())
+    }
+
+    async fn wait_for_request(&mut self, idle: Duration) -> bool {
+        match self.reader.as_mut() {
+            Some(reader) => wait_for_request(reader, self.shutdown.as_mut(), idle).await,
+            None => false,
+        }
+    }
+}
+
+pub(crate) fn shutting_down(shutdown: Option<&watch::Receiver<bool>>) -> bool {
+    shutdown.is_some_and(|shutdown| *shutdown.borrow())
+}
+
+/// Waits up to `idle` for the next request to start arriving.
+pub(crate) async fn wait_for_request(
+    reader: &mut Reader,
+    shutdown: Option<&mut watch::Receiver<bool>>,
+    idle: Duration,
+) -> bool {
+    tokio::select! {
+        ready = timeout(idle, reader.fill_buf()) => {
+            matches!(ready, Ok(Ok(buffer)) if !buffer.is_empty())
+        }
+        () = shutdown_signalled(shutdown) => false,
+    }
+}
+
+/// Keep-alive limits for one connection: the server's configuration,
+/// tightened by whatever the client asked for in its `Keep-Alive` header.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct KeepAlivePolicy {
+    /// How long the connection may sit idle between requests.
+    pub(crate) keepalive_timeout: Duration,
+    /// Most requests served on the connection.
+    pub(crate) keepalive_max: usize,
+}
+
+impl KeepAlivePolicy {
+    pub(crate) fn new(config: &ServerConfig) -> KeepAlivePolicy {
+        KeepAlivePolicy {
+            keepalive_timeout: config.keep_alive_timeout.unwrap_or(config.read_timeout),
+            keepalive_max: config.keep_alive_max,
+        }
+    }
+
+    /// Applies the `timeout=` (seconds) and `max=` directives of a client's
+    /// `Keep-Alive` header. Limits only ever shrink; unknown or malformed
+    /// directives are ignored.
+    pub(crate) fn restrict(&mut self, headers: &Headers) {
+        for directive in headers.get_all("Keep-Alive") {
+            let Some((name, value)) = directive.split_once('=') else {
+                continue;
+            };
+            let Ok(value) = value.trim().parse::<u64>() else {
+                continue;
+            };
+            match name.trim().to_ascii_lowercase().as_str() {
+                "timeout" => {
+                    self.keepalive_timeout = self.keepalive_timeout.min(Duration::from_secs(value));
+                }
+                "max" => {
+                    let max = usize::try_from(value).unwrap_or(usize::MAX);
+                    self.keepalive_max = self.keepalive_max.min(max);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Resolves once shutdown has been signalled; never if it cannot be.
+async fn shutdown_signalled(shutdown: Option<&mut watch::Receiver<bool>>) {
+    if let Some(shutdown) = shutdown {
+        if shutdown.wait_for(|stop| *stop).await.is_ok() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+/// Serves requests on `connection` until either side closes it, it sits
+/// idle for longer than `keep_alive_timeout` or the server shuts down.
+/// A malformed request line after a good request is answered with `400`
+/// and skipped, see `resync`; any other unreadable request closes the
+/// connection.
+pub async fn serve_connection(
+    mut connection: Connection,
+    router: &Arc<Router>,
+    middleware: &MiddlewareStack,
+    config: &ServerConfig,
+) {
+    if let Some(metrics) = &connection.metrics {
+        metrics.on_connection_open();
+    }
+    if config.pipeline_depth > 1 {
+        serve_pipelined(&mut connection, router, middleware, config).await;
+        if let Some(metrics) = &connection.metrics {
+            metrics.on_connection_close();
+        }
+        return;
+    }
+    // The first request gets the full read timeout to start arriving.
+    let mut idle = config.read_timeout;
+    // Requests served when the connection last recovered from a malformed
+    // request line; recovering again takes a good request in between.
+    let mut resynced_at = None;
+    while connection.wait_for_request(idle).await {
+        match handle_connection(&mut connection, router, middleware, config).await {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err)
+                if connection.bad_request_line
+                    && connection.served > 0
+                    && resynced_at != Some(connection.served) =>
+            {
+                if !resync(&mut connection, config, &err).await {
+                    break;
+                }
+                resynced_at = Some(connection.served);
+            }
+            Err(err) => {
+                reject(&mut connection, config, &err).await;
+                break;
+            }
+        }
+        idle = connection
+            .keep_alive
+            .unwrap_or_else(|| KeepAlivePolicy::new(config))
+            .keepalive_timeout;
+    }
+    if let Some(metrics) = &connection.metrics {
+        metrics.on_connection_close();
+    }
+}
+
+/// Reads a single request from `connection`, dispatches it through
+/// `middleware` and `router` and writes the response. Returns `true` when
+/// the connection should be kept open for another request, and the error
+/// when the request could not be read or its response not written; the
+/// caller answers it with `reject` and closes the connection.
+///
+/// Runs inside a `handle_connection` span carrying the peer address, the
+/// request line, the status and the total time in microseconds, with child
+/// spans for reading the head, running the handler, writing the response
+/// and draining the body.
+pub async fn handle_connection(
+    connection: &mut Connection,
+    router: &Arc<Router>,
+    middleware: &MiddlewareStack,
+    config: &ServerConfig,
+) -> Result<bool, HTTPError> {
+    let span = request_span(connection.peer_addr);
+    timed(span, handle_request(connection, router, middleware, config)).await
+}
+
+/// The `handle_connection` span one request runs in.
+pub(crate) fn request_span(peer_addr: Option<SocketAddr>) -> Span {
+    let peer_addr = peer_addr.map_or_else(|| "unknown".to_string(), |addr| addr.to_string());
+    info_span!(
+        "handle_connection",
+        peer_addr = %peer_addr,
+        method = field::Empty,
+        path = field::Empty,
+        request_id = field::Empty,
+        status = field::Empty,
+        elapsed_us = field::Empty,
+    )
+}
+
+async fn handle_request(
+    connection: &mut Connection,
+    router: &Arc<Router>,
+    middleware: &MiddlewareStack,
+    config: &ServerConfig,
+) -> Result<bool, HTTPError> {
+    let Some(mut reader) = connection.reader.take() else {
+        return Ok(false);
+    };
+
+    // Headers and body share one deadline.
+    let deadline = Instant::now() + config.read_timeout;
+    let mut bad_request_line = false;
+    let head = timed(
+        info_span!("read_head", elapsed_us = field::Empty),
+        timeout_at(
+            deadline,
+            read_head(&mut reader, config, &mut bad_request_line),
+        ),
+    )
+    .await
+    .unwrap_or(Err(HTTPError::Timeout));
+    connection.bad_request_line = bad_request_line && head.is_err();
+    if connection.bad_request_line {
+        connection.reader = Some(reader);
+        return head.map(|_| false);
+    }
+    // Client closed the connection between requests.
+    let Some((mut request, framing, trace)) = head? else {
+        return Ok(false);
+    };
+    Span::current()
+        .record("method", request.method.as_str())
+        .record("path", request.path.as_str());
+
+    let started = Instant::now();
+    let method = request.method.clone();
+    if let Some(metrics) = &connection.metrics {
+        metrics.on_request_start(&method);
+    }
+
+    let policy = connection
+        .keep_alive
+        .get_or_insert_with(|| KeepAlivePolicy::new(config));
+    policy.restrict(&request.headers);
+    let policy = *policy;
+
+    request.peer_addr = connection.peer_addr;
+    request.remote_addr = connection.remote_addr.or(connection.peer_addr);
+    request.tls = connection.tls;
+    request.trust_forwarded_proto = config.trust_forwarded_proto;
+    request.redacted_headers = config.redacted_headers.clone();
+    request.span = Span::current();
+    match check_expectation(&request, &framing, config) {
+        Expectation::None => {}
+        Expectation::Continue => {
+            timeout(
+                config.write_timeout,
+                connection
+                    .writer
+                    .write_all(b"HTTP/1.1 100 Continue\r\n\r\n"),
+            )
+            .await
+            .map_err(io::Error::from)??;
+        }
+        // The body was never asked for, so it cannot be drained reliably
+        // and the connection is closed after the response.
+        Expectation::Reject(response) => {
+            connection.served += 1;
+            let mut response = (*response).keep_alive(false);
+            response.omit_body = method == "HEAD";
+            Span::current().record("status", response.code.code());
+            let sent = write_response(&mut connection.writer, &mut response, config).await;
+            if let (Some(metrics), Ok(written)) = (&connection.metrics, &sent) {
+                metrics.on_request_end(&method, response.code.code(), started.elapsed(), *written);
+            }
+            sent?;
+            return Ok(false);
+        }
+    }
+    let (body, body_handle) = Body::streaming(reader, framing, deadline, config);
+    request.body = body;
+
+    let keep_alive = request.keep_alive;
+    let preconditions = Preconditions::new(&request.headers);
+    let handler = async {
+        // TRACE is answered here, before any middleware or routes.
+        if let Some(trace) = trace {
+            return trace_response(config, router, &request.path, trace);
+        }
+        if request.method_enum() == HttpMethod::Connect {
+            let targets = &config.connect_targets;
+            return connect_tunnel(&request.path, targets, config.read_timeout).await;
+        }
+        match decode_body(&mut request, config).await {
+            Ok(()) => middleware.run(router.clone(), request).await,
+            Err(err) => error_response(config, &err),
+        }
+    };
+    let mut response = timed(info_span!("handler", elapsed_us = field::Empty), handler).await;
+    if body_handle.exceeded().await {
+        response = error_response(config, &HTTPError::BodyTooLarge);
+    }
+    response = conditional(&method, &preconditions, response);
+    response.omit_body = method == "HEAD";
+    Span::current().record("status", response.code.code());
+
+    connection.served += 1;
+    let remaining = policy.keepalive_max.saturating_sub(connection.served);
+    response.keep_alive &= keep_alive && remaining > 0 && !connection.shutting_down();
+    set_keep_alive_header(&mut response, &policy, remaining);
+    let sent = timed(
+        info_span!("write_response", elapsed_us = field::Empty),
+        write_response(&mut connection.writer, &mut response, config),
+    )
+    .await;
+    if let (Some(metrics), Ok(written)) = (&connection.metrics, &sent) {
+        metrics.on_request_end(&method, response.code.code(), started.elapsed(), *written);
+    }
+    sent?;
+
+    // Whatever the handler left unread must be consumed before the next
+    // request can be parsed.
+    connection.reader = timed(
+        info_span!("drain_body", elapsed_us = field::Empty),
+        body_handle.finish(),
+    )
+    .await;
+    if let Some(upgrade) = response.upgrade.take() {
+        // The connection now belongs to the new protocol and is closed
+        // when its session ends.
+        if let Some(reader) = connection.reader.take() {
+            let writer = mem::replace(&mut connection.writer, Box::new(tokio::io::sink()));
+            upgrade.run(reader, writer, config.max_body_size).await;
+        }
+        return Ok(false);
+    }
+    Ok(response.keep_alive && connection.reader.is_some())
+}
+
+/// Answers a request that `handle_connection` failed on with the
+/// configured error response. After an I/O error the connection is broken,
+/// possibly halfway through a response, so nothing more is written to it.
+pub(crate) async fn reject(connection: &mut Connection, config: &ServerConfig, err: &HTTPError) {
+    warn!(peer_addr = ?connection.peer_addr, error = %err, "rejected request");
+    if matches!(err, HTTPError::Io(_)) {
+        return;
+    }
+    let mut response = error_response(config, err);
+    let _ = write_response(&mut connection.writer, &mut response, config).await;
+}
+
+/// Most bytes `resync` skips looking for the end of a malformed head.
+const RESYNC_LIMIT: usize = 64 * 1024;
+
+/// Recovers a persistent connection from a malformed request line: answers
+/// `err` without closing the connection, then skips the rest of the bad
+/// request's head, see `skip_head`, so the next request can be read.
+/// Returns `false` when the connection has to be closed instead.
+pub(crate) async fn resync(
+    connection: &mut Connection,
+    config: &ServerConfig,
+    err: &HTTPError,
+) -> bool {
+    warn!(peer_addr = ?connection.peer_addr, error = %err, "skipping malformed request");
+    connection.bad_request_line = false;
+    let mut response = config.error_handler.handle(err);
+    if write_response(&mut connection.writer, &mut response, config)
+        .await
+        .is_err()
+        || !response.keep_alive
+    {
+        return false;
+    }
+    match connection.reader.as_mut() {
+        Some(reader) => skip_head(reader, config).await,
+        None => false,
+    }
+}
+
+/// Skips the rest of a head whose request line was malformed, up to the
+/// next blank line. Returns `false` when the head does not end within the
+/// read timeout and `RESYNC_LIMIT` bytes.
+///
+/// Any body the bad request carried cannot be told apart from the next
+/// request; it fails to parse in turn and closes the connection.
+pub(crate) async fn skip_head(reader: &mut Reader, config: &ServerConfig) -> bool {
+    let skip = async {
+        let mut skipped = 0;
+        let mut line = Vec::new();
+        while skipped < RESYNC_LIMIT {
+            line.clear();
+            let read = (&mut *reader)
+                .take((RESYNC_LIMIT - skipped) as u64)
+                .read_until(b'\n', &mut line)
+                .await?;
+            if read == 0 {
+                break;
+            }
+            skipped += read;
+            if line == b"\r\n" || line == b"\n" {
+                return Ok(true);
+            }
+        }
+        Ok::<_, io::Error>(false)
+    };
+    matches!(timeout(config.read_timeout, skip).await, Ok(Ok(true)))
+}
+
+/// Decodes a gzip or deflate encoded request body, see
+/// `decompress_request`.
+#[cfg(feature = "compression")]
+pub(crate) async fn decode_body(
+    request: &mut Request,
+    config: &ServerConfig,
+) -> Result<(), HTTPError> {
+    decompress_request(request, config.max_body_size).await
+}
+
+/// Without compression support, encoded bodies reach the handler as-is.
+#[cfg(not(feature = "compression"))]
+pub(crate) async fn decode_body(
+    _request: &mut Request,
+    _config: &ServerConfig,
+) -> Result<(), HTTPError> {
+    Ok(())
+}
+
+/// Echo of a `TRACE` request's head, or `405 Method Not Allowed` when
+/// tracing is disabled, with an `Allow` header listing the methods
+/// `router` has for `path`.
+pub(crate) fn trace_response(
+    config: &ServerConfig,
+    router: &Router,
+    path: &str,
+    trace: String,
+) -> Response {
+    if !config.trace_enabled {
+        return Response::new(HTTPCodes::MethodNotAllowed)
+            .header("Allow", &router.allowed_for(path));
+    }
+    Response::new(HTTPCodes::OK)
+        .header("Content-Type", "message/http")
+        .body(trace)
+}
+
+/// Advertises the keep-alive limits on responses that leave the connection
+/// open, with `remaining` requests left on it.
+pub(crate) fn set_keep_alive_header(
+    response: &mut Response,
+    policy: &KeepAlivePolicy,
+    remaining: usize,
+) {
+    if response.keep_alive && response.upgrade.is_none() {
+        response.headers.set(
+            "Keep-Alive",
+            &format!(
+                "timeout={}, max={remaining}",
+                policy.keepalive_timeout.as_secs()
+            ),
+        );
+    } else {
+        response.headers.remove("Keep-Alive");
+    }
+}
+
+/// Runs `future` inside `span` and records how long it took as the span's
+/// `elapsed_us` field.
+pub(crate) async fn timed<F: Future>(span: Span, future: F) -> F::Output {
+    let start = Instant::now();
+    let output = future.instrument(span.clone()).await;
+    span.record("elapsed_us", start.elapsed().as_micros() as u64);
+    output
+}
+
+/// Response sent when a request is rejected before or while reading it,
+/// built by the configured `ErrorHandler`. The connection is always closed
+/// afterwards.
+pub(crate) fn error_response(config: &ServerConfig, err: &HTTPError) -> Response {
+    config.error_handler.handle(err).keep_alive(false)
+}
+
+/// Writes `response` and returns how many bytes went out.
+pub(crate) async fn write_response<W>(
+    writer: &mut W,
+    response: &mut Response,
+    config: &ServerConfig,
+) -> io::Result<usize>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = CountingWriter {
+        inner: writer,
+        written: 0,
+    };
+    // A streamed body may legitimately take longer than `write_timeout`.
+    if response.is_chunked() {
+        response.write_to(&mut writer).await?;
+    } else {
+        timeout(config.write_timeout, response.write_to(&mut writer))
+            .await
+            .unwrap_or_else(|elapsed| Err(elapsed.into()))?;
+    }
+    Ok(writer.written)
+}
+
+/// Passes writes through to `inner`, counting the bytes accepted.
+struct CountingWriter<'a, W> {
+    inner: &'a mut W,
+    written: usize,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut *this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            this.written += written;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Reads a line of any length; only tests read without a limit.
+#[cfg(test)]
+pub(crate) async fn read_line<R>(reader: &mut R) -> Result<Option<String>, HTTPError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut unlimited = usize::MAX;
+    read_line_within(reader, &mut unlimited, usize::MAX).await
+}
+
+/// Longest line accepted in a request head.
+pub(crate) const MAX_HEAD_LINE: usize = 16 * 1024;
+
+/// Reads a line of at most `max` bytes, counted against `budget`. Fails
+/// with `HeadTooLarge` rather than buffering past either.
+pub(crate) async fn read_line_within<R>(
+    reader: &mut R,
+    budget: &mut usize,
+    max: usize,
+) -> Result<Option<String>, HTTPError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let limit = max.min(*budget);
+    let mut line = Vec::new();
+    let read = (&mut *reader)
+        .take(limit as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    *budget -= read;
+    if read == limit && !line.ends_with(b"\n") {
+        return Err(HTTPError::HeadTooLarge);
+    }
+    if read == 0 {
+        return Ok(None);
+    }
+    let line = String::from_utf8(line).map_err(|_| HTTPError::ParsingError {
+        header: "non UTF-8 line".to_string(),
+    })?;
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// Checks whether the comma separated `Connection` header lists `token`.
+pub(crate) fn connection_has(headers: &Headers, token: &str) -> bool {
+    headers
+        .get_all("Connection")
+        .iter()
+        .any(|option| option.eq_ignore_ascii_case(token))
+}
+
+/// Header fields left out of a `TRACE` echo because they carry
+/// credentials.
+const UNTRACED_HEADERS: [&str; 3] = ["authorization", "cookie", "proxy-authorization"];
+
+/// Reads the request line and headers. The body is left on the wire and
+/// described by the returned `Framing`. For `TRACE` requests the head is
+/// also returned as received, minus credentials, to be echoed back.
+/// `bad_request_line` is set when it fails on the request line, with the
+/// headers after it still unread.
+pub(crate) async fn read_head(
+    reader: &mut Reader,
+    config: &ServerConfig,
+    bad_request_line: &mut bool,
+) -> Result<Option<(Request, Framing, Option<String>)>, HTTPError> {
+    // Every line, up to the blank one ending the head, counts against
+    // `max_head_size`.
+    let mut budget = config.max_head_size;
+    // Empty lines before the request line, e.g. a stray CRLF after the
+    // previous request's body, are ignored (RFC 9112, section 2.2).
+    let request_line = loop {
+        match read_line_within(reader, &mut budget, MAX_HEAD_LINE).await? {
+            Some(line) if line.is_empty() => {}
+            Some(line) => break line,
+            None => return Ok(None),
+        }
+    };
+
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        *bad_request_line = true;
+        return Err(HTTPError::ParsingError {
+            header: request_line,
+        });
+    };
+
+    let mut trace = (method == "TRACE").then(|| format!("{request_line}\r\n"));
+    let mut headers = Headers::new();
+    let mut count = 0;
+    loop {
+        let line = read_line_within(reader, &mut budget, MAX_HEAD_LINE)
+            .await?
+            .ok_or_else(|| HTTPError::ParsingError {
+                header: "unterminated headers".to_string(),
+            })?;
+        if line.is_empty() {
+            break;
+        }
+        // Past the limit the rest of the head is read, still within
+        // `max_head_size`, but not kept, so the `431` goes out once the
+        // client has finished sending it.
+        count += 1;
+        if count > config.max_header_count {
+            continue;
+        }
+        headers.parse_line(&line)?;
+        if let Some(trace) = &mut trace {
+            let name = line.split(':').next().unwrap_or_default().trim();
+            if !UNTRACED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+                trace.push_str(&line);
+                trace.push_str("\r\n");
+            }
+        }
+    }
+
+    if count > config.max_header_count {
+        return Err(HTTPError::TooManyHeaders);
+    }
+
+    // HTTP/1.1 connections are persistent unless the client opts out,
+    // HTTP/1.0 ones close unless the client opts in.
+    let keep_alive = if version == "HTTP/1.0" {
+        connection_has(&headers, "keep-alive")
+    } else {
+        !connection_has(&headers, "close")
+    };
+
+    // Transfer-Encoding takes precedence over Content-Length.
+    let framing = if let Some(encoding) = headers.get("Transfer-Encoding") {
+        let chunked = encoding
+            .rsplit(',')
+            .next()
+            .is_some_and(|last| last.trim().eq_ignore_ascii_case("chunked"));
+        if !chunked {
+            return Err(HTTPError::ParsingError {
+                header: "Transfer-Encoding".to_string(),
+            });
+        }
+        Framing::ChunkSize
+    } else if let Some(length) = headers.get("Content-Length") {
+        let length: usize = length.parse().map_err(|_| HTTPError::ParsingError {
+            header: "Content-Length".to_string(),
+        })?;
+        if length > config.max_body_size {
+            return Err(HTTPError::BodyTooLarge);
+        }
+        Framing::Length(length)
+    } else {
+        Framing::Done
+    };
+
+    let mut request = Request::from_head(method, target, version, headers)?;
+    request.keep_alive = keep_alive;
+    Ok(Some((request, framing, trace)))
+}
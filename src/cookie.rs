@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use crate::error::HTTPError;
+use crate::headers::{is_token_char, is_valid_value};
+
+/// Parses a `Cookie: name=value; other=val` request header. Pairs without
+/// `=` are skipped; surrounding double quotes are stripped from values.
+pub fn parse_cookies(header: &str) -> HashMap<String, String> {
+    header
+        .split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            (name.trim().to_string(), value.to_string())
+        })
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+/// A cookie to be sent with `Set-Cookie`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    pub max_age: Option<Duration>,
+    pub http_only: bool,
+    pub secure: bool,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Cookie {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            http_only: false,
+            secure: false,
+        }
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Checks that the cookie serializes to a single `Set-Cookie` line
+    /// without extra attributes: the name must be a token, and the value,
+    /// path and domain must not contain `;`, `\r`, `\n` or NUL.
+    pub fn validate(&self) -> Result<(), HTTPError> {
+        let invalid = || HTTPError::InvalidHeaderValue {
+            name: "set-cookie".to_string(),
+        };
+        if self.name.is_empty() || !self.name.bytes().all(is_token_char) {
+            return Err(invalid());
+        }
+        let attributes = [Some(&self.value), self.path.as_ref(), self.domain.as_ref()];
+        for value in attributes.into_iter().flatten() {
+            if value.contains(';') || !is_valid_value(value) {
+                return Err(invalid());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats the cookie as a `Set-Cookie` header value.
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={path}")?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={domain}")?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use std::time::Duration;
+
+use crate::codes::HTTPCodes;
+use crate::method::HttpMethod;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::BoxFuture;
+
+/// Cross-origin resource sharing policy, usable directly as middleware.
+/// Answers preflight `OPTIONS` requests itself and adds the
+/// `Access-Control-*` headers to every other response for allowed origins.
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    /// Origins allowed to make requests; `"*"` allows any origin, but only
+    /// without credentials. With `allow_credentials` only the origins
+    /// listed by name are allowed.
+    pub allowed_origins: Vec<String>,
+    /// Methods a preflight may ask for, matched case-sensitively.
+    pub allowed_methods: Vec<String>,
+    /// Request headers allowed in preflight. Empty echoes whatever the
+    /// client asked for.
+    pub allowed_headers: Vec<String>,
+    pub max_age: Option<Duration>,
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            allowed_methods: ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"]
+                .map(String::from)
+                .to_vec(),
+            allowed_headers: Vec::new(),
+            max_age: None,
+            allow_credentials: false,
+        }
+    }
+}
+
+impl CorsConfig {
+    /// Whether any origin is allowed, answered with the `*` wildcard.
+    /// Credentialed requests never get the wildcard, and reflecting any
+    /// origin with credentials would let every site read the responses.
+    fn allows_any(&self) -> bool {
+        !self.allow_credentials && self.allowed_origins.iter().any(|allowed| allowed == "*")
+    }
+
+    /// Value for `Access-Control-Allow-Origin`, or `None` if `origin` is
+    /// not allowed.
+    fn allow_origin(&self, origin: &str) -> Option<String> {
+        if self.allows_any() {
+            Some("*".to_string())
+        } else if self.allowed_origins.iter().any(|allowed| allowed == origin) {
+            Some(origin.to_string())
+        } else {
+            None
+        }
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        self.allowed_methods.iter().any(|allowed| allowed == method)
+    }
+
+    fn apply(&self, response: &mut Response, allow_origin: &str) {
+        response
+            .headers
+            .set("Access-Control-Allow-Origin", allow_origin);
+        if self.allow_credentials {
+            response
+                .headers
+                .set("Access-Control-Allow-Credentials", "true");
+        }
+    }
+
+    fn preflight(&self, request: &Request, allow_origin: &str) -> Response {
+        let mut response = Response::new(HTTPCodes::NoContent);
+        self.apply(&mut response, allow_origin);
+        response.headers.add(
+            "Access-Control-Allow-Methods",
+            &self.allowed_methods.join(", "),
+        );
+
+        let allowed_headers = if self.allowed_headers.is_empty() {
+            request
+                .headers
+                .get("Access-Control-Request-Headers")
+                .map(str::to_string)
+        } else {
+            Some(self.allowed_headers.join(", "))
+        };
+        if let Some(allowed_headers) = allowed_headers {
+            response
+                .headers
+                .add("Access-Control-Allow-Headers", &allowed_headers);
+        }
+        if let Some(max_age) = self.max_age {
+            response
+                .headers
+                .add("Access-Control-Max-Age", &max_age.as_secs().to_string());
+        }
+        response
+    }
+}
+
+impl Middleware for CorsConfig {
+    fn handle(&self, request: Request, next: Next) -> BoxFuture<Response> {
+        // Unless every origin gets the same answer, caches must keep
+        // responses apart by `Origin`, including those that were refused
+        // CORS headers.
+        let vary = !self.allows_any();
+        let allow_origin = request
+            .headers
+            .get("Origin")
+            .and_then(|origin| self.allow_origin(origin));
+
+        let requested_method = request.headers.get("Access-Control-Request-Method");
+        if let (true, Some(allow_origin), Some(requested_method)) = (
+            request.method_enum() == HttpMethod::Options,
+            &allow_origin,
+            requested_method,
+        ) {
+            // A refused preflight gets no CORS headers, so the browser does
+            // not send the actual request.
+            let mut response = if self.allows_method(requested_method) {
+                self.preflight(&request, allow_origin)
+            } else {
+                Response::new(HTTPCodes::NoContent)
+            };
+            if vary {
+                response.vary(&["Origin"]);
+            }
+            return Box::pin(async move { response });
+        }
+
+        let config = self.clone();
+        Box::pin(async move {
+            let mut response = next.run(request).await;
+            if let Some(allow_origin) = &allow_origin {
+                config.apply(&mut response, allow_origin);
+            }
+            if vary {
+                response.vary(&["Origin"]);
+            }
+            response
+        })
+    }
+}
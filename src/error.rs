@@ -0,0 +1,125 @@
+use std::{error, fmt, io};
+
+use crate::codes::HTTPCodes;
+use crate::response::Response;
+
+#[derive(Debug)]
+pub enum HTTPError {
+    /// The request (or one of its headers) could not be parsed.
+    ParsingError {
+        header: String,
+    },
+    /// A route pattern was rejected at registration time.
+    InvalidRoute {
+        pattern: String,
+        reason: String,
+    },
+    /// A header name contains characters outside the RFC 9110 token set.
+    InvalidHeaderName {
+        name: String,
+    },
+    /// A header value contains `\r`, `\n` or NUL, which would let it end
+    /// the header line early.
+    InvalidHeaderValue {
+        name: String,
+    },
+    /// The client did not deliver the request within the read timeout.
+    Timeout,
+    /// The request body exceeds the configured `max_body_size`.
+    BodyTooLarge,
+    /// The request carries more than the configured `max_header_count`
+    /// headers.
+    TooManyHeaders,
+    /// The request line or a header line is longer than the server reads,
+    /// or the whole head exceeds the configured `max_head_size`.
+    HeadTooLarge,
+    /// Reading from or writing to the connection failed.
+    Io(io::Error),
+    Unknown,
+}
+
+impl fmt::Display for HTTPError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HTTPError::ParsingError { header } => write!(f, "failed to parse {header}"),
+            HTTPError::InvalidRoute { pattern, reason } => {
+                write!(f, "invalid route {pattern}: {reason}")
+            }
+            HTTPError::InvalidHeaderName { name } => write!(f, "invalid header name {name:?}"),
+            HTTPError::InvalidHeaderValue { name } => write!(f, "invalid value for header {name}"),
+            HTTPError::Timeout => write!(f, "timed out"),
+            HTTPError::BodyTooLarge => write!(f, "body too large"),
+            HTTPError::TooManyHeaders => write!(f, "too many headers"),
+            HTTPError::HeadTooLarge => write!(f, "request head too large"),
+            HTTPError::Io(err) => write!(f, "I/O error: {err}"),
+            HTTPError::Unknown => write!(f, "unknown error"),
+        }
+    }
+}
+
+impl error::Error for HTTPError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            HTTPError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HTTPError {
+    fn from(err: io::Error) -> HTTPError {
+        HTTPError::Io(err)
+    }
+}
+
+/// A body that is not valid JSON, or not the JSON the handler expects, is
+/// the client's fault.
+#[cfg(feature = "json")]
+impl From<serde_json::Error> for HTTPError {
+    fn from(err: serde_json::Error) -> HTTPError {
+        HTTPError::ParsingError {
+            header: format!("JSON body: {err}"),
+        }
+    }
+}
+
+/// Turns errors raised while reading or preparing a request into the
+/// response sent to the client. Implemented for every
+/// `Fn(&HTTPError) -> Response`.
+pub trait ErrorHandler: Send + Sync + 'static {
+    fn handle(&self, err: &HTTPError) -> Response;
+}
+
+impl<F> ErrorHandler for F
+where
+    F: Fn(&HTTPError) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, err: &HTTPError) -> Response {
+        self(err)
+    }
+}
+
+/// Answers with an empty response whose status fits the error: `400` for
+/// malformed requests, `408` for timeouts, `413` for oversized bodies,
+/// `431` for too many or too large headers and `500` for everything else.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultErrorHandler;
+
+impl ErrorHandler for DefaultErrorHandler {
+    fn handle(&self, err: &HTTPError) -> Response {
+        let code = match err {
+            HTTPError::ParsingError { .. }
+            | HTTPError::InvalidHeaderName { .. }
+            | HTTPError::InvalidHeaderValue { .. } => HTTPCodes::BadRequest,
+            HTTPError::Timeout => HTTPCodes::RequestTimeout,
+            HTTPError::BodyTooLarge => HTTPCodes::ContentTooLarge,
+            HTTPError::TooManyHeaders | HTTPError::HeadTooLarge => {
+                HTTPCodes::RequestHeaderFieldsTooLarge
+            }
+            HTTPError::InvalidRoute { .. } | HTTPError::Io(_) | HTTPError::Unknown => {
+                HTTPCodes::InternalServerError
+            }
+        };
+        Response::new(code)
+    }
+}
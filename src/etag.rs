@@ -0,0 +1,118 @@
+//! Entity tags and conditional `GET` handling. `strong` and `weak` are
+//! exported as `strong_etag` and `weak_etag`.
+
+use httpdate::HttpDate;
+
+use crate::codes::HTTPCodes;
+use crate::headers::Headers;
+use crate::response::Response;
+
+/// A strong entity tag for `data`: its length and CRC32 in hex, quoted,
+/// e.g. `"b-0d4a1185"`. Cheap enough to compute for every response, but
+/// not collision resistant, so do not use it to detect tampering.
+pub fn strong(data: &[u8]) -> String {
+    format!("\"{:x}-{:08x}\"", data.len(), crc32fast::hash(data))
+}
+
+/// The weak form of `tag`, e.g. `W/"b-0d4a1185"`, for responses that are
+/// equivalent but not byte for byte identical. Unquoted tags are quoted
+/// and weak tags are returned unchanged.
+pub fn weak(tag: &str) -> String {
+    if tag.starts_with("W/") {
+        tag.to_string()
+    } else if tag.starts_with('"') {
+        format!("W/{tag}")
+    } else {
+        format!("W/\"{tag}\"")
+    }
+}
+
+/// Whether an `If-None-Match` value lists `etag`, or is `*`. Uses the weak
+/// comparison RFC 9110 prescribes for it: `W/` prefixes are ignored.
+pub(crate) fn none_match(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match.split(',').any(|candidate| {
+        let candidate = candidate.trim();
+        candidate == "*" || candidate.trim_start_matches("W/") == etag
+    })
+}
+
+/// Headers a `304 Not Modified` carries over from the response it stands
+/// in for, so caches can update their stored copy.
+const NOT_MODIFIED_HEADERS: [&str; 6] = [
+    "Cache-Control",
+    "Content-Location",
+    "ETag",
+    "Expires",
+    "Last-Modified",
+    "Vary",
+];
+
+/// The conditional headers of a request, kept while the request itself
+/// is handed to the handler.
+#[derive(Debug, Default)]
+pub(crate) struct Preconditions {
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+}
+
+impl Preconditions {
+    pub(crate) fn new(headers: &Headers) -> Preconditions {
+        Preconditions {
+            if_none_match: headers.get("If-None-Match").map(str::to_string),
+            if_modified_since: headers.get("If-Modified-Since").map(str::to_string),
+        }
+    }
+
+    /// `If-None-Match` is checked against the `ETag`; only without it is
+    /// `If-Modified-Since` compared to `Last-Modified`. Dates that do not
+    /// parse are ignored.
+    fn not_modified(&self, response: &Response) -> bool {
+        if let Some(if_none_match) = &self.if_none_match {
+            return response
+                .headers
+                .get("ETag")
+                .is_some_and(|etag| none_match(if_none_match, etag));
+        }
+        let since = self
+            .if_modified_since
+            .as_deref()
+            .and_then(|since| httpdate::parse_http_date(since).ok());
+        let modified = response
+            .headers
+            .get("Last-Modified")
+            .and_then(|modified| httpdate::parse_http_date(modified).ok());
+        match (since, modified) {
+            (Some(since), Some(modified)) => HttpDate::from(modified) <= HttpDate::from(since),
+            _ => false,
+        }
+    }
+}
+
+/// Replaces a successful `GET` or `HEAD` response with `304 Not Modified`
+/// when the request's `preconditions` show the client already has it, so
+/// the body is not sent again.
+pub(crate) fn conditional(
+    method: &str,
+    preconditions: &Preconditions,
+    response: Response,
+) -> Response {
+    let matched = matches!(method, "GET" | "HEAD")
+        && response.code == HTTPCodes::OK
+        && preconditions.not_modified(&response);
+    if !matched {
+        return response;
+    }
+    let mut not_modified = Response::new(HTTPCodes::NotModified).keep_alive(response.keep_alive);
+    for name in NOT_MODIFIED_HEADERS {
+        for value in response
+            .headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+        {
+            not_modified.headers.add(name, value);
+        }
+    }
+    not_modified
+}
@@ -0,0 +1,58 @@
+use crate::body::Framing;
+use crate::codes::HTTPCodes;
+use crate::request::Request;
+use crate::response::Response;
+use crate::server::ServerConfig;
+
+/// Decides whether a request sent with `Expect: 100-continue` may go on
+/// to send its body. It sees the method, path and headers; the body has
+/// not arrived yet. Returning a response rejects the request with it,
+/// `None` lets the client continue. Implemented for every
+/// `Fn(&Request) -> Option<Response>`.
+pub trait ContinueHandler: Send + Sync + 'static {
+    fn check(&self, request: &Request) -> Option<Response>;
+}
+
+impl<F> ContinueHandler for F
+where
+    F: Fn(&Request) -> Option<Response> + Send + Sync + 'static,
+{
+    fn check(&self, request: &Request) -> Option<Response> {
+        self(request)
+    }
+}
+
+/// What to do about a request's `Expect` header before its body is read.
+pub(crate) enum Expectation {
+    /// Nothing to do: no `Expect`, or no body to wait for.
+    None,
+    /// Send `100 Continue`, then read the body.
+    Continue,
+    /// Answer with this response without reading the body.
+    Reject(Box<Response>),
+}
+
+pub(crate) fn check_expectation(
+    request: &Request,
+    framing: &Framing,
+    config: &ServerConfig,
+) -> Expectation {
+    let Some(expect) = request.headers.get("Expect") else {
+        return Expectation::None;
+    };
+    if !expect.trim().eq_ignore_ascii_case("100-continue") {
+        return Expectation::Reject(Box::new(Response::new(HTTPCodes::ExpectationFailed)));
+    }
+    // HTTP/1.0 clients do not understand interim responses.
+    if request.version == "HTTP/1.0" || matches!(framing, Framing::Done) {
+        return Expectation::None;
+    }
+    match config
+        .continue_handler
+        .as_ref()
+        .and_then(|handler| handler.check(request))
+    {
+        Some(response) => Expectation::Reject(Box::new(response)),
+        None => Expectation::Continue,
+    }
+}
@@ -0,0 +1,215 @@
+use std::borrow::Borrow;
+use std::fmt;
+
+use indexmap::IndexMap;
+
+use crate::error::HTTPError;
+
+/// A validated, lowercased header name.
+///
+/// Only RFC 9110 token characters are accepted, so a name can never smuggle
+/// `\r\n` or a `:` into the serialized head.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HeaderName(String);
+
+impl HeaderName {
+    pub fn new(name: &str) -> Result<HeaderName, HTTPError> {
+        if name.is_empty() || !name.bytes().all(is_token_char) {
+            return Err(HTTPError::InvalidHeaderName {
+                name: name.to_string(),
+            });
+        }
+        Ok(HeaderName(name.to_ascii_lowercase()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+pub(crate) fn is_token_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+/// Whether `value` can be written on a header line as-is: `\r` and `\n`
+/// would end the line and start a new header, or the body.
+pub(crate) fn is_valid_value(value: &str) -> bool {
+    !value
+        .bytes()
+        .any(|byte| matches!(byte, b'\r' | b'\n' | b'\0'))
+}
+
+fn check_value(name: &HeaderName, value: &str) -> Result<(), HTTPError> {
+    if !is_valid_value(value) {
+        return Err(HTTPError::InvalidHeaderValue {
+            name: name.to_string(),
+        });
+    }
+    Ok(())
+}
+
+impl Borrow<str> for HeaderName {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for HeaderName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Header map with case-insensitive names. Names are stored lowercased.
+/// A name can hold several values, one per header line, in the order
+/// they were added. Names iterate, and are written, in the order they
+/// were first added.
+#[derive(Debug, Clone, Default)]
+pub struct Headers {
+    raw: IndexMap<HeaderName, Vec<String>>,
+}
+
+fn valid<T>(checked: Result<T, HTTPError>) -> T {
+    checked.unwrap_or_else(|err| panic!("{err}"))
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    /// Parses a single `Name: value` header line. A repeated field adds
+    /// another value instead of replacing the earlier one.
+    pub fn parse_line(&mut self, line: &str) -> Result<(), HTTPError> {
+        let (key, value) = line
+            .split_once(':')
+            .ok_or_else(|| HTTPError::ParsingError {
+                header: line.to_string(),
+            })?;
+        let invalid = || HTTPError::ParsingError {
+            header: line.to_string(),
+        };
+        let name = HeaderName::new(key.trim()).map_err(|_| invalid())?;
+        self.append(name, value.trim()).map_err(|_| invalid())
+    }
+
+    /// Appends a value, keeping any already present for `name`. Fails with
+    /// `InvalidHeaderValue` if `value` contains `\r`, `\n` or NUL.
+    pub fn append(&mut self, name: HeaderName, value: &str) -> Result<(), HTTPError> {
+        check_value(&name, value)?;
+        self.raw.entry(name).or_default().push(value.to_string());
+        Ok(())
+    }
+
+    /// Replaces every value of `name` with `value`. Fails like `append`.
+    pub fn insert(&mut self, name: HeaderName, value: &str) -> Result<(), HTTPError> {
+        check_value(&name, value)?;
+        self.raw.insert(name, vec![value.to_string()]);
+        Ok(())
+    }
+
+    /// Like `append`, for names and values known to be valid.
+    ///
+    /// # Panics
+    ///
+    /// If `key` is not a valid `HeaderName` or `value` contains `\r`, `\n`
+    /// or NUL. Use `HeaderName::new` and `append` for headers that come
+    /// from user input.
+    pub fn add(&mut self, key: &str, value: &str) {
+        valid(HeaderName::new(key).and_then(|name| self.append(name, value)));
+    }
+
+    /// Like `insert`, for names and values known to be valid.
+    ///
+    /// # Panics
+    ///
+    /// Like `add`.
+    pub fn set(&mut self, key: &str, value: &str) {
+        valid(HeaderName::new(key).and_then(|name| self.insert(name, value)));
+    }
+
+    /// The last value added for `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.raw
+            .get(key.to_ascii_lowercase().as_str())
+            .and_then(|values| values.last())
+            .map(String::as_str)
+    }
+
+    /// Every element of a list header, across all of its lines, trimmed
+    /// and in order. `Set-Cookie` is not a list (cookie dates contain
+    /// commas), so its values are returned whole.
+    pub fn get_all(&self, key: &str) -> Vec<String> {
+        let key = key.to_ascii_lowercase();
+        let values = self.raw.get(key.as_str()).into_iter().flatten();
+        if key == "set-cookie" {
+            return values.cloned().collect();
+        }
+        values
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Media ranges from `Accept` with their `q` values, see
+    /// `with_quality`.
+    pub fn accept_with_quality(&self) -> Vec<(String, f32)> {
+        self.with_quality("Accept")
+    }
+
+    /// Elements of a list header weighted by `q`, such as `Accept` or
+    /// `Accept-Encoding`, with their `q` values (1.0 when absent), most
+    /// preferred first. Elements with equal quality keep their order. The
+    /// `q` parameter is stripped; other parameters are kept.
+    pub fn with_quality(&self, key: &str) -> Vec<(String, f32)> {
+        let mut ranges: Vec<(String, f32)> = self
+            .get_all(key)
+            .iter()
+            .map(|item| {
+                let mut quality = 1.0;
+                let mut parts = Vec::new();
+                for param in item.split(';').map(str::trim) {
+                    match param.strip_prefix("q=") {
+                        Some(q) => quality = q.parse().unwrap_or(1.0),
+                        None => parts.push(param),
+                    }
+                }
+                (parts.join(";"), quality)
+            })
+            .collect();
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranges
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.raw.contains_key(key.to_ascii_lowercase().as_str())
+    }
+
+    /// Removes every value of `key`, returning the last one.
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.raw
+            .shift_remove(key.to_ascii_lowercase().as_str())
+            .and_then(|mut values| values.pop())
+    }
+
+    /// Number of values, counting each line of a repeated header.
+    pub fn len(&self) -> usize {
+        self.raw.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.raw.is_empty()
+    }
+
+    /// Every name and value pair, with one pair per value of a repeated
+    /// header.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.raw.iter().flat_map(|(key, values)| {
+            values
+                .iter()
+                .map(move |value| (key.as_str(), value.as_str()))
+        })
+    }
+}
@@ -0,0 +1,310 @@
+use std::future::poll_fn;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::{timeout, Instant};
+use tracing::{field, info_span, Span};
+
+use crate::body::Body;
+use crate::codes::HTTPCodes;
+use crate::connection::{decode_body, error_response, timed};
+use crate::error::HTTPError;
+use crate::etag::{conditional, Preconditions};
+use crate::headers::Headers;
+use crate::method::HttpMethod;
+use crate::metrics::Metrics;
+use crate::middleware::MiddlewareStack;
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::Router;
+use crate::server::ServerConfig;
+
+/// Connection-specific headers that are forbidden in HTTP/2.
+const CONNECTION_HEADERS: [&str; 6] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+];
+
+/// Addresses of an HTTP/2 connection: the TCP peer and the client it
+/// speaks for, which differ behind a PROXY protocol load balancer.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Peer {
+    pub(crate) addr: SocketAddr,
+    pub(crate) remote: SocketAddr,
+}
+
+/// Serves an HTTP/2 connection negotiated through ALPN. Every stream is
+/// turned into a `Request`, run through the same middleware and router as
+/// HTTP/1.1, and its `Response` is sent back on that stream.
+pub(crate) async fn serve_h2<S>(
+    stream: S,
+    peer: Peer,
+    router: Arc<Router>,
+    middleware: MiddlewareStack,
+    config: Arc<ServerConfig>,
+    mut shutdown: watch::Receiver<bool>,
+    metrics: Option<Arc<dyn Metrics>>,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let mut builder = h2::server::Builder::new();
+    builder.max_header_list_size(u32::try_from(config.max_head_size).unwrap_or(u32::MAX));
+    let Ok(Ok(mut connection)) = timeout(config.read_timeout, builder.handshake(stream)).await
+    else {
+        return;
+    };
+
+    if let Some(metrics) = &metrics {
+        metrics.on_connection_open();
+    }
+    let mut streams = JoinSet::new();
+    let mut draining = false;
+    loop {
+        tokio::select! {
+            accepted = connection.accept() => {
+                let Some(Ok((request, respond))) = accepted else {
+                    break;
+                };
+                let router = router.clone();
+                let middleware = middleware.clone();
+                let config = config.clone();
+                let metrics = metrics.clone();
+                let span = info_span!(
+                    "h2_stream",
+                    peer_addr = %peer.addr,
+                    method = %request.method(),
+                    path = request.uri().path(),
+                    request_id = field::Empty,
+                    status = field::Empty,
+                    elapsed_us = field::Empty,
+                );
+                streams.spawn(timed(span, async move {
+                    let stream = Stream { respond, peer, metrics };
+                    serve_stream(request, stream, &router, &middleware, &config).await;
+                }));
+            }
+            Some(_) = streams.join_next(), if !streams.is_empty() => {}
+            // Sends GOAWAY; `accept` keeps driving the open streams and
+            // returns `None` once they are done.
+            Ok(_) = shutdown.wait_for(|stop| *stop), if !draining => {
+                draining = true;
+                connection.graceful_shutdown();
+            }
+        }
+    }
+    while streams.join_next().await.is_some() {}
+    if let Some(metrics) = &metrics {
+        metrics.on_connection_close();
+    }
+}
+
+/// Per-stream state besides the request itself.
+struct Stream {
+    respond: SendResponse<Bytes>,
+    peer: Peer,
+    metrics: Option<Arc<dyn Metrics>>,
+}
+
+async fn serve_stream(
+    request: http::Request<RecvStream>,
+    mut stream: Stream,
+    router: &Arc<Router>,
+    middleware: &MiddlewareStack,
+    config: &ServerConfig,
+) {
+    let started = Instant::now();
+    let method = request.method().to_string();
+    if let Some(metrics) = &stream.metrics {
+        metrics.on_request_start(&method);
+    }
+
+    let deadline = started + config.read_timeout;
+    let request = match convert_request(request, stream.peer, deadline, config) {
+        Ok(mut request) => decode_body(&mut request, config).await.map(|()| request),
+        Err(err) => Err(err),
+    };
+    let mut response = match request {
+        // Tunnels are only offered over HTTP/1.1, see `connect_tunnel`;
+        // the router must not see CONNECT as a request for a path.
+        Ok(request) if request.method_enum() == HttpMethod::Connect => {
+            Response::new(HTTPCodes::NotImplemented)
+        }
+        Ok(request) => {
+            let preconditions = Preconditions::new(&request.headers);
+            let response = middleware.run(router.clone(), request).await;
+            conditional(&method, &preconditions, response)
+        }
+        Err(err) => error_response(config, &err),
+    };
+    response.omit_body = method == "HEAD";
+    Span::current().record("status", response.code.code());
+    let sent = send(&mut stream.respond, &mut response, config.write_timeout).await;
+    if let (Some(metrics), Ok(written)) = (&stream.metrics, sent) {
+        metrics.on_request_end(&method, response.code.code(), started.elapsed(), written);
+    }
+}
+
+fn convert_request(
+    request: http::Request<RecvStream>,
+    peer: Peer,
+    deadline: Instant,
+    config: &ServerConfig,
+) -> Result<Request, HTTPError> {
+    let (parts, body) = request.into_parts();
+    if parts.headers.len() > config.max_header_count {
+        return Err(HTTPError::TooManyHeaders);
+    }
+
+    let mut headers = Headers::new();
+    let mut cookies = Vec::new();
+    for (key, value) in &parts.headers {
+        let value = value.to_str().map_err(|_| HTTPError::ParsingError {
+            header: key.to_string(),
+        })?;
+        // HTTP/2 clients may split cookies over several headers.
+        if key == http::header::COOKIE {
+            cookies.push(value);
+        } else {
+            headers.add(key.as_str(), value);
+        }
+    }
+    if !cookies.is_empty() {
+        headers.add("Cookie", &cookies.join("; "));
+    }
+    if let Some(authority) = parts.uri.authority() {
+        if !headers.contains("Host") {
+            headers.add("Host", authority.as_str());
+        }
+    }
+
+    let too_large = headers
+        .get("Content-Length")
+        .and_then(|length| length.parse::<usize>().ok())
+        .is_some_and(|length| length > config.max_body_size);
+    if too_large {
+        return Err(HTTPError::BodyTooLarge);
+    }
+
+    let target = parts
+        .uri
+        .path_and_query()
+        .map_or("/", |target| target.as_str());
+    let mut request = Request::from_head(parts.method.as_str(), target, "HTTP/2", headers)?;
+    request.body = Body::h2(body, deadline, config.max_body_size);
+    request.peer_addr = Some(peer.addr);
+    request.remote_addr = Some(peer.remote);
+    // HTTP/2 is only negotiated over TLS.
+    request.tls = true;
+    request.trust_forwarded_proto = config.trust_forwarded_proto;
+    request.redacted_headers = config.redacted_headers.clone();
+    request.span = Span::current();
+    Ok(request)
+}
+
+/// Sends `response` on the stream and returns the number of body bytes
+/// sent; headers are HPACK compressed and not counted. `write_timeout`
+/// bounds each wait for the peer to take more data, not the whole body,
+/// so streamed responses may run as long as they like.
+async fn send(
+    respond: &mut SendResponse<Bytes>,
+    response: &mut Response,
+    write_timeout: Duration,
+) -> Result<usize, h2::Error> {
+    let mut head = http::Response::builder().status(response.code.code());
+    for (key, value) in response.headers.iter() {
+        if !CONNECTION_HEADERS.contains(&key) {
+            head = head.header(key, value);
+        }
+    }
+    for cookie in &response.cookies {
+        head = head.header("set-cookie", cookie.to_string());
+    }
+
+    let allows_body = response.allows_body();
+    let has_body = allows_body && (!response.body.is_empty() || response.chunked.is_some());
+    if has_body && !response.headers.contains("Content-Type") {
+        head = head.header("content-type", "text/html; charset=utf-8");
+    }
+    if let Some(length) = response.content_length().filter(|_| allows_body) {
+        head = head.header("content-length", length);
+    }
+    let Ok(head) = head.body(()) else {
+        // A header the handler set is not valid HTTP/2.
+        let head = http::Response::builder().status(500).body(()).unwrap();
+        respond.send_response(head, true)?;
+        return Ok(0);
+    };
+
+    let end_of_stream = !has_body || response.omit_body;
+    let mut stream = respond.send_response(head, end_of_stream)?;
+    if end_of_stream {
+        return Ok(0);
+    }
+    match response.chunked.as_mut() {
+        Some(chunked) => {
+            let mut sent = 0;
+            loop {
+                match chunked.next_chunk().await {
+                    Ok(Some(chunk)) => {
+                        sent += chunk.len();
+                        send_data(&mut stream, Bytes::from(chunk), false, write_timeout).await?;
+                    }
+                    Ok(None) => {
+                        stream.send_data(Bytes::new(), true)?;
+                        return Ok(sent);
+                    }
+                    Err(_) => {
+                        stream.send_reset(h2::Reason::INTERNAL_ERROR);
+                        return Ok(sent);
+                    }
+                }
+            }
+        }
+        None => {
+            let body = mem::take(&mut response.body);
+            let sent = body.len();
+            send_data(&mut stream, Bytes::from(body), true, write_timeout).await?;
+            Ok(sent)
+        }
+    }
+}
+
+/// Sends `data` as the peer's flow-control window allows, so a large body
+/// is not buffered inside h2 all at once. The stream is reset if the
+/// window stays closed for `write_timeout`.
+async fn send_data(
+    stream: &mut SendStream<Bytes>,
+    mut data: Bytes,
+    end_of_stream: bool,
+    write_timeout: Duration,
+) -> Result<(), h2::Error> {
+    while !data.is_empty() {
+        stream.reserve_capacity(data.len());
+        let capacity = match timeout(write_timeout, poll_fn(|cx| stream.poll_capacity(cx))).await {
+            Ok(Some(capacity)) => capacity?,
+            Ok(None) => return Err(h2::Error::from(h2::Reason::CANCEL)),
+            Err(_) => {
+                stream.send_reset(h2::Reason::CANCEL);
+                return Err(h2::Error::from(h2::Reason::CANCEL));
+            }
+        };
+        if capacity == 0 {
+            continue;
+        }
+        let piece = data.split_to(capacity.min(data.len()));
+        stream.send_data(piece, end_of_stream && data.is_empty())?;
+    }
+    Ok(())
+}
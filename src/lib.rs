@@ -0,0 +1,87 @@
+mod access;
+mod auth;
+mod body;
+mod codes;
+#[cfg(feature = "compression")]
+mod compression;
+mod connection;
+mod cookie;
+mod cors;
+mod error;
+mod etag;
+mod expect;
+mod extensions;
+mod headers;
+#[cfg(feature = "http2")]
+mod http2;
+mod limit;
+mod method;
+mod metrics;
+mod middleware;
+mod mime;
+#[cfg(feature = "multipart")]
+mod multipart;
+mod pipeline;
+mod proxy;
+mod rate_limit;
+mod request;
+mod request_id;
+mod response;
+mod router;
+mod server;
+mod sse;
+mod static_files;
+mod stats;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tower")]
+mod tower;
+mod tunnel;
+mod url;
+mod vhost;
+#[cfg(feature = "websocket")]
+mod websocket;
+
+pub use access::AccessControl;
+pub use auth::{BearerAuthMiddleware, BearerError};
+pub use body::{Body, BodyStream};
+pub use codes::HTTPCodes;
+#[cfg(feature = "compression")]
+pub use compression::CompressionMiddleware;
+pub use connection::{handle_connection, serve_connection, Connection};
+pub use cookie::{parse_cookies, Cookie};
+pub use cors::CorsConfig;
+pub use error::{DefaultErrorHandler, ErrorHandler, HTTPError};
+pub use etag::{strong as strong_etag, weak as weak_etag};
+pub use expect::ContinueHandler;
+pub use extensions::Extensions;
+pub use headers::{HeaderName, Headers};
+/// CIDR range type used by `AccessControl`.
+pub use ipnetwork::IpNetwork;
+pub use method::HttpMethod;
+pub use metrics::Metrics;
+#[cfg(feature = "metrics")]
+pub use metrics::PrometheusMetrics;
+pub use middleware::{LogFormat, LoggingMiddleware, Middleware, MiddlewareStack, Next};
+pub use mime::mime_type_for_extension;
+#[cfg(feature = "multipart")]
+pub use multipart::{MultipartField, MultipartParser};
+pub use rate_limit::RateLimitMiddleware;
+pub use request::Request;
+pub use request_id::RequestIdMiddleware;
+pub use response::{ChunkedBody, IntoResponse, Response};
+pub use router::{BoxError, BoxFuture, Handler, Router};
+pub use server::{Server, ServerBuilder, ServerConfig, SocketOptions};
+pub use sse::{SseEvent, SseStream};
+pub use static_files::StaticFiles;
+pub use stats::ServerStats;
+#[cfg(feature = "tls")]
+pub use tls::{load_tls_config, TlsConfig};
+#[cfg(feature = "tower")]
+pub use tower::{from_tower_service, TowerAdapter, TowerHandler};
+pub use url::percent_decode;
+pub use vhost::VHostRouter;
+#[cfg(feature = "websocket")]
+pub use websocket::{upgrade_websocket, WebSocket, WsMessage};
@@ -0,0 +1,102 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+use tracing::warn;
+
+use crate::codes::HTTPCodes;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::BoxFuture;
+
+/// Caps how many requests a `Server` handles at once, however many
+/// connections they arrive on. Requests over the cap wait up to `wait`
+/// for a permit in a queue of `queue_depth`; once that is full, or the
+/// wait is over, they are answered with `503 Service Unavailable`.
+///
+/// Runs as the outermost middleware, so the permit is held while the
+/// middleware and the handler produce the response, and released before
+/// it is written.
+pub(crate) struct RequestLimit {
+    permits: Arc<Semaphore>,
+    queued: AtomicUsize,
+    queue_depth: usize,
+    wait: Duration,
+}
+
+/// A request let in by `RequestLimit::admit`.
+pub(crate) enum Admission {
+    /// Handled right away under this permit.
+    Ready(OwnedSemaphorePermit),
+    /// Holding a place in the queue until `Admission::permit` gets one.
+    Queued(QueueSlot),
+}
+
+/// A place in the queue, given up when dropped.
+pub(crate) struct QueueSlot(Arc<RequestLimit>);
+
+impl RequestLimit {
+    pub(crate) fn new(max: usize, queue_depth: usize, wait: Duration) -> Arc<RequestLimit> {
+        Arc::new(RequestLimit {
+            permits: Arc::new(Semaphore::new(max.min(Semaphore::MAX_PERMITS))),
+            queued: AtomicUsize::new(0),
+            queue_depth,
+            wait,
+        })
+    }
+
+    /// `None` when every permit is taken and the queue is full.
+    pub(crate) fn admit(self: &Arc<Self>) -> Option<Admission> {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Some(Admission::Ready(permit));
+        }
+        self.queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+                (queued < self.queue_depth).then_some(queued + 1)
+            })
+            .ok()
+            .map(|_| Admission::Queued(QueueSlot(self.clone())))
+    }
+}
+
+impl Admission {
+    /// The permit to handle the request under, waiting up to `wait` for
+    /// one if the request was queued.
+    pub(crate) async fn permit(self, wait: Duration) -> Option<OwnedSemaphorePermit> {
+        match self {
+            Admission::Ready(permit) => Some(permit),
+            Admission::Queued(slot) => {
+                let acquire = slot.0.permits.clone().acquire_owned();
+                // The semaphore is never closed.
+                timeout(wait, acquire).await.ok()?.ok()
+            }
+        }
+    }
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        self.0.queued.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Middleware for Arc<RequestLimit> {
+    fn handle(&self, request: Request, next: Next) -> BoxFuture<Response> {
+        let admission = self.admit();
+        let wait = self.wait;
+        Box::pin(async move {
+            let permit = match admission {
+                Some(admission) => admission.permit(wait).await,
+                None => None,
+            };
+            let Some(_permit) = permit else {
+                warn!(path = %request.path, "too many requests in flight");
+                return Response::new(HTTPCodes::ServiceUnavailable);
+            };
+            next.run(request).await
+        })
+    }
+}
@@ -1 +1,206 @@
-fn main() {}
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use socket::{
+    upgrade_websocket, CompressionMiddleware, Cookie, CorsConfig, HTTPCodes, LoggingMiddleware,
+    Request, RequestIdMiddleware, Response, Router, Server, SseEvent, SseStream, StaticFiles,
+    WsMessage,
+};
+use tokio::sync::mpsc;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Test {
+    name: String,
+    count: u32,
+}
+
+async fn hello(_request: Request) -> Response {
+    Response::new(HTTPCodes::OK).body("Hello world")
+}
+
+async fn lorem(_request: Request) -> Response {
+    Response::text(&"Lorem ipsum dolor sit amet. ".repeat(100))
+}
+
+async fn echo(mut request: Request) -> Response {
+    let content_type = request
+        .headers
+        .get("Content-Type")
+        .unwrap_or("application/octet-stream")
+        .to_string();
+    match request.body.read_to_end().await {
+        Ok(body) => Response::new(HTTPCodes::OK)
+            .header("Content-Type", &content_type)
+            .body(body),
+        Err(_) => Response::new(HTTPCodes::BadRequest),
+    }
+}
+
+async fn increment(mut request: Request) -> Response {
+    match request.json::<Test>().await {
+        Ok(mut test) => {
+            test.count += 1;
+            Response::json(&test)
+        }
+        Err(err) => Response::new(HTTPCodes::BadRequest).body(err.to_string()),
+    }
+}
+
+async fn upload(mut request: Request) -> Response {
+    match request.multipart().await {
+        Ok(fields) => {
+            let summary: Vec<String> = fields
+                .iter()
+                .map(|field| {
+                    format!(
+                        "{} {:?} {:?} {} bytes",
+                        field.name,
+                        field.filename,
+                        field.content_type,
+                        field.data.len()
+                    )
+                })
+                .collect();
+            Response::new(HTTPCodes::OK).body(summary.join("\n"))
+        }
+        Err(err) => Response::new(HTTPCodes::BadRequest).body(err.to_string()),
+    }
+}
+
+async fn login(mut request: Request) -> Response {
+    match request.form().await {
+        Ok(form) => {
+            let user = form.get("user").map_or("stranger", String::as_str);
+            Response::new(HTTPCodes::OK).body(format!("Welcome, {user}"))
+        }
+        Err(err) => Response::new(HTTPCodes::BadRequest).body(err.to_string()),
+    }
+}
+
+async fn admin(request: Request) -> Response {
+    match request.basic_auth() {
+        Some((user, password)) if user == "admin" && password == "secret" => {
+            Response::new(HTTPCodes::OK).body("Hello admin")
+        }
+        _ => Response::www_authenticate("admin area"),
+    }
+}
+
+async fn search(request: Request) -> Response {
+    let query = request.query.get("q").map_or("", String::as_str);
+    let tags = request.query_multi.get("tags").cloned().unwrap_or_default();
+    Response::new(HTTPCodes::OK).body(format!("q={query} tags={tags:?}"))
+}
+
+async fn visit(request: Request) -> Response {
+    let visits: u32 = request
+        .cookies
+        .get("visits")
+        .and_then(|visits| visits.parse().ok())
+        .unwrap_or(0)
+        + 1;
+    Response::new(HTTPCodes::OK)
+        .set_cookie(
+            Cookie::new("visits", &visits.to_string())
+                .path("/")
+                .max_age(Duration::from_secs(3600))
+                .http_only(true),
+        )
+        .body(format!("Visit number {visits}"))
+}
+
+async fn user(request: Request) -> Response {
+    Response::new(HTTPCodes::OK).body(format!("User {}", request.params["id"]))
+}
+
+async fn count(_request: Request) -> Response {
+    let (sender, receiver) = mpsc::channel(4);
+    tokio::spawn(async move {
+        for i in 1..=5 {
+            if sender.send(format!("{i}\n").into_bytes()).await.is_err() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    });
+    Response::new(HTTPCodes::OK)
+        .header("Content-Type", "text/plain")
+        .chunked_channel(receiver)
+}
+
+async fn ws_echo(request: Request) -> Response {
+    upgrade_websocket(&request, |mut socket| async move {
+        while let Some(message) = socket.next_message().await {
+            let sent = match message {
+                WsMessage::Text(text) => socket.send_text(&text).await,
+                WsMessage::Binary(data) => socket.send_binary(&data).await,
+            };
+            if sent.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+async fn ticks(_request: Request, mut events: SseStream) {
+    for i in 1..=3 {
+        let event = SseEvent::new(&format!("tick {i}"))
+            .id(&i.to_string())
+            .event("tick");
+        if events.send(event).await.is_err() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // Defaults to info level; override with e.g. `RUST_LOG=socket=debug`.
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let mut router = Router::new();
+    router
+        .get("/", hello)?
+        .get("/lorem", lorem)?
+        .post("/echo", echo)?
+        .post("/increment", increment)?
+        .post("/upload", upload)?
+        .post("/login", login)?
+        .get("/users/:id", user)?
+        .get("/count", count)?
+        .get("/search", search)?
+        .get("/visit", visit)?
+        .get("/admin", admin)?
+        .get("/ws", ws_echo)?
+        .sse("/events", ticks)?
+        .get("/static/*path", StaticFiles::new(Path::new("static")))?
+        .get("/users/me", |_request| async {
+            Response::new(HTTPCodes::OK).body("It's you")
+        })?
+        .get("/files/*path", |request: Request| async move {
+            Response::new(HTTPCodes::OK).body(request.params["path"].clone())
+        })?;
+
+    Server::new()
+        .bind("127.0.0.1:4488")
+        .router(router)
+        .with(RequestIdMiddleware::new())
+        .with(LoggingMiddleware::default())
+        .with(CorsConfig::default())
+        .with(CompressionMiddleware::new())
+        .build()?
+        .run(async {
+            let _ = tokio::signal::ctrl_c().await;
+            tracing::info!("shutting down");
+        })
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+/// Hooks for collecting operational metrics. Every method defaults to
+/// doing nothing, so implementations only override what they record.
+pub trait Metrics: Send + Sync + 'static {
+    /// A request head has been parsed and is about to be handled.
+    fn on_request_start(&self, _method: &str) {}
+
+    /// The response has been written. `bytes_sent` counts everything put on
+    /// the wire, head included, for HTTP/1.1; HTTP/2 headers are compressed
+    /// by the codec, so only body bytes are counted there.
+    fn on_request_end(&self, _method: &str, _status: u16, _duration: Duration, _bytes_sent: usize) {
+    }
+
+    fn on_connection_open(&self) {}
+
+    fn on_connection_close(&self) {}
+}
+
+#[cfg(feature = "metrics")]
+pub use self::prometheus::PrometheusMetrics;
+
+#[cfg(feature = "metrics")]
+mod prometheus {
+    use std::time::Duration;
+
+    use prometheus::{
+        Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+    };
+
+    use super::Metrics;
+
+    /// `Metrics` backed by a Prometheus registry:
+    ///
+    /// - `http_requests_total{method, status}`
+    /// - `http_request_duration_seconds{method}`
+    /// - `http_response_bytes_total{method}`
+    /// - `http_open_connections`
+    ///
+    /// Serve `render()` from a route to let Prometheus scrape it.
+    #[derive(Debug, Clone)]
+    pub struct PrometheusMetrics {
+        registry: Registry,
+        requests: IntCounterVec,
+        duration: HistogramVec,
+        bytes: IntCounterVec,
+        connections: IntGauge,
+    }
+
+    /// Keeps label cardinality bounded when clients send made-up methods.
+    fn method_label(method: &str) -> &str {
+        match method {
+            "GET" | "HEAD" | "POST" | "PUT" | "PATCH" | "DELETE" | "OPTIONS" | "CONNECT"
+            | "TRACE" => method,
+            _ => "OTHER",
+        }
+    }
+
+    impl PrometheusMetrics {
+        pub fn new() -> prometheus::Result<PrometheusMetrics> {
+            PrometheusMetrics::with_registry(Registry::new())
+        }
+
+        /// Registers the collectors in an existing `registry`.
+        pub fn with_registry(registry: Registry) -> prometheus::Result<PrometheusMetrics> {
+            let requests = IntCounterVec::new(
+                Opts::new("http_requests_total", "Requests handled"),
+                &["method", "status"],
+            )?;
+            let duration = HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "Time from parsed request head to written response",
+                ),
+                &["method"],
+            )?;
+            let bytes = IntCounterVec::new(
+                Opts::new("http_response_bytes_total", "Bytes written in responses"),
+                &["method"],
+            )?;
+            let connections = IntGauge::new("http_open_connections", "Open connections")?;
+
+            registry.register(Box::new(requests.clone()))?;
+            registry.register(Box::new(duration.clone()))?;
+            registry.register(Box::new(bytes.clone()))?;
+            registry.register(Box::new(connections.clone()))?;
+            Ok(PrometheusMetrics {
+                registry,
+                requests,
+                duration,
+                bytes,
+                connections,
+            })
+        }
+
+        pub fn registry(&self) -> &Registry {
+            &self.registry
+        }
+
+        /// Current values in the Prometheus text exposition format.
+        pub fn render(&self) -> String {
+            let mut buffer = Vec::new();
+            let _ = TextEncoder::new().encode(&self.registry.gather(), &mut buffer);
+            String::from_utf8(buffer).unwrap_or_default()
+        }
+    }
+
+    impl Metrics for PrometheusMetrics {
+        fn on_request_end(&self, method: &str, status: u16, duration: Duration, bytes_sent: usize) {
+            let method = method_label(method);
+            self.requests
+                .with_label_values(&[method, &status.to_string()])
+                .inc();
+            self.duration
+                .with_label_values(&[method])
+                .observe(duration.as_secs_f64());
+            self.bytes
+                .with_label_values(&[method])
+                .inc_by(bytes_sent as u64);
+        }
+
+        fn on_connection_open(&self) {
+            self.connections.inc();
+        }
+
+        fn on_connection_close(&self) {
+            self.connections.dec();
+        }
+    }
+}
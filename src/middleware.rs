@@ -0,0 +1,176 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+
+use tracing::info;
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::{BoxFuture, Handler, Router};
+
+/// Wraps request handling. Call `next.run(request)` to continue down the
+/// chain, or return a response directly to short-circuit it. Implemented
+/// for every `Fn(Request, Next) -> impl Future<Output = Response>`.
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, request: Request, next: Next) -> BoxFuture<Response>;
+}
+
+impl<F, Fut> Middleware for F
+where
+    F: Fn(Request, Next) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    fn handle(&self, request: Request, next: Next) -> BoxFuture<Response> {
+        Box::pin(self(request, next))
+    }
+}
+
+/// The rest of the middleware chain, ending in the router, or in a
+/// route's handler for middleware scoped to routes.
+pub struct Next {
+    layers: Arc<Vec<Arc<dyn Middleware>>>,
+    index: usize,
+    endpoint: Endpoint,
+}
+
+enum Endpoint {
+    Router(Arc<Router>),
+    Handler(Arc<dyn Handler>),
+}
+
+impl Next {
+    /// A chain of just `layer` in front of `handler`.
+    pub(crate) fn scoped(
+        layer: Arc<dyn Middleware>,
+        handler: Arc<dyn Handler>,
+        request: Request,
+    ) -> BoxFuture<Response> {
+        let next = Next {
+            layers: Arc::new(Vec::new()),
+            index: 0,
+            endpoint: Endpoint::Handler(handler),
+        };
+        layer.handle(request, next)
+    }
+
+    pub async fn run(self, request: Request) -> Response {
+        match self.layers.get(self.index).cloned() {
+            Some(layer) => {
+                let next = Next {
+                    layers: self.layers,
+                    index: self.index + 1,
+                    endpoint: self.endpoint,
+                };
+                layer.handle(request, next).await
+            }
+            None => match self.endpoint {
+                Endpoint::Router(router) => router.handle(request).await,
+                Endpoint::Handler(handler) => handler.call(request).await,
+            },
+        }
+    }
+}
+
+/// Ordered list of middleware; the first one pushed runs outermost.
+#[derive(Clone, Default)]
+pub struct MiddlewareStack {
+    layers: Arc<Vec<Arc<dyn Middleware>>>,
+}
+
+impl MiddlewareStack {
+    pub fn new() -> MiddlewareStack {
+        MiddlewareStack::default()
+    }
+
+    pub fn push<M: Middleware>(&mut self, middleware: M) -> &mut Self {
+        Arc::make_mut(&mut self.layers).push(Arc::new(middleware));
+        self
+    }
+
+    /// A copy of the stack with `middleware` in front of every layer.
+    pub(crate) fn wrapped<M: Middleware>(&self, middleware: M) -> MiddlewareStack {
+        let mut layers: Vec<Arc<dyn Middleware>> = vec![Arc::new(middleware)];
+        layers.extend(self.layers.iter().cloned());
+        MiddlewareStack {
+            layers: Arc::new(layers),
+        }
+    }
+
+    /// Runs `request` through every layer and then `router`.
+    pub async fn run(&self, router: Arc<Router>, request: Request) -> Response {
+        let next = Next {
+            layers: self.layers.clone(),
+            index: 0,
+            endpoint: Endpoint::Router(router),
+        };
+        next.run(request).await
+    }
+}
+
+/// Message of the events `LoggingMiddleware` emits. The fields are the
+/// same either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `GET /users/7 -> 200 (12 bytes) in 85µs`
+    #[default]
+    Human,
+    /// No message, only the fields, for a subscriber that writes each
+    /// event as one JSON object, e.g. `tracing_subscriber`'s `json()`.
+    JsonLines,
+}
+
+/// Emits one `tracing` event per request, at `INFO` and with the
+/// `method`, `path`, `status`, `bytes` (absent for streamed bodies) and
+/// `elapsed_us` fields. Push it first so it wraps the whole chain and
+/// sees the status even when a later layer short-circuits.
+#[derive(Debug, Clone, Default)]
+pub struct LoggingMiddleware {
+    pub format: LogFormat,
+}
+
+impl LoggingMiddleware {
+    pub fn new(format: LogFormat) -> LoggingMiddleware {
+        LoggingMiddleware { format }
+    }
+}
+
+/// Size of the body about to be sent, or `None` for a chunked stream.
+fn body_size(response: &Response) -> Option<u64> {
+    match (&response.chunked, response.length) {
+        (None, _) => Some(response.body.len() as u64),
+        (Some(_), length) => length,
+    }
+}
+
+impl Middleware for LoggingMiddleware {
+    fn handle(&self, request: Request, next: Next) -> BoxFuture<Response> {
+        let format = self.format;
+        Box::pin(async move {
+            let start = Instant::now();
+            let method = request.method.clone();
+            let path = request.path.clone();
+            let response = next.run(request).await;
+            let elapsed = start.elapsed();
+            let status = response.code.code();
+            let size = body_size(&response);
+            let elapsed_us = elapsed.as_micros() as u64;
+            match format {
+                LogFormat::Human => {
+                    let shown = size.map_or("streamed".to_string(), |size| format!("{size} bytes"));
+                    info!(
+                        method = %method,
+                        path = %path,
+                        status,
+                        bytes = size,
+                        elapsed_us,
+                        "{method} {path} -> {status} ({shown}) in {elapsed:?}"
+                    );
+                }
+                LogFormat::JsonLines => {
+                    info!(method = %method, path = %path, status, bytes = size, elapsed_us);
+                }
+            }
+            response
+        })
+    }
+}
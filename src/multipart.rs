@@ -0,0 +1,155 @@
+use crate::error::HTTPError;
+use crate::headers::Headers;
+
+/// One part of a `multipart/form-data` body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultipartField {
+    /// Form field name from `Content-Disposition`.
+    pub name: String,
+    /// Original file name, present for file inputs.
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+/// Splits a `multipart/form-data` body into its fields.
+#[derive(Debug, Clone)]
+pub struct MultipartParser {
+    boundary: String,
+}
+
+fn invalid(header: &str) -> HTTPError {
+    HTTPError::ParsingError {
+        header: header.to_string(),
+    }
+}
+
+impl MultipartParser {
+    /// Takes the boundary from a `multipart/form-data; boundary=...`
+    /// content type.
+    pub fn from_content_type(content_type: &str) -> Result<MultipartParser, HTTPError> {
+        let (essence, params) = content_type.split_once(';').unwrap_or((content_type, ""));
+        if !essence.trim().eq_ignore_ascii_case("multipart/form-data") {
+            return Err(invalid("Content-Type: expected multipart/form-data"));
+        }
+        // RFC 2046 limits boundaries to 70 characters.
+        match parameter(params, "boundary") {
+            Some(boundary) if !boundary.is_empty() && boundary.len() <= 70 => {
+                Ok(MultipartParser { boundary })
+            }
+            _ => Err(invalid("Content-Type: missing multipart boundary")),
+        }
+    }
+
+    pub fn parse(&self, body: &[u8]) -> Result<Vec<MultipartField>, HTTPError> {
+        let delimiter = format!("--{}", self.boundary).into_bytes();
+        // Every delimiter after the first one starts on a new line.
+        let mut separator = b"\r\n".to_vec();
+        separator.extend_from_slice(&delimiter);
+
+        // Anything before the first delimiter is preamble.
+        let mut rest = if body.starts_with(&delimiter) {
+            &body[delimiter.len()..]
+        } else {
+            let start = find(body, &separator).ok_or_else(|| invalid("multipart body"))?;
+            &body[start + separator.len()..]
+        };
+
+        let mut fields = Vec::new();
+        loop {
+            if rest.starts_with(b"--") {
+                return Ok(fields);
+            }
+            // Whitespace may pad the delimiter line.
+            while let [b' ' | b'\t', tail @ ..] = rest {
+                rest = tail;
+            }
+            rest = rest
+                .strip_prefix(b"\r\n")
+                .ok_or_else(|| invalid("multipart delimiter"))?;
+
+            let end =
+                find(rest, &separator).ok_or_else(|| invalid("unterminated multipart body"))?;
+            fields.push(parse_part(&rest[..end])?);
+            rest = &rest[end + separator.len()..];
+        }
+    }
+}
+
+fn parse_part(part: &[u8]) -> Result<MultipartField, HTTPError> {
+    // A part with no headers starts straight away with the blank line.
+    let (head, data) = if let Some(data) = part.strip_prefix(b"\r\n") {
+        (&[][..], data)
+    } else {
+        let end = find(part, b"\r\n\r\n").ok_or_else(|| invalid("multipart part headers"))?;
+        (&part[..end], &part[end + 4..])
+    };
+
+    let head = std::str::from_utf8(head).map_err(|_| invalid("multipart part headers"))?;
+    let mut headers = Headers::new();
+    for line in head.split("\r\n").filter(|line| !line.is_empty()) {
+        headers.parse_line(line)?;
+    }
+
+    let disposition = headers
+        .get("Content-Disposition")
+        .ok_or_else(|| invalid("Content-Disposition: missing in multipart part"))?;
+    let (kind, params) = disposition.split_once(';').unwrap_or((disposition, ""));
+    if !kind.trim().eq_ignore_ascii_case("form-data") {
+        return Err(invalid("Content-Disposition: expected form-data"));
+    }
+    let name = parameter(params, "name")
+        .ok_or_else(|| invalid("Content-Disposition: missing field name"))?;
+
+    Ok(MultipartField {
+        name,
+        filename: parameter(params, "filename"),
+        content_type: headers.get("Content-Type").map(str::to_string),
+        data: data.to_vec(),
+    })
+}
+
+/// Looks up `name` in a `; key=value; key="quoted value"` parameter list.
+fn parameter(params: &str, name: &str) -> Option<String> {
+    let mut rest = params;
+    loop {
+        rest = rest.trim_start_matches([';', ' ', '\t']);
+        if rest.is_empty() {
+            return None;
+        }
+        let (key, after) = rest.split_once('=')?;
+        let key = key.trim();
+        let after = after.trim_start();
+
+        let (value, tail) = if let Some(quoted) = after.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = None;
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next().map(|(_, escaped)| escaped)),
+                    '"' => {
+                        end = Some(i + 1);
+                        break;
+                    }
+                    c => value.push(c),
+                }
+            }
+            (value, &quoted[end?..])
+        } else {
+            let end = after.find(';').unwrap_or(after.len());
+            (after[..end].trim().to_string(), &after[end..])
+        };
+
+        if key.eq_ignore_ascii_case(name) {
+            return Some(value);
+        }
+        rest = tail;
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
@@ -0,0 +1,344 @@
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, timeout_at, Instant};
+use tracing::{field, info_span, warn, Instrument, Span};
+
+use crate::body::{Body, Reader};
+use crate::codes::HTTPCodes;
+use crate::connection::{
+    decode_body, error_response, read_head, request_span, set_keep_alive_header, shutting_down,
+    skip_head, timed, trace_response, wait_for_request, write_response, Connection,
+    KeepAlivePolicy,
+};
+use crate::error::HTTPError;
+use crate::etag::{conditional, Preconditions};
+use crate::expect::{check_expectation, Expectation};
+use crate::method::HttpMethod;
+use crate::metrics::Metrics;
+use crate::middleware::MiddlewareStack;
+use crate::response::{OnUpgrade, Response};
+use crate::router::Router;
+use crate::server::ServerConfig;
+use crate::tunnel::connect_tunnel;
+
+/// One entry in a pipelined connection's response queue. Entries are
+/// written strictly in the order their requests arrived.
+enum Slot {
+    /// A request being handled in its own task.
+    Pending(JoinHandle<Response>, Exchange),
+    /// A response known without running a handler: `TRACE` echoes,
+    /// rejected expectations and errors.
+    Ready(Box<Response>, Exchange),
+    /// `100 Continue` for the request being read, due once every earlier
+    /// response is out. The reader waits on the sender before reading the
+    /// body.
+    Continue(oneshot::Sender<()>),
+}
+
+/// What reading requests needs to know about the connection.
+struct Origin {
+    peer_addr: Option<SocketAddr>,
+    remote_addr: Option<SocketAddr>,
+    tls: bool,
+    shutdown: Option<watch::Receiver<bool>>,
+    metrics: Option<Arc<dyn Metrics>>,
+}
+
+/// What writing a response needs to know about its request.
+struct Exchange {
+    method: String,
+    preconditions: Preconditions,
+    /// Whether the request allows the connection to stay open afterwards.
+    keep_alive: bool,
+    policy: KeepAlivePolicy,
+    /// Requests left on the connection after this one.
+    remaining: usize,
+    span: Span,
+    started: Instant,
+}
+
+/// Serves a connection whose client may pipeline requests: up to
+/// `pipeline_depth` requests are read ahead, each with its body buffered,
+/// and handled concurrently while their responses are written in order.
+///
+/// Requests asking for an upgrade stop the read-ahead, so the connection
+/// can be handed over once their response is written.
+pub(crate) async fn serve_pipelined(
+    connection: &mut Connection,
+    router: &Arc<Router>,
+    middleware: &MiddlewareStack,
+    config: &ServerConfig,
+) {
+    let Some(reader) = connection.reader.take() else {
+        return;
+    };
+    let origin = Origin {
+        peer_addr: connection.peer_addr,
+        remote_addr: connection.remote_addr,
+        tls: connection.tls,
+        shutdown: connection.shutdown.clone(),
+        metrics: connection.metrics.clone(),
+    };
+    // The writer holds one more response while it waits for or writes it.
+    let (slots, queue) = mpsc::channel(config.pipeline_depth - 1);
+    let reading = read_requests(reader, origin, slots, router, middleware, config);
+    let writing = write_responses(
+        &mut connection.writer,
+        queue,
+        connection.shutdown.clone(),
+        connection.metrics.clone(),
+        config,
+    );
+    let (reader, upgrade) = tokio::join!(reading, writing);
+    if let (Some(reader), Some(upgrade)) = (reader, upgrade) {
+        let writer = mem::replace(&mut connection.writer, Box::new(tokio::io::sink()));
+        upgrade.run(reader, writer, config.max_body_size).await;
+    }
+}
+
+/// Reads requests and queues their responses until the connection is to
+/// close. Returns the reader when the last request asked for an upgrade.
+///
+/// A malformed request line after a good request is answered and skipped,
+/// as `serve_connection` does, see `resync`. Any other unreadable request,
+/// a refused expectation or a body that fails to arrive leaves the rest of
+/// the stream unframed, so its error response closes the connection.
+async fn read_requests(
+    mut reader: Reader,
+    mut origin: Origin,
+    slots: mpsc::Sender<Slot>,
+    router: &Arc<Router>,
+    middleware: &MiddlewareStack,
+    config: &ServerConfig,
+) -> Option<Reader> {
+    let mut policy: Option<KeepAlivePolicy> = None;
+    let mut served = 0;
+    // Requests served when the connection last recovered from a malformed
+    // request line; recovering again takes a good request in between.
+    let mut resynced_at = None;
+    // The first request gets the full read timeout to start arriving.
+    let mut idle = config.read_timeout;
+    loop {
+        // The writer stops early when a response closes the connection.
+        tokio::select! {
+            ready = wait_for_request(&mut reader, origin.shutdown.as_mut(), idle) => {
+                if !ready {
+                    return None;
+                }
+            }
+            () = slots.closed() => return None,
+        }
+        let span = request_span(origin.peer_addr);
+        let started = Instant::now();
+
+        // Headers and body share one deadline.
+        let deadline = started + config.read_timeout;
+        let mut bad_request_line = false;
+        let head = timed(
+            info_span!(parent: &span, "read_head", elapsed_us = field::Empty),
+            timeout_at(
+                deadline,
+                read_head(&mut reader, config, &mut bad_request_line),
+            ),
+        )
+        .await
+        .unwrap_or(Err(HTTPError::Timeout));
+        let policy = policy.get_or_insert_with(|| KeepAlivePolicy::new(config));
+        let mut exchange = Exchange {
+            method: String::new(),
+            preconditions: Preconditions::default(),
+            keep_alive: false,
+            policy: *policy,
+            remaining: 0,
+            span: span.clone(),
+            started,
+        };
+        let (mut request, framing, trace) = match head {
+            Ok(Some(head)) => head,
+            Ok(None) => return None,
+            Err(err) if bad_request_line && served > 0 && resynced_at != Some(served) => {
+                span.in_scope(|| warn!(error = %err, "skipping malformed request"));
+                exchange.keep_alive = true;
+                exchange.remaining = policy.keepalive_max.saturating_sub(served);
+                let response = config.error_handler.handle(&err);
+                if slots
+                    .send(Slot::Ready(Box::new(response), exchange))
+                    .await
+                    .is_err()
+                    || !skip_head(&mut reader, config).await
+                {
+                    return None;
+                }
+                resynced_at = Some(served);
+                idle = policy.keepalive_timeout;
+                continue;
+            }
+            Err(err) => {
+                span.in_scope(|| warn!(error = %err, "rejected request"));
+                let _ = slots
+                    .send(Slot::Ready(
+                        Box::new(error_response(config, &err)),
+                        exchange,
+                    ))
+                    .await;
+                return None;
+            }
+        };
+        span.record("method", request.method.as_str())
+            .record("path", request.path.as_str());
+        if let Some(metrics) = &origin.metrics {
+            metrics.on_request_start(&request.method);
+        }
+
+        policy.restrict(&request.headers);
+        served += 1;
+        let remaining = policy.keepalive_max.saturating_sub(served);
+        let connect = request.method_enum() == HttpMethod::Connect;
+        let upgrade = request.upgrade().is_some() || connect;
+        exchange.method = request.method.clone();
+        exchange.preconditions = Preconditions::new(&request.headers);
+        exchange.keep_alive = request.keep_alive && remaining > 0;
+        exchange.policy = *policy;
+        exchange.remaining = remaining;
+        let more = exchange.keep_alive && !upgrade;
+
+        request.peer_addr = origin.peer_addr;
+        request.remote_addr = origin.remote_addr.or(origin.peer_addr);
+        request.tls = origin.tls;
+        request.trust_forwarded_proto = config.trust_forwarded_proto;
+        request.redacted_headers = config.redacted_headers.clone();
+        request.span = span.clone();
+        match check_expectation(&request, &framing, config) {
+            Expectation::None => {}
+            Expectation::Continue => {
+                let (ready, continued) = oneshot::channel();
+                if slots.send(Slot::Continue(ready)).await.is_err() || continued.await.is_err() {
+                    return None;
+                }
+            }
+            Expectation::Reject(mut response) => {
+                response.keep_alive = false;
+                let _ = slots.send(Slot::Ready(response, exchange)).await;
+                return None;
+            }
+        }
+
+        // The body is read in full here, so that the next request can be
+        // read while this one is handled.
+        let (mut body, body_handle) = Body::streaming(reader, framing, deadline, config);
+        let data = body.read_to_end().await;
+        drop(body);
+        let next = body_handle.finish().await;
+        let (data, next) = match (data, next) {
+            (Ok(data), Some(next)) => (data, next),
+            (data, _) => {
+                let err = data.err().unwrap_or(HTTPError::Unknown);
+                let _ = slots
+                    .send(Slot::Ready(
+                        Box::new(error_response(config, &err)),
+                        exchange,
+                    ))
+                    .await;
+                return None;
+            }
+        };
+        reader = next;
+        request.body = Body::from(data);
+
+        let slot = if let Some(trace) = trace {
+            Slot::Ready(
+                Box::new(trace_response(config, router, &request.path, trace)),
+                exchange,
+            )
+        } else if connect {
+            let targets = config.connect_targets.clone();
+            let wait = config.read_timeout;
+            let tunnel = async move { connect_tunnel(&request.path, &targets, wait).await };
+            Slot::Pending(tokio::spawn(tunnel), exchange)
+        } else if let Err(err) = decode_body(&mut request, config).await {
+            Slot::Ready(Box::new(error_response(config, &err)), exchange)
+        } else {
+            let router = router.clone();
+            let middleware = middleware.clone();
+            let handler = async move { middleware.run(router, request).await };
+            let handler = handler.instrument(info_span!(parent: &span, "handler"));
+            Slot::Pending(tokio::spawn(handler), exchange)
+        };
+        if slots.send(slot).await.is_err() {
+            return None;
+        }
+        if !more || shutting_down(origin.shutdown.as_ref()) {
+            return upgrade.then_some(reader);
+        }
+        idle = policy.keepalive_timeout;
+    }
+}
+
+/// Writes queued responses in order until the queue ends or a response
+/// closes the connection. Returns the upgrade of the last response, if
+/// it switches protocols.
+async fn write_responses(
+    writer: &mut Box<dyn AsyncWrite + Send + Unpin>,
+    mut queue: mpsc::Receiver<Slot>,
+    shutdown: Option<watch::Receiver<bool>>,
+    metrics: Option<Arc<dyn Metrics>>,
+    config: &ServerConfig,
+) -> Option<OnUpgrade> {
+    while let Some(slot) = queue.recv().await {
+        let (response, exchange) = match slot {
+            Slot::Continue(ready) => {
+                let written = timeout(
+                    config.write_timeout,
+                    writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n"),
+                )
+                .await;
+                if !matches!(written, Ok(Ok(()))) {
+                    return None;
+                }
+                let _ = ready.send(());
+                continue;
+            }
+            Slot::Ready(response, exchange) => (*response, exchange),
+            Slot::Pending(handler, exchange) => {
+                let response = handler
+                    .await
+                    .unwrap_or_else(|_| Response::new(HTTPCodes::InternalServerError));
+                (response, exchange)
+            }
+        };
+
+        let mut response = conditional(&exchange.method, &exchange.preconditions, response);
+        response.omit_body = exchange.method == "HEAD";
+        exchange.span.record("status", response.code.code());
+        response.keep_alive &= exchange.keep_alive && !shutting_down(shutdown.as_ref());
+        set_keep_alive_header(&mut response, &exchange.policy, exchange.remaining);
+        let sent = timed(
+            info_span!(parent: &exchange.span, "write_response", elapsed_us = field::Empty),
+            write_response(writer, &mut response, config),
+        )
+        .await;
+        if let (Some(metrics), Ok(written)) = (&metrics, &sent) {
+            let elapsed = exchange.started.elapsed();
+            metrics.on_request_end(&exchange.method, response.code.code(), elapsed, *written);
+        }
+        exchange
+            .span
+            .record("elapsed_us", exchange.started.elapsed().as_micros() as u64);
+
+        if sent.is_err() {
+            return None;
+        }
+        if response.upgrade.is_some() {
+            return response.upgrade.take();
+        }
+        if !response.keep_alive {
+            return None;
+        }
+    }
+    None
+}
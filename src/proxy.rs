@@ -0,0 +1,66 @@
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Longest PROXY protocol v1 line, CRLF included.
+const MAX_LINE: usize = 107;
+
+/// Reads a PROXY protocol v1 header, e.g.
+/// `PROXY TCP4 192.0.2.1 192.0.2.2 12345 80\r\n`, and returns the source
+/// address it reports. `PROXY UNKNOWN` yields `None`: the proxy could not
+/// tell, and the connection's own peer address stands.
+///
+/// Reads byte by byte so nothing after the header is consumed.
+pub(crate) async fn read_proxy_header<R>(stream: &mut R) -> io::Result<Option<SocketAddr>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut line = Vec::with_capacity(MAX_LINE);
+    while !line.ends_with(b"\r\n") {
+        if line.len() == MAX_LINE {
+            return Err(malformed());
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| malformed())?;
+    parse_proxy_line(line).ok_or_else(malformed)
+}
+
+fn parse_proxy_line(line: &str) -> Option<Option<SocketAddr>> {
+    let mut parts = line.split(' ');
+    if parts.next()? != "PROXY" {
+        return None;
+    }
+    let family = parts.next()?;
+    if family == "UNKNOWN" {
+        return Some(None);
+    }
+    let (Some(source), Some(destination), Some(source_port), Some(destination_port), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return None;
+    };
+
+    let source: IpAddr = source.parse().ok()?;
+    let destination: IpAddr = destination.parse().ok()?;
+    let family_matches = match family {
+        "TCP4" => source.is_ipv4() && destination.is_ipv4(),
+        "TCP6" => source.is_ipv6() && destination.is_ipv6(),
+        _ => false,
+    };
+    if !family_matches {
+        return None;
+    }
+    let source_port: u16 = source_port.parse().ok()?;
+    destination_port.parse::<u16>().ok()?;
+    Some(Some(SocketAddr::new(source, source_port)))
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "malformed PROXY protocol header")
+}
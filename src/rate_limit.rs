@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use crate::codes::HTTPCodes;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::BoxFuture;
+
+/// Tokens left for one client, refilled lazily whenever it is touched.
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+type Buckets = Mutex<HashMap<IpAddr, TokenBucket>>;
+
+/// Limits each client IP to bursts of `capacity` requests, refilled at
+/// `refill_rate` requests per second. Clients over the limit get `429 Too
+/// Many Requests` with a `Retry-After` header. Requests whose
+/// `remote_addr` is unknown are not limited.
+///
+/// Clones share the same buckets. A background task started with the first
+/// request drops buckets of clients that have been quiet long enough to be
+/// full again.
+#[derive(Debug, Clone)]
+pub struct RateLimitMiddleware {
+    pub capacity: u32,
+    pub refill_rate: f64,
+    /// How often stale buckets are removed.
+    pub cleanup_interval: Duration,
+    buckets: Arc<Buckets>,
+    cleanup_started: Arc<AtomicBool>,
+}
+
+impl RateLimitMiddleware {
+    pub fn new(capacity: u32, refill_rate: f64) -> RateLimitMiddleware {
+        RateLimitMiddleware {
+            capacity,
+            refill_rate,
+            cleanup_interval: Duration::from_secs(60),
+            buckets: Arc::default(),
+            cleanup_started: Arc::default(),
+        }
+    }
+
+    pub fn cleanup_interval(mut self, interval: Duration) -> Self {
+        self.cleanup_interval = interval;
+        self
+    }
+
+    /// Takes a token for `ip`, or returns how long until one is available.
+    fn acquire(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let capacity = f64::from(self.capacity);
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(ip).or_insert(TokenBucket {
+            tokens: capacity,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_rate).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let missing = 1.0 - bucket.tokens;
+        Err(Duration::try_from_secs_f64(missing / self.refill_rate).unwrap_or(Duration::MAX))
+    }
+
+    fn start_cleanup(&self) {
+        if self.cleanup_started.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        // A bucket untouched for this long has refilled completely, so
+        // forgetting it changes nothing for the client.
+        let full_after = Duration::try_from_secs_f64(f64::from(self.capacity) / self.refill_rate)
+            .unwrap_or(Duration::MAX);
+        tokio::spawn(cleanup(
+            Arc::downgrade(&self.buckets),
+            self.cleanup_interval,
+            full_after,
+        ));
+    }
+}
+
+/// Runs until the middleware, and with it the buckets, is dropped.
+async fn cleanup(buckets: Weak<Buckets>, interval: Duration, full_after: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let Some(buckets) = buckets.upgrade() else {
+            return;
+        };
+        let now = Instant::now();
+        buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| now.duration_since(bucket.updated) < full_after);
+    }
+}
+
+impl Middleware for RateLimitMiddleware {
+    fn handle(&self, request: Request, next: Next) -> BoxFuture<Response> {
+        let Some(remote_addr) = request.remote_addr else {
+            return Box::pin(next.run(request));
+        };
+        self.start_cleanup();
+
+        match self.acquire(remote_addr.ip()) {
+            Ok(()) => Box::pin(next.run(request)),
+            Err(wait) => {
+                // Retry-After is in whole seconds; round up so clients do
+                // not come back too early.
+                let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                let response = Response::new(HTTPCodes::TooManyRequests)
+                    .header("Retry-After", &retry_after.to_string());
+                Box::pin(async move { response })
+            }
+        }
+    }
+}
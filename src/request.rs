@@ -0,0 +1,571 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, LazyLock};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+#[cfg(feature = "json")]
+use serde::de::DeserializeOwned;
+use tracing::Span;
+
+use crate::body::Body;
+use crate::connection::connection_has;
+use crate::cookie::parse_cookies;
+use crate::error::HTTPError;
+use crate::extensions::Extensions;
+use crate::headers::Headers;
+use crate::method::HttpMethod;
+#[cfg(feature = "multipart")]
+use crate::multipart::{MultipartField, MultipartParser};
+use crate::router::negotiate;
+use crate::url::{decode_path, parse_query};
+
+pub struct Request {
+    pub method: String,
+    /// Percent-decoded request target without the query string. `%2F` is
+    /// kept encoded so it cannot be confused with a segment separator.
+    pub path: String,
+    pub version: String,
+    pub headers: Headers,
+    pub body: Body,
+    pub keep_alive: bool,
+    /// Values bound by `:name` segments of the matched route.
+    pub params: HashMap<String, String>,
+    /// The rest of the path matched by the route's trailing `*name`
+    /// segment, whatever its name; also in `params` under that name.
+    pub wildcard: Option<String>,
+    /// Decoded query string values; the first one wins for repeated keys.
+    pub query: HashMap<String, String>,
+    /// Every decoded value of each query string key, in order.
+    pub query_multi: HashMap<String, Vec<String>>,
+    /// Cookies sent in the `Cookie` header.
+    pub cookies: HashMap<String, String>,
+    /// Address at the other end of the TCP connection, when the
+    /// connection knows it. Behind a load balancer this is the balancer.
+    pub peer_addr: Option<SocketAddr>,
+    /// Address of the client the request comes from. Set by the server for
+    /// every accepted connection, and for connections built with
+    /// `Connection::with_peer_addr`; `None` otherwise. Same as `peer_addr`
+    /// unless a proxy in front reported the original client.
+    pub remote_addr: Option<SocketAddr>,
+    /// Whether the request arrived over TLS. Set by the server for
+    /// connections accepted on a TLS listener, and for connections built
+    /// with `Connection::with_tls`.
+    pub tls: bool,
+    /// Whether `scheme` believes `X-Forwarded-Proto`, from
+    /// `ServerConfig::trust_forwarded_proto`.
+    pub(crate) trust_forwarded_proto: bool,
+    /// Headers whose values `Debug` hides, from
+    /// `ServerConfig::redacted_headers`.
+    pub(crate) redacted_headers: Arc<[String]>,
+    /// Media type negotiated from `Accept` when the matched route declared
+    /// what it `produces`.
+    pub media_type: Option<String>,
+    /// Typed values attached by middleware for later layers and handlers.
+    pub extensions: Extensions,
+    /// Correlation ID assigned by `RequestIdMiddleware`; empty without it.
+    pub id: String,
+    /// Root tracing span of the request, for recording fields that are
+    /// only known once middleware has run.
+    pub(crate) span: Span,
+}
+
+impl Request {
+    /// Builds a request with an empty body from its method, raw target,
+    /// protocol version and headers, decoding the path, query and cookies.
+    pub(crate) fn from_head(
+        method: &str,
+        target: &str,
+        version: &str,
+        headers: Headers,
+    ) -> Result<Request, HTTPError> {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let path = decode_path(path).ok_or_else(|| HTTPError::ParsingError {
+            header: "request path".to_string(),
+        })?;
+        let query_multi = parse_query(query).ok_or_else(|| HTTPError::ParsingError {
+            header: "query string".to_string(),
+        })?;
+        let query = query_multi
+            .iter()
+            .map(|(key, values)| (key.clone(), values[0].clone()))
+            .collect();
+        let cookies = headers.get("Cookie").map(parse_cookies).unwrap_or_default();
+
+        Ok(Request {
+            method: method.to_string(),
+            path,
+            version: version.to_string(),
+            headers,
+            body: Body::empty(),
+            keep_alive: true,
+            params: HashMap::new(),
+            wildcard: None,
+            query,
+            query_multi,
+            cookies,
+            peer_addr: None,
+            remote_addr: None,
+            tls: false,
+            trust_forwarded_proto: false,
+            redacted_headers: default_redacted_headers(),
+            media_type: None,
+            extensions: Extensions::new(),
+            id: String::new(),
+            span: Span::none(),
+        })
+    }
+
+    /// `method` as an `HttpMethod`, for matching without string typos.
+    pub fn method_enum(&self) -> HttpMethod {
+        HttpMethod::parse(&self.method)
+    }
+
+    /// Reads the rest of the body into memory, so that clones of the
+    /// request carry it too.
+    pub async fn buffer_body(&mut self) -> Result<(), HTTPError> {
+        self.body = Body::from(self.body.read_to_end().await?);
+        Ok(())
+    }
+
+    /// Reads the rest of the body into memory, see `buffer_body`, and
+    /// returns a clone carrying all of it.
+    pub async fn buffered_clone(&mut self) -> Result<Request, HTTPError> {
+        self.body_bytes().await?;
+        Ok(self.clone())
+    }
+
+    /// The rest of the body, read into memory on first use. Unlike
+    /// `body.read_to_end`, it leaves the body in place for later reads.
+    pub async fn body_bytes(&mut self) -> Result<&[u8], HTTPError> {
+        if self.body.buffered().is_none() {
+            self.buffer_body().await?;
+        }
+        Ok(self.body.buffered().unwrap_or_default())
+    }
+
+    /// The rest of the body as text, see `body_bytes`. Fails with
+    /// `HTTPError::ParsingError` unless it is valid UTF-8.
+    pub async fn body_string(&mut self) -> Result<String, HTTPError> {
+        let body = self.body_bytes().await?;
+        match std::str::from_utf8(body) {
+            Ok(body) => Ok(body.to_string()),
+            Err(err) => Err(HTTPError::ParsingError {
+                header: format!("body: invalid UTF-8 after {} bytes", err.valid_up_to()),
+            }),
+        }
+    }
+
+    /// A copy of everything but the body and `extensions`, kept for
+    /// reporting on the request after it was handed to a handler.
+    pub(crate) fn copy_head(&self) -> Request {
+        Request {
+            method: self.method.clone(),
+            path: self.path.clone(),
+            version: self.version.clone(),
+            headers: self.headers.clone(),
+            body: Body::empty(),
+            keep_alive: self.keep_alive,
+            params: self.params.clone(),
+            wildcard: self.wildcard.clone(),
+            query: self.query.clone(),
+            query_multi: self.query_multi.clone(),
+            cookies: self.cookies.clone(),
+            peer_addr: self.peer_addr,
+            remote_addr: self.remote_addr,
+            tls: self.tls,
+            trust_forwarded_proto: self.trust_forwarded_proto,
+            redacted_headers: self.redacted_headers.clone(),
+            media_type: self.media_type.clone(),
+            extensions: Extensions::new(),
+            id: self.id.clone(),
+            span: self.span.clone(),
+        }
+    }
+
+    /// The value of type `T` that middleware stored in `extensions`.
+    pub fn extract<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.extensions.get()
+    }
+
+    /// The protocols named in `Upgrade`, e.g. `"websocket"`, when the
+    /// request asks to switch protocols: `Connection` must list `upgrade`
+    /// too, otherwise a proxy added the header and it is not meant for
+    /// this server.
+    pub fn upgrade(&self) -> Option<String> {
+        let upgrade = self.headers.get("Upgrade")?;
+        connection_has(&self.headers, "upgrade").then(|| upgrade.trim().to_string())
+    }
+
+    /// Whether the request was sent by `XMLHttpRequest`, as far as
+    /// `X-Requested-With: XMLHttpRequest` says; libraries like jQuery set
+    /// it, browsers do not.
+    pub fn is_xhr(&self) -> bool {
+        self.headers
+            .get("X-Requested-With")
+            .is_some_and(|with| with.trim().eq_ignore_ascii_case("XMLHttpRequest"))
+    }
+
+    /// The browser's `Sec-Fetch-Mode`: `navigate` for page loads, `cors`
+    /// or `same-origin` for `fetch` calls, `no-cors` for images and
+    /// scripts. Handy to answer API calls with JSON and navigations with
+    /// HTML from the same route.
+    pub fn fetch_mode(&self) -> Option<&str> {
+        self.headers.get("Sec-Fetch-Mode").map(str::trim)
+    }
+
+    /// The `Host` header without its port, e.g. `example.com` for
+    /// `example.com:8080`. IPv6 addresses keep their brackets, as in
+    /// `[::1]`, so the result can go straight into a URL.
+    pub fn host(&self) -> Option<&str> {
+        let host = self.headers.get("Host")?.trim();
+        Some(split_port(host).0).filter(|host| !host.is_empty())
+    }
+
+    /// The port of the `Host` header, or the default port of `scheme`
+    /// when it names none: `80` for `http`, `443` for `https`. `None`
+    /// without a `Host` or when the port is not a number.
+    pub fn host_port(&self) -> Option<u16> {
+        let host = self.headers.get("Host")?.trim();
+        match split_port(host).1 {
+            Some(port) => port.parse().ok(),
+            None => Some(default_port(self.scheme())),
+        }
+    }
+
+    /// `"https"` for requests that arrived over TLS, `"http"` otherwise.
+    /// With `ServerConfig::trust_forwarded_proto`, the scheme a proxy in
+    /// front reports in `X-Forwarded-Proto` or `Forwarded: proto=` wins.
+    pub fn scheme(&self) -> &str {
+        if self.trust_forwarded_proto {
+            let forwarded = self
+                .headers
+                .get_all("Forwarded")
+                .iter()
+                .flat_map(|element| element.split(';'))
+                .filter_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+                    key.trim()
+                        .eq_ignore_ascii_case("proto")
+                        .then(|| value.to_string())
+                })
+                .next()
+                .or_else(|| self.headers.get_all("X-Forwarded-Proto").into_iter().next());
+            match forwarded
+                .as_deref()
+                .map(|proto| proto.trim().trim_matches('"'))
+            {
+                Some(proto) if proto.eq_ignore_ascii_case("https") => return "https",
+                Some(proto) if proto.eq_ignore_ascii_case("http") => return "http",
+                _ => {}
+            }
+        }
+        if self.tls {
+            "https"
+        } else {
+            "http"
+        }
+    }
+
+    /// The URL of the server's root as the client sees it, e.g.
+    /// `https://example.com` or `http://localhost:4488`, for building
+    /// absolute URLs. The port is left out when it is the scheme's
+    /// default. `None` without a `Host` header.
+    pub fn base_url(&self) -> Option<String> {
+        let scheme = self.scheme();
+        let host = self.host()?;
+        let port = self.host_port()?;
+        if port == default_port(scheme) {
+            Some(format!("{scheme}://{host}"))
+        } else {
+            Some(format!("{scheme}://{host}:{port}"))
+        }
+    }
+
+    /// Whether a response of type `mime` is acceptable to the client. The
+    /// most specific matching range of `Accept` decides, the same way
+    /// routes declaring what they `produces` are chosen: `text/html`
+    /// over `text/*` over `*/*`, and `q=0` rejects. Parameters of `mime`,
+    /// like `; charset=utf-8`, are ignored. Everything is acceptable
+    /// without an `Accept` header.
+    pub fn accepts(&self, mime: &str) -> bool {
+        let essence = mime.split(';').next().unwrap_or_default().trim();
+        negotiate(&[essence.to_string()], &self.headers.accept_with_quality()).is_some()
+    }
+
+    /// The content coding the client prefers in `Accept-Encoding`, e.g.
+    /// `"gzip"`, `"br"`, `"identity"` or `"*"`: the one with the highest
+    /// `q` value, the first listed on ties. Codings with `q=0` are never
+    /// returned.
+    pub fn preferred_encoding(&self) -> Option<String> {
+        self.headers
+            .with_quality("Accept-Encoding")
+            .into_iter()
+            .find(|(coding, quality)| !coding.is_empty() && *quality > 0.0)
+            .map(|(coding, _)| coding)
+    }
+
+    /// Client addresses reported by proxies, the original client first:
+    /// the `for=` nodes of `Forwarded`, or `X-Forwarded-For` when there
+    /// is no `Forwarded`. Entries that are not IP addresses, such as
+    /// `unknown` or obfuscated identifiers, are skipped; ports are dropped.
+    ///
+    /// Any client can send these headers. Only believe them when a proxy
+    /// you control sets or overwrites them.
+    pub fn forwarded_for(&self) -> Vec<IpAddr> {
+        let forwarded = self.headers.get_all("Forwarded");
+        if !forwarded.is_empty() {
+            return forwarded
+                .iter()
+                .flat_map(|element| element.split(';'))
+                .filter_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+                    key.trim().eq_ignore_ascii_case("for").then_some(value)
+                })
+                .filter_map(parse_node)
+                .collect();
+        }
+        self.headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|node| parse_node(node))
+            .collect()
+    }
+
+    /// The client's IP address: the first of `forwarded_for` when
+    /// `trust_proxy` is set and a proxy reported one, otherwise that of
+    /// `remote_addr`. `None` when neither is known.
+    pub fn real_ip(&self, trust_proxy: bool) -> Option<IpAddr> {
+        let forwarded = trust_proxy
+            .then(|| self.forwarded_for().first().copied())
+            .flatten();
+        forwarded.or(self.remote_addr.map(|addr| addr.ip()))
+    }
+
+    /// Credentials from an `Authorization: Basic` header as
+    /// `(username, password)`, or `None` if absent or malformed.
+    pub fn basic_auth(&self) -> Option<(String, String)> {
+        let authorization = self.headers.get("Authorization")?;
+        let (scheme, credentials) = authorization.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return None;
+        }
+        let decoded = BASE64.decode(credentials.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        Some((username.to_string(), password.to_string()))
+    }
+
+    /// Token from an `Authorization: Bearer` header, or `None` if absent or
+    /// another scheme is used.
+    pub fn bearer_token(&self) -> Option<&str> {
+        let authorization = self.headers.get("Authorization")?;
+        let (scheme, token) = authorization.trim().split_once(' ')?;
+        let token = token.trim();
+        (scheme.eq_ignore_ascii_case("Bearer") && !token.is_empty()).then_some(token)
+    }
+
+    /// Reads the body and deserializes it as JSON. Fails unless the request
+    /// declares an `application/json` content type.
+    #[cfg(feature = "json")]
+    pub async fn json<T: DeserializeOwned>(&mut self) -> Result<T, HTTPError> {
+        let is_json = self
+            .headers
+            .get("Content-Type")
+            .is_some_and(|value| value.contains("application/json"));
+        if !is_json {
+            return Err(HTTPError::ParsingError {
+                header: "Content-Type: expected application/json".to_string(),
+            });
+        }
+
+        let body = self.body.read_to_end().await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Reads an `application/x-www-form-urlencoded` body. Repeated fields
+    /// keep their first value; see `form_multi` for all of them.
+    pub async fn form(&mut self) -> Result<HashMap<String, String>, HTTPError> {
+        let fields = self.form_multi().await?;
+        Ok(fields
+            .into_iter()
+            .map(|(key, mut values)| (key, values.swap_remove(0)))
+            .collect())
+    }
+
+    /// Reads an `application/x-www-form-urlencoded` body, keeping every
+    /// value of repeated fields in order. Fails if the request declares
+    /// another content type or the body is not valid form encoding.
+    pub async fn form_multi(&mut self) -> Result<HashMap<String, Vec<String>>, HTTPError> {
+        let is_form = self.headers.get("Content-Type").is_some_and(|value| {
+            value.split(';').next().is_some_and(|essence| {
+                essence
+                    .trim()
+                    .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+            })
+        });
+        if !is_form {
+            return Err(HTTPError::ParsingError {
+                header: "Content-Type: expected application/x-www-form-urlencoded".to_string(),
+            });
+        }
+
+        let body = self.body.read_to_end().await?;
+        let invalid = || HTTPError::ParsingError {
+            header: "form body".to_string(),
+        };
+        let body = String::from_utf8(body).map_err(|_| invalid())?;
+        parse_query(&body).ok_or_else(invalid)
+    }
+
+    /// Reads the body and splits it into `multipart/form-data` fields. Fails
+    /// unless the content type is multipart with a boundary.
+    #[cfg(feature = "multipart")]
+    pub async fn multipart(&mut self) -> Result<Vec<MultipartField>, HTTPError> {
+        let content_type = self.headers.get("Content-Type").unwrap_or_default();
+        let parser = MultipartParser::from_content_type(content_type)?;
+        let body = self.body.read_to_end().await?;
+        parser.parse(&body)
+    }
+}
+
+/// An address from `Forwarded` or `X-Forwarded-For`, optionally quoted
+/// and with a port: `192.0.2.1`, `"192.0.2.1:8080"` or
+/// `"[2001:db8::1]:4711"`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            let ip = node.strip_prefix('[')?.strip_suffix(']')?;
+            ip.parse().ok()
+        })
+}
+
+fn default_port(scheme: &str) -> u16 {
+    if scheme == "https" {
+        443
+    } else {
+        80
+    }
+}
+
+/// `example.com:4488` -> (`example.com`, `4488`), `[::1]:4488` ->
+/// (`[::1]`, `4488`), `[::1]` -> (`[::1]`, none).
+fn split_port(host: &str) -> (&str, Option<&str>) {
+    match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => (&host[..colon], Some(&host[colon + 1..])),
+        _ => (host, None),
+    }
+}
+
+/// Headers holding credentials, hidden by `Request`'s `Debug` unless
+/// `ServerConfig::redacted_headers` says otherwise.
+pub(crate) fn default_redacted_headers() -> Arc<[String]> {
+    static DEFAULT: LazyLock<Arc<[String]>> = LazyLock::new(|| {
+        [
+            "Authorization",
+            "Proxy-Authorization",
+            "Cookie",
+            "Set-Cookie",
+        ]
+        .map(String::from)
+        .into()
+    });
+    DEFAULT.clone()
+}
+
+/// Copies every field but `extensions`, whose values are type-erased; the
+/// clone starts with none. Cloning a buffered body copies all of its
+/// bytes, while a body still streaming in cannot be copied and the clone
+/// gets an empty one: use `Request::buffered_clone` to keep it.
+impl Clone for Request {
+    fn clone(&self) -> Request {
+        Request {
+            body: self.body.try_clone().unwrap_or_else(Body::empty),
+            ..self.copy_head()
+        }
+    }
+}
+
+/// Shows every field, with the values of `redacted_headers` replaced by
+/// `[REDACTED]`. Cookies are hidden along with the `Cookie` header.
+impl fmt::Debug for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let redacted = |name: &str| {
+            self.redacted_headers
+                .iter()
+                .any(|redacted| redacted.eq_ignore_ascii_case(name))
+        };
+        let headers = Redacted(|f: &mut fmt::Formatter<'_>| {
+            let mut map = f.debug_map();
+            for (name, value) in self.headers.iter() {
+                map.entry(&name, &if redacted(name) { REDACTED } else { value });
+            }
+            map.finish()
+        });
+        let cookies = Redacted(|f: &mut fmt::Formatter<'_>| {
+            if redacted("Cookie") {
+                let names = self.cookies.keys().map(|name| (name, REDACTED));
+                f.debug_map().entries(names).finish()
+            } else {
+                fmt::Debug::fmt(&self.cookies, f)
+            }
+        });
+        f.debug_struct("Request")
+            .field("method", &self.method)
+            .field("path", &self.path)
+            .field("version", &self.version)
+            .field("headers", &headers)
+            .field("body", &self.body)
+            .field("keep_alive", &self.keep_alive)
+            .field("params", &self.params)
+            .field("wildcard", &self.wildcard)
+            .field("query", &self.query)
+            .field("query_multi", &self.query_multi)
+            .field("cookies", &cookies)
+            .field("peer_addr", &self.peer_addr)
+            .field("remote_addr", &self.remote_addr)
+            .field("tls", &self.tls)
+            .field("media_type", &self.media_type)
+            .field("extensions", &self.extensions)
+            .field("id", &self.id)
+            .field("span", &self.span)
+            .finish_non_exhaustive()
+    }
+}
+
+/// One line for logs, e.g. `GET /path HTTP/1.1 (3 headers, 42 bytes)`.
+/// The size is the declared `Content-Length` and left out without one.
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let headers = self.headers.len();
+        let plural = if headers == 1 { "" } else { "s" };
+        write!(
+            f,
+            "{} {} {} ({headers} header{plural}",
+            self.method, self.path, self.version
+        )?;
+        let length = self.headers.get("Content-Length");
+        if let Some(length) = length.and_then(|length| length.trim().parse::<u64>().ok()) {
+            write!(f, ", {length} bytes")?;
+        }
+        f.write_str(")")
+    }
+}
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Formats with the closure it wraps.
+struct Redacted<F>(F);
+
+impl<F> fmt::Debug for Redacted<F>
+where
+    F: Fn(&mut fmt::Formatter<'_>) -> fmt::Result,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.0)(f)
+    }
+}
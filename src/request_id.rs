@@ -0,0 +1,47 @@
+use uuid::Uuid;
+
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::BoxFuture;
+
+/// Longest client-supplied ID that is kept; longer ones are replaced.
+const MAX_ID_LENGTH: usize = 200;
+
+/// Gives every request a correlation ID: the client's `X-Request-ID` when
+/// it sent a usable one, otherwise a fresh UUID v4. The ID is stored in
+/// `Request::id`, recorded as `request_id` on the request's root tracing
+/// span and echoed in the response's `X-Request-ID`.
+///
+/// Push it before `LoggingMiddleware` and other layers that should see
+/// the ID.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestIdMiddleware;
+
+impl RequestIdMiddleware {
+    pub fn new() -> RequestIdMiddleware {
+        RequestIdMiddleware
+    }
+}
+
+/// Client IDs end up in logs, so only short runs of visible ASCII are
+/// trusted.
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LENGTH && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+impl Middleware for RequestIdMiddleware {
+    fn handle(&self, mut request: Request, next: Next) -> BoxFuture<Response> {
+        let id = match request.headers.get("X-Request-ID").map(str::trim) {
+            Some(id) if is_valid_id(id) => id.to_string(),
+            _ => Uuid::new_v4().to_string(),
+        };
+        request.span.record("request_id", id.as_str());
+        request.id = id.clone();
+        Box::pin(async move {
+            let mut response = next.run(request).await;
+            response.headers.set("X-Request-ID", &id);
+            response
+        })
+    }
+}
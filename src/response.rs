@@ -0,0 +1,622 @@
+use std::fmt;
+use std::io;
+#[cfg(feature = "mmap")]
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+#[cfg(feature = "json")]
+use serde::Serialize;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+use crate::body::Reader;
+use crate::codes::HTTPCodes;
+use crate::cookie::Cookie;
+use crate::error::HTTPError;
+use crate::headers::{HeaderName, Headers};
+use crate::mime::mime_type_for_extension;
+use crate::router::{BoxError, BoxFuture};
+
+/// Source of a body sent with `Transfer-Encoding: chunked`, for responses
+/// whose size is not known when the headers go out.
+pub enum ChunkedBody {
+    /// Read until EOF, one chunk per read.
+    Reader(Box<dyn AsyncRead + Send + Unpin>),
+    /// Every received buffer becomes a chunk; the body ends when all
+    /// senders are dropped.
+    Channel(mpsc::Receiver<Vec<u8>>),
+    /// A memory-mapped file, see `Response::send_file_mapped_above`,
+    /// written from the cursor's position on.
+    #[cfg(feature = "mmap")]
+    Mapped(Cursor<Mmap>),
+}
+
+impl ChunkedBody {
+    pub(crate) async fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            ChunkedBody::Reader(reader) => {
+                let mut buffer = vec![0; 8192];
+                let read = reader.read(&mut buffer).await?;
+                buffer.truncate(read);
+                Ok((read > 0).then_some(buffer))
+            }
+            ChunkedBody::Channel(receiver) => Ok(receiver.recv().await),
+            #[cfg(feature = "mmap")]
+            ChunkedBody::Mapped(map) => {
+                let mut buffer = vec![0; 64 * 1024];
+                let read = Read::read(map, &mut buffer)?;
+                buffer.truncate(read);
+                Ok((read > 0).then_some(buffer))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ChunkedBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkedBody::Reader(_) => f.write_str("ChunkedBody::Reader"),
+            ChunkedBody::Channel(_) => f.write_str("ChunkedBody::Channel"),
+            #[cfg(feature = "mmap")]
+            ChunkedBody::Mapped(_) => f.write_str("ChunkedBody::Mapped"),
+        }
+    }
+}
+
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Callback run with the connection after a `101 Switching Protocols`
+/// response, or a `CONNECT` tunnel's `200`: its read and write halves, and
+/// the largest message the new protocol should accept.
+pub(crate) struct OnUpgrade(Box<dyn FnOnce(Reader, Writer, usize) -> BoxFuture<()> + Send>);
+
+impl OnUpgrade {
+    pub(crate) fn new<F>(on_upgrade: F) -> OnUpgrade
+    where
+        F: FnOnce(Reader, Writer, usize) -> BoxFuture<()> + Send + 'static,
+    {
+        OnUpgrade(Box::new(on_upgrade))
+    }
+
+    pub(crate) async fn run(self, reader: Reader, writer: Writer, limit: usize) {
+        (self.0)(reader, writer, limit).await
+    }
+}
+
+impl fmt::Debug for OnUpgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnUpgrade")
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub code: HTTPCodes,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// When set, replaces `body` and is streamed with chunked encoding,
+    /// unless `length` is known.
+    pub chunked: Option<ChunkedBody>,
+    /// Size of the streamed body. When set, `chunked` is sent as-is with
+    /// this `Content-Length` instead of being chunk encoded.
+    pub length: Option<u64>,
+    /// Each cookie is written as its own `Set-Cookie` header.
+    pub cookies: Vec<Cookie>,
+    /// Whether the connection may stay open after this response. The
+    /// connection is closed if either this or the request says so.
+    pub keep_alive: bool,
+    /// Set by `upgrade_websocket`; takes over the connection once this
+    /// response has been written.
+    pub(crate) upgrade: Option<OnUpgrade>,
+    /// Set when answering `HEAD`: the head is written exactly as for `GET`,
+    /// but the body is not.
+    pub(crate) omit_body: bool,
+    /// Why a handler failed, see `IntoResponse`; taken by the router for
+    /// `Router::internal_error`.
+    pub(crate) error: Option<BoxError>,
+    /// Reason phrase sent instead of the code's canonical one.
+    pub(crate) reason: Option<&'static str>,
+}
+
+/// What route handlers may return: a `Response`, or a `Result` whose
+/// error is logged and answered by `Router::internal_error`, with a plain
+/// `500 Internal Server Error` by default.
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+impl<E> IntoResponse for Result<Response, E>
+where
+    E: Into<BoxError>,
+{
+    fn into_response(self) -> Response {
+        self.unwrap_or_else(|err| {
+            let mut response = Response::internal_error();
+            response.error = Some(err.into());
+            response
+        })
+    }
+}
+
+impl Response {
+    pub fn new(code: HTTPCodes) -> Response {
+        Response {
+            code,
+            headers: Headers::new(),
+            body: Vec::new(),
+            chunked: None,
+            length: None,
+            cookies: Vec::new(),
+            keep_alive: true,
+            upgrade: None,
+            omit_body: false,
+            error: None,
+            reason: None,
+        }
+    }
+
+    /// `200 OK` with `value` serialized as JSON, or `500 Internal Server
+    /// Error` if serialization fails.
+    #[cfg(feature = "json")]
+    pub fn json<T: Serialize>(value: &T) -> Response {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response::new(HTTPCodes::OK)
+                .header("Content-Type", "application/json")
+                .body(body),
+            Err(_) => Response::new(HTTPCodes::InternalServerError),
+        }
+    }
+
+    /// `200 OK` with `body` as `text/html; charset=utf-8`.
+    pub fn html(body: &str) -> Response {
+        Response::new(HTTPCodes::OK)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(body)
+    }
+
+    /// `200 OK` with `body` as `text/plain; charset=utf-8`.
+    pub fn text(body: &str) -> Response {
+        Response::new(HTTPCodes::OK)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(body)
+    }
+
+    /// `401 Unauthorized` asking the client for Basic credentials for
+    /// `realm`.
+    pub fn www_authenticate(realm: &str) -> Response {
+        let realm = realm.replace('\\', "\\\\").replace('"', "\\\"");
+        Response::new(HTTPCodes::Unauthorized)
+            .header("WWW-Authenticate", &format!("Basic realm=\"{realm}\""))
+    }
+
+    /// The default answer when a handler fails.
+    pub(crate) fn internal_error() -> Response {
+        Response::new(HTTPCodes::InternalServerError)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body("Internal Server Error")
+    }
+
+    /// `301 Moved Permanently` when `permanent`, `302 Found` otherwise,
+    /// pointing the client at `location`. Fails if `location` contains
+    /// `\r`, `\n` or NUL.
+    pub fn redirect(location: &str, permanent: bool) -> Result<Response, HTTPError> {
+        let code = if permanent {
+            HTTPCodes::MovedPermanently
+        } else {
+            HTTPCodes::Found
+        };
+        Response::new(code).try_header("Location", location)
+    }
+
+    /// `303 See Other` pointing at `location`, which the client fetches
+    /// with `GET` whatever the original method was. The usual answer to a
+    /// successful form `POST`. Fails like `redirect`.
+    pub fn see_other(location: &str) -> Result<Response, HTTPError> {
+        Response::new(HTTPCodes::SeeOther).try_header("Location", location)
+    }
+
+    /// Replaces the status. A body already set is dropped if the new
+    /// status must not carry one.
+    pub fn status(mut self, code: HTTPCodes) -> Self {
+        self.code = code;
+        if !self.allows_body() {
+            self.body.clear();
+        }
+        self
+    }
+
+    /// Adds a header. Panics if `key` is not a valid `HeaderName` or
+    /// `value` contains `\r`, `\n` or NUL; see `try_header`.
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.headers.add(key, value);
+        self
+    }
+
+    /// Adds a header whose name or value comes from user input.
+    pub fn try_header(mut self, key: &str, value: &str) -> Result<Self, HTTPError> {
+        self.headers.append(HeaderName::new(key)?, value)?;
+        Ok(self)
+    }
+
+    /// Adds `headers` to `Vary`, telling caches that the response depends
+    /// on those request headers, e.g. `Accept-Encoding` for compressed
+    /// bodies. Names already listed are not repeated.
+    ///
+    /// `Vary: *` says the response depends on something other than request
+    /// headers, such as the client's address or the time, and effectively
+    /// makes it uncacheable by shared caches. Once set it absorbs any
+    /// further names.
+    pub fn vary(&mut self, headers: &[&str]) -> &mut Self {
+        let mut vary = self.headers.get_all("Vary");
+        for header in headers {
+            if vary.iter().any(|listed| listed == "*") {
+                break;
+            }
+            if *header == "*" {
+                vary = vec!["*".to_string()];
+            } else if !vary
+                .iter()
+                .any(|listed| listed.eq_ignore_ascii_case(header))
+            {
+                vary.push(header.to_string());
+            }
+        }
+        if !vary.is_empty() {
+            self.headers.set("Vary", &vary.join(", "));
+        }
+        self
+    }
+
+    /// Sets the body. Ignored for statuses that must not carry one, such as
+    /// `204 No Content`.
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        if self.allows_body() {
+            self.body = body.into();
+        }
+        self
+    }
+
+    /// Same as `body`, for chaining with a byte buffer.
+    pub fn body_bytes(self, body: Vec<u8>) -> Self {
+        self.body(body)
+    }
+
+    /// Same as `body`, for chaining with text.
+    pub fn body_str(self, body: impl Into<String>) -> Self {
+        self.body(body.into())
+    }
+
+    /// Streams the body from `reader` with chunked encoding.
+    pub fn chunked_reader<R>(mut self, reader: R) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        if self.allows_body() {
+            self.chunked = Some(ChunkedBody::Reader(Box::new(reader)));
+        }
+        self
+    }
+
+    /// Streams `length` bytes from `reader` with a `Content-Length` header,
+    /// e.g. for a file whose size is known up front.
+    pub fn sized_reader<R>(mut self, reader: R, length: u64) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        if self.allows_body() {
+            self.chunked = Some(ChunkedBody::Reader(Box::new(reader)));
+            self.length = Some(length);
+        }
+        self
+    }
+
+    /// `200 OK` with the file at `path` as its body, along with its
+    /// `Content-Length`, `Last-Modified` and a `Content-Type` guessed from
+    /// the extension. The file is copied to the connection while the
+    /// response is written rather than read into memory first.
+    pub async fn send_file(path: &Path) -> io::Result<Response> {
+        let (file, length, response) = Response::open_file(path).await?;
+        Ok(response.sized_reader(file, length))
+    }
+
+    /// `send_file`, but files larger than `threshold` bytes are
+    /// memory-mapped and written straight from the mapping.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be truncated or modified while the response is
+    /// alive: reading a mapped page past the file's new end kills the
+    /// process with `SIGBUS` on most systems. Only use this for files that
+    /// are replaced rather than rewritten in place.
+    #[cfg(feature = "mmap")]
+    pub async unsafe fn send_file_mapped_above(
+        path: &Path,
+        threshold: u64,
+    ) -> io::Result<Response> {
+        let (file, length, mut response) = Response::open_file(path).await?;
+        if length <= threshold {
+            return Ok(response.sized_reader(file, length));
+        }
+        let file = file.into_std().await;
+        // SAFETY: the mapping is only read, and the caller guarantees that
+        // the file is left alone while it is mapped.
+        let map = tokio::task::spawn_blocking(move || unsafe { Mmap::map(&file) })
+            .await
+            .map_err(io::Error::other)??;
+        if response.allows_body() {
+            response.chunked = Some(ChunkedBody::Mapped(Cursor::new(map)));
+            response.length = Some(length);
+        }
+        Ok(response)
+    }
+
+    /// Opens a regular file for `send_file`, returning it with its length
+    /// and the response headers.
+    async fn open_file(path: &Path) -> io::Result<(File, u64, Response)> {
+        let file = File::open(path).await?;
+        let metadata = file.metadata().await?;
+        if !metadata.is_file() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        let mut response = Response::new(HTTPCodes::OK).header(
+            "Content-Type",
+            mime_type_for_extension(extension.unwrap_or_default()),
+        );
+        if let Ok(modified) = metadata.modified() {
+            response = response.last_modified(modified);
+        }
+        Ok((file, metadata.len(), response))
+    }
+
+    /// Streams every buffer received on `receiver` as one chunk.
+    pub fn chunked_channel(mut self, receiver: mpsc::Receiver<Vec<u8>>) -> Self {
+        if self.allows_body() {
+            self.chunked = Some(ChunkedBody::Channel(receiver));
+        }
+        self
+    }
+
+    /// Adds a `Set-Cookie` header. Panics if the cookie does not pass
+    /// `Cookie::validate`; see `try_set_cookie`.
+    pub fn set_cookie(self, cookie: Cookie) -> Self {
+        self.try_set_cookie(cookie)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Adds a `Set-Cookie` header for a cookie built from user input.
+    pub fn try_set_cookie(mut self, cookie: Cookie) -> Result<Self, HTTPError> {
+        cookie.validate()?;
+        self.cookies.push(cookie);
+        Ok(self)
+    }
+
+    /// `Cache-Control: public, max-age=<seconds>`: any cache may store the
+    /// response and reuse it for `max_age` without revalidating.
+    pub fn cache_control_public(self, max_age: Duration) -> Self {
+        self.cache_directive("public")
+            .cache_directive(&format!("max-age={}", max_age.as_secs()))
+    }
+
+    /// `Cache-Control: private`: only the client's own cache may store the
+    /// response, e.g. for pages personalised to a user.
+    pub fn cache_control_private(self) -> Self {
+        self.cache_directive("private")
+    }
+
+    /// `Cache-Control: no-cache`: caches may store the response but must
+    /// revalidate it before every reuse.
+    pub fn no_cache(self) -> Self {
+        self.cache_directive("no-cache")
+    }
+
+    /// `Cache-Control: no-store`: nothing may store the response at all.
+    pub fn no_store(self) -> Self {
+        self.cache_directive("no-store")
+    }
+
+    /// `Cache-Control: immutable`: the response never changes while fresh,
+    /// so clients need not revalidate it on reload. Meant for fingerprinted
+    /// assets, together with a long `cache_control_public`.
+    pub fn immutable(self) -> Self {
+        self.cache_directive("immutable")
+    }
+
+    /// `Cache-Control: no-transform`: proxies must not recompress or
+    /// otherwise alter the body.
+    pub fn no_transform(self) -> Self {
+        self.cache_directive("no-transform")
+    }
+
+    /// Sets `Last-Modified` to `time` as an HTTP-date. A `GET` carrying
+    /// an `If-Modified-Since` no earlier than it is answered with
+    /// `304 Not Modified` instead.
+    pub fn last_modified(mut self, time: SystemTime) -> Self {
+        self.headers
+            .set("Last-Modified", &httpdate::fmt_http_date(time));
+        self
+    }
+
+    /// Adds `directive` to `Cache-Control`, replacing an earlier value of
+    /// the same directive. `public` and `private` replace each other.
+    fn cache_directive(mut self, directive: &str) -> Self {
+        fn key(directive: &str) -> &str {
+            match directive.split('=').next().unwrap_or_default().trim() {
+                "private" => "public",
+                name => name,
+            }
+        }
+        let mut directives = self.headers.get_all("Cache-Control");
+        directives.retain(|existing| !key(existing).eq_ignore_ascii_case(key(directive)));
+        directives.push(directive.to_string());
+        self.headers.set("Cache-Control", &directives.join(", "));
+        self
+    }
+
+    pub fn keep_alive(mut self, keep_alive: bool) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        self.chunked.is_some()
+    }
+
+    /// 1xx, 204 and 304 responses never have a body or `Content-Length`.
+    pub(crate) fn allows_body(&self) -> bool {
+        let code = self.code.code();
+        code >= 200 && code != 204 && code != 304
+    }
+
+    /// Length announced in `Content-Length`, or `None` to chunk encode the
+    /// body. A `Content-Length` header set by the handler is honoured for
+    /// streamed bodies of unknown length and for `HEAD` responses, whose
+    /// body is not sent; buffered bodies always announce their real size.
+    pub(crate) fn content_length(&self) -> Option<u64> {
+        let declared = || {
+            self.headers
+                .get("Content-Length")
+                .and_then(|length| length.trim().parse().ok())
+        };
+        match &self.chunked {
+            Some(_) => self.length.or_else(declared),
+            None if self.omit_body && self.body.is_empty() => declared().or(Some(0)),
+            None => Some(self.body.len() as u64),
+        }
+    }
+
+    /// Serializes the status line, headers and body to `stream`. A
+    /// `Content-Type` set by the handler is kept, `text/html` is assumed
+    /// otherwise. `Content-Length` follows `content_length`, and
+    /// `Transfer-Encoding` and `Connection` are always derived from the
+    /// response itself.
+    pub async fn write_to<W>(&mut self, stream: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let reason = self.reason.unwrap_or(self.code.as_str());
+        let mut head = format!("HTTP/1.1 {} {reason}\r\n", self.code.code());
+        for (key, value) in self.headers.iter() {
+            if matches!(key, "content-length" | "transfer-encoding" | "connection") {
+                continue;
+            }
+            head.push_str(&format!("{key}: {value}\r\n"));
+        }
+        // `cookies` is public, so cookies may not have gone through
+        // `set_cookie`; one that would break the head is left out.
+        for cookie in self
+            .cookies
+            .iter()
+            .filter(|cookie| cookie.validate().is_ok())
+        {
+            head.push_str(&format!("set-cookie: {cookie}\r\n"));
+        }
+
+        // A tunnel's `200` has no body; the connection carries raw bytes
+        // right after the head.
+        let allows_body = self.allows_body() && self.upgrade.is_none();
+        let length = self.content_length();
+        if allows_body {
+            let has_body = !self.body.is_empty() || self.chunked.is_some();
+            if has_body && !self.headers.contains("Content-Type") {
+                head.push_str("content-type: text/html; charset=utf-8\r\n");
+            }
+            match length {
+                Some(length) => head.push_str(&format!("content-length: {length}\r\n")),
+                None => head.push_str("transfer-encoding: chunked\r\n"),
+            }
+        }
+        let connection = match &self.upgrade {
+            Some(_) if self.code == HTTPCodes::SwitchingProtocols => Some("upgrade"),
+            Some(_) => None,
+            None if self.keep_alive => Some("keep-alive"),
+            None => Some("close"),
+        };
+        if let Some(connection) = connection {
+            head.push_str(&format!("connection: {connection}\r\n"));
+        }
+        head.push_str("\r\n");
+
+        stream.write_all(head.as_bytes()).await?;
+        if self.omit_body {
+            return Ok(());
+        }
+        match (self.chunked.as_mut(), length) {
+            // Copied straight through, but never past the announced length
+            // or the next response on the connection would be corrupted.
+            (Some(ChunkedBody::Reader(reader)), Some(length)) if allows_body => {
+                tokio::io::copy(&mut reader.take(length), stream).await?;
+            }
+            #[cfg(feature = "mmap")]
+            (Some(ChunkedBody::Mapped(map)), Some(length)) if allows_body => {
+                let start = (map.position() as usize).min(map.get_ref().len());
+                let rest = &map.get_ref()[start..];
+                let end = rest
+                    .len()
+                    .min(usize::try_from(length).unwrap_or(usize::MAX));
+                stream.write_all(&rest[..end]).await?;
+                map.set_position((start + end) as u64);
+            }
+            (Some(chunked), Some(_)) if allows_body => {
+                while let Some(chunk) = chunked.next_chunk().await? {
+                    stream.write_all(&chunk).await?;
+                }
+            }
+            (Some(chunked), None) if allows_body => {
+                while let Some(chunk) = chunked.next_chunk().await? {
+                    // An empty chunk would terminate the body early.
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    stream
+                        .write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+                        .await?;
+                    stream.write_all(&chunk).await?;
+                    stream.write_all(b"\r\n").await?;
+                    stream.flush().await?;
+                }
+                stream.write_all(b"0\r\n\r\n").await?;
+            }
+            _ if allows_body => stream.write_all(&self.body).await?,
+            _ => {}
+        }
+        stream.flush().await
+    }
+}
+
+/// `200 OK` with no headers and an empty body, to be built up from.
+impl Default for Response {
+    fn default() -> Response {
+        Response::new(HTTPCodes::OK)
+    }
+}
+
+/// Copies the status, headers, body bytes and cookies. A streamed body
+/// and a pending upgrade cannot be duplicated, so a clone has neither.
+impl Clone for Response {
+    fn clone(&self) -> Response {
+        Response {
+            code: self.code,
+            headers: self.headers.clone(),
+            body: self.body.clone(),
+            chunked: None,
+            length: None,
+            cookies: self.cookies.clone(),
+            keep_alive: self.keep_alive,
+            upgrade: None,
+            omit_body: self.omit_body,
+            error: None,
+            reason: self.reason,
+        }
+    }
+}
@@ -0,0 +1,652 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tracing::error;
+
+use crate::codes::HTTPCodes;
+use crate::error::HTTPError;
+use crate::method::HttpMethod;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::{IntoResponse, Response};
+use crate::sse::{event_stream, SseStream};
+
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// The error passed to `Router::internal_error` handlers.
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+type ErrorPage = dyn Fn(Request, BoxError) -> BoxFuture<Response> + Send + Sync;
+
+/// Anything that can turn a `Request` into a `Response`. Implemented for
+/// every `Fn(Request) -> impl Future<Output = impl IntoResponse>`, so
+/// handlers may return `Response` or `Result<Response, E>`.
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, request: Request) -> BoxFuture<Response>;
+}
+
+impl<F, Fut> Handler for F
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: IntoResponse,
+{
+    fn call(&self, request: Request) -> BoxFuture<Response> {
+        let handled = self(request);
+        Box::pin(async move { handled.await.into_response() })
+    }
+}
+
+#[derive(Clone)]
+enum Segment {
+    Static(String),
+    /// `:name` segment binding one path segment into `Request::params`.
+    Param(String),
+    /// `*name` segment binding the rest of the path. Only valid last.
+    Wildcard(String),
+}
+
+impl Segment {
+    fn parse(segment: &str) -> Segment {
+        if let Some(name) = segment.strip_prefix(':') {
+            Segment::Param(name.to_string())
+        } else if let Some(name) = segment.strip_prefix('*') {
+            Segment::Wildcard(name.to_string())
+        } else {
+            Segment::Static(segment.to_string())
+        }
+    }
+
+    fn parse_pattern(pattern: &str) -> Result<Vec<Segment>, HTTPError> {
+        let segments: Vec<Segment> = split_path(pattern).map(Segment::parse).collect();
+        let wildcard = segments
+            .iter()
+            .position(|segment| matches!(segment, Segment::Wildcard(_)));
+        if wildcard.is_some_and(|position| position != segments.len() - 1) {
+            return Err(HTTPError::InvalidRoute {
+                pattern: pattern.to_string(),
+                reason: "wildcard segment must be last".to_string(),
+            });
+        }
+        Ok(segments)
+    }
+
+    /// Whether both segments match the same path segments, whatever
+    /// their names.
+    fn same_matches(&self, other: &Segment) -> bool {
+        match (self, other) {
+            (Segment::Static(a), Segment::Static(b)) => a == b,
+            (Segment::Param(_), Segment::Param(_)) => true,
+            (Segment::Wildcard(_), Segment::Wildcard(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Lower is more specific; used to prefer static routes over params.
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Static(_) => 0,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 2,
+        }
+    }
+}
+
+/// The request path arrives decoded except for `%2F`, which only matters
+/// for finding segment boundaries; captured values get a plain `/`.
+fn decode_slashes(value: &str) -> String {
+    value.replace("%2F", "/")
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+struct Route {
+    /// `None` matches any method.
+    method: Option<HttpMethod>,
+    segments: Vec<Segment>,
+    /// Media types the handler can respond with; empty for any.
+    produces: Vec<String>,
+    handler: Arc<dyn Handler>,
+}
+
+impl Route {
+    fn matches(&self, path: &[&str]) -> Option<HashMap<String, String>> {
+        let has_wildcard = matches!(self.segments.last(), Some(Segment::Wildcard(_)));
+        let fixed = self.segments.len() - usize::from(has_wildcard);
+        if path.len() < fixed || (!has_wildcard && path.len() != fixed) {
+            return None;
+        }
+
+        let mut params = HashMap::new();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Static(expected) if expected == path[i] => {}
+                Segment::Static(_) => return None,
+                Segment::Param(name) => {
+                    params.insert(name.clone(), decode_slashes(path[i]));
+                }
+                Segment::Wildcard(name) => {
+                    params.insert(name.clone(), decode_slashes(&path[i..].join("/")));
+                }
+            }
+        }
+        Some(params)
+    }
+
+    fn rank(&self) -> Vec<u8> {
+        self.segments.iter().map(Segment::rank).collect()
+    }
+
+    /// Whether `self` matches every request `other` does: the same path
+    /// segments, and the same method or any method.
+    fn shadows(&self, other: &Route) -> bool {
+        (self.method.is_none() || self.method == other.method)
+            && self.segments.len() == other.segments.len()
+            && self
+                .segments
+                .iter()
+                .zip(&other.segments)
+                .all(|(a, b)| a.same_matches(b))
+    }
+
+    /// The pattern the route was registered with, less any slashes.
+    fn pattern(&self) -> String {
+        let segments: Vec<String> = self
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Static(text) => text.clone(),
+                Segment::Param(name) => format!(":{name}"),
+                Segment::Wildcard(name) => format!("*{name}"),
+            })
+            .collect();
+        format!("/{}", segments.join("/"))
+    }
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    /// Answers requests no route matches; `None` for the plain default.
+    not_found: Option<Arc<dyn Handler>>,
+    /// Answers requests whose handler panicked; `None` for the plain
+    /// default.
+    internal_error: Option<Arc<ErrorPage>>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Registers `handler` for `method` requests on `path`. Methods are
+    /// case-sensitive, see `HttpMethod`: `"propfind"` does not match
+    /// `PROPFIND` requests.
+    ///
+    /// Patterns may contain `:name` segments and a trailing `*name` segment;
+    /// a wildcard anywhere else is rejected with `HTTPError::InvalidRoute`.
+    ///
+    /// A route that an earlier one for the same method, or for any
+    /// method, would always shadow is a mistake, e.g. `GET /about` twice
+    /// or `/users/:name` after `/users/:id`. It is rejected with
+    /// `HTTPError::InvalidRoute`. Routes that differ only in what they
+    /// `produces` are fine.
+    pub fn route<H: Handler>(
+        &mut self,
+        method: &str,
+        path: &str,
+        handler: H,
+    ) -> Result<&mut Self, HTTPError> {
+        self.add(Some(HttpMethod::parse(method)), path, handler)
+    }
+
+    pub fn get<H: Handler>(&mut self, path: &str, handler: H) -> Result<&mut Self, HTTPError> {
+        self.add(Some(HttpMethod::Get), path, handler)
+    }
+
+    pub fn post<H: Handler>(&mut self, path: &str, handler: H) -> Result<&mut Self, HTTPError> {
+        self.add(Some(HttpMethod::Post), path, handler)
+    }
+
+    pub fn put<H: Handler>(&mut self, path: &str, handler: H) -> Result<&mut Self, HTTPError> {
+        self.add(Some(HttpMethod::Put), path, handler)
+    }
+
+    pub fn patch<H: Handler>(&mut self, path: &str, handler: H) -> Result<&mut Self, HTTPError> {
+        self.add(Some(HttpMethod::Patch), path, handler)
+    }
+
+    pub fn delete<H: Handler>(&mut self, path: &str, handler: H) -> Result<&mut Self, HTTPError> {
+        self.add(Some(HttpMethod::Delete), path, handler)
+    }
+
+    /// Registers a `GET` route that streams Server-Sent Events. `handler`
+    /// runs in its own task with an `SseStream` for pushing events; the
+    /// response stays open until the stream is dropped.
+    pub fn sse<F, Fut>(&mut self, path: &str, handler: F) -> Result<&mut Self, HTTPError>
+    where
+        F: Fn(Request, SseStream) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.get(path, move |request| {
+            let response = event_stream(request, handler.clone());
+            async move { response }
+        })
+    }
+
+    /// Registers `handler` for every method on `path`.
+    pub fn any<H: Handler>(&mut self, path: &str, handler: H) -> Result<&mut Self, HTTPError> {
+        self.add(None, path, handler)
+    }
+
+    fn add<H: Handler>(
+        &mut self,
+        method: Option<HttpMethod>,
+        path: &str,
+        handler: H,
+    ) -> Result<&mut Self, HTTPError> {
+        self.push(Route {
+            method,
+            segments: Segment::parse_pattern(path)?,
+            produces: Vec::new(),
+            handler: Arc::new(handler),
+        })?;
+        Ok(self)
+    }
+
+    /// Adds `route` unless an earlier route serving any media type
+    /// already matches the same requests, which would leave it
+    /// unreachable; see `route`.
+    fn push(&mut self, route: Route) -> Result<(), HTTPError> {
+        let shadowing = self
+            .routes
+            .iter()
+            .find(|existing| existing.produces.is_empty() && existing.shadows(&route));
+        if let Some(existing) = shadowing {
+            let method = existing
+                .method
+                .as_ref()
+                .map_or("any method", HttpMethod::as_str);
+            return Err(HTTPError::InvalidRoute {
+                pattern: route.pattern(),
+                reason: format!("shadowed by {method} {}", existing.pattern()),
+            });
+        }
+        self.routes.push(route);
+        Ok(())
+    }
+
+    /// Mounts every route of `sub_router` under `prefix`, e.g. `"/api"`
+    /// turns its `/users/:id` into `/api/users/:id`. Slashes around
+    /// `prefix` do not matter. The routes keep their methods and what
+    /// they `produces`, and compete with this router's routes as if they
+    /// had been added here.
+    ///
+    /// A wildcard segment in `prefix` is rejected with
+    /// `HTTPError::InvalidRoute`, since it would not be last, and so are
+    /// mounted routes that duplicate existing ones, as for `route`. The
+    /// `not_found` handler of `sub_router` is not carried over.
+    pub fn scope(&mut self, prefix: &str, sub_router: Router) -> Result<&mut Self, HTTPError> {
+        let prefix_segments = Segment::parse_pattern(prefix)?;
+        if prefix_segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Wildcard(_)))
+        {
+            return Err(HTTPError::InvalidRoute {
+                pattern: prefix.to_string(),
+                reason: "wildcard segment must be last".to_string(),
+            });
+        }
+        for mut route in sub_router.routes {
+            let mut segments = prefix_segments.clone();
+            segments.append(&mut route.segments);
+            route.segments = segments;
+            self.push(route)?;
+        }
+        Ok(self)
+    }
+
+    /// Like `scope`, and runs `middleware` around each of the mounted
+    /// routes' handlers. It runs after the server-wide middleware and
+    /// only for requests that reach one of these routes, with their
+    /// `params` already bound.
+    pub fn scope_with_middleware<M: Middleware>(
+        &mut self,
+        prefix: &str,
+        middleware: M,
+        mut sub_router: Router,
+    ) -> Result<&mut Self, HTTPError> {
+        let middleware: Arc<dyn Middleware> = Arc::new(middleware);
+        for route in &mut sub_router.routes {
+            route.handler = Arc::new(Scoped {
+                middleware: middleware.clone(),
+                handler: route.handler.clone(),
+            });
+        }
+        self.scope(prefix, sub_router)
+    }
+
+    /// Answers requests whose path matches no route with `handler`
+    /// instead of the default plain-text `404 Not Found`, e.g. with a
+    /// branded HTML page or a JSON error. It gets the original request,
+    /// path included. Requests for a known path with the wrong method
+    /// still get `405 Method Not Allowed`.
+    pub fn not_found<H: Handler>(&mut self, handler: H) -> &mut Self {
+        self.not_found = Some(Arc::new(handler));
+        self
+    }
+
+    /// Answers requests whose handler panicked or returned an `Err` with
+    /// `handler` instead of the default plain-text `500 Internal Server
+    /// Error`. It gets a copy of the request without its body or
+    /// extensions, and the panic message or the returned error. The error
+    /// is logged either way, and the panic hook prints a panic's backtrace
+    /// as usual when `RUST_BACKTRACE` is set.
+    ///
+    /// Setting it makes every routed request keep that copy while its
+    /// handler runs.
+    pub fn internal_error<F, Fut>(&mut self, handler: F) -> &mut Self
+    where
+        F: Fn(Request, BoxError) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.internal_error = Some(Arc::new(move |request, err| {
+            Box::pin(handler(request, err))
+        }));
+        self
+    }
+
+    /// Declares the media types the most recently added route can respond
+    /// with, in order of preference, as in
+    /// `router.get("/data", handler)?.produces(["application/json", "text/csv"])`.
+    /// The type negotiated from the request's `Accept` header is passed on
+    /// as `Request::media_type`. Several routes for the same path and
+    /// method may each produce different types; the one offering the
+    /// client's most preferred type handles the request. When none can
+    /// satisfy `Accept`, the answer is `406 Not Acceptable` listing the
+    /// available types.
+    pub fn produces<I, S>(&mut self, media_types: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        if let Some(route) = self.routes.last_mut() {
+            route.produces = media_types.into_iter().map(Into::into).collect();
+        }
+        self
+    }
+
+    /// Dispatches `request` to the most specific matching route, or answers
+    /// with `404 Not Found` when nothing matches. Static segments win over
+    /// `:param` segments, comparing from the left. `HEAD` requests without
+    /// a `HEAD` route of their own are handled by the matching `GET` route;
+    /// the connection drops the body.
+    ///
+    /// When the path matches but the method does not, the answer is `405
+    /// Method Not Allowed`, except for `OPTIONS`, which gets `200 OK`. Both
+    /// carry an `Allow` header listing the methods registered for the
+    /// path, or for any path when an `OPTIONS` target is `*`.
+    pub async fn handle(&self, mut request: Request) -> Response {
+        let path: Vec<&str> = split_path(&request.path).collect();
+
+        let accept = request.headers.accept_with_quality();
+        let method = request.method_enum();
+        let mut found = self.find(&method, &path, &accept);
+        if matches!(found, Found::None) && method == HttpMethod::Head {
+            found = self.find(&HttpMethod::Get, &path, &accept);
+        }
+        match found {
+            Found::Route(route, params, media_type) => {
+                let negotiated = media_type.is_some();
+                request.wildcard = match route.segments.last() {
+                    Some(Segment::Wildcard(name)) => params.get(name).cloned(),
+                    _ => None,
+                };
+                request.params = params;
+                request.media_type = media_type;
+                let copy = self.internal_error.as_ref().map(|_| request.copy_head());
+                let handled =
+                    match panic::catch_unwind(AssertUnwindSafe(|| route.handler.call(request))) {
+                        Ok(future) => CatchPanic(future).await,
+                        Err(payload) => Err(payload),
+                    };
+                // The request span carries the method and path.
+                let failed = match handled {
+                    Ok(mut response) => match response.error.take() {
+                        Some(err) => {
+                            error!(error = %err, "handler failed");
+                            Err((err, response))
+                        }
+                        None => Ok(response),
+                    },
+                    Err(payload) => {
+                        let err = panic_error(payload);
+                        error!(error = %err, "handler panicked");
+                        Err((err, Response::internal_error()))
+                    }
+                };
+                let mut response = match (failed, &self.internal_error, copy) {
+                    (Ok(response), _, _) => response,
+                    (Err((err, _)), Some(internal_error), Some(copy)) => {
+                        internal_error(copy, err).await
+                    }
+                    (Err((_, response)), _, _) => response,
+                };
+                if negotiated {
+                    response.vary(&["Accept"]);
+                }
+                response
+            }
+            Found::NotAcceptable(available) => {
+                let mut response = Response::new(HTTPCodes::NotAcceptable)
+                    .header("Content-Type", "text/plain; charset=utf-8")
+                    .body(available.join("\n"));
+                response.vary(&["Accept"]);
+                response
+            }
+            Found::None => {
+                let any_path = method == HttpMethod::Options && request.path == "*";
+                let allowed =
+                    self.allowed_methods(|route| any_path || route.matches(&path).is_some());
+                if allowed.is_empty() {
+                    match &self.not_found {
+                        Some(not_found) => not_found.call(request).await,
+                        None => Response::new(HTTPCodes::NotFound)
+                            .header("Content-Type", "text/plain; charset=utf-8")
+                            .body("Not Found"),
+                    }
+                } else if method == HttpMethod::Options {
+                    Response::new(HTTPCodes::OK).header("Allow", &allowed)
+                } else {
+                    Response::new(HTTPCodes::MethodNotAllowed).header("Allow", &allowed)
+                }
+            }
+        }
+    }
+
+    /// Methods with a route matching `path`, as an `Allow` header value.
+    /// Empty when there are none.
+    pub(crate) fn allowed_for(&self, path: &str) -> String {
+        let path: Vec<&str> = split_path(path).collect();
+        self.allowed_methods(|route| route.matches(&path).is_some())
+    }
+
+    /// Methods with a route for which `matches` holds, as an `Allow` header
+    /// value. Empty when there are none.
+    fn allowed_methods(&self, matches: impl Fn(&Route) -> bool) -> String {
+        let mut allowed: Vec<&str> = Vec::new();
+        for route in self.routes.iter().filter(|route| matches(route)) {
+            let Some(method) = &route.method else {
+                continue;
+            };
+            if !allowed.contains(&method.as_str()) {
+                allowed.push(method.as_str());
+            }
+            if *method == HttpMethod::Get && !allowed.contains(&"HEAD") {
+                allowed.push("HEAD");
+            }
+        }
+        if !allowed.is_empty() && !allowed.contains(&"OPTIONS") {
+            allowed.push("OPTIONS");
+        }
+        allowed.join(", ")
+    }
+
+    fn find<'a>(
+        &'a self,
+        method: &HttpMethod,
+        path: &[&str],
+        accept: &[(String, f32)],
+    ) -> Found<'a> {
+        let mut best: Option<Candidate> = None;
+        let mut available = Vec::new();
+        for route in &self.routes {
+            let method_matches = route
+                .method
+                .as_ref()
+                .is_none_or(|route_method| route_method == method);
+            if !method_matches {
+                continue;
+            }
+            let Some(params) = route.matches(path) else {
+                continue;
+            };
+            let (media_type, quality) = if route.produces.is_empty() {
+                (None, 1.0)
+            } else {
+                match negotiate(&route.produces, accept) {
+                    Some((media_type, quality)) => (Some(media_type), quality),
+                    None => {
+                        available.extend(route.produces.iter().cloned());
+                        continue;
+                    }
+                }
+            };
+            let rank = route.rank();
+            let better = best.as_ref().is_none_or(|best| {
+                rank < best.rank || (rank == best.rank && quality > best.quality)
+            });
+            if better {
+                best = Some(Candidate {
+                    route,
+                    rank,
+                    quality,
+                    params,
+                    media_type,
+                });
+            }
+        }
+        match best {
+            Some(best) => Found::Route(best.route, best.params, best.media_type),
+            None if !available.is_empty() => Found::NotAcceptable(available),
+            None => Found::None,
+        }
+    }
+}
+
+/// Resolves to the handler's response, or to the payload of a panic
+/// while polling it.
+struct CatchPanic(BoxFuture<Response>);
+
+impl Future for CatchPanic {
+    type Output = Result<Response, Box<dyn Any + Send>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match panic::catch_unwind(AssertUnwindSafe(|| self.0.as_mut().poll(cx))) {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(response)) => Poll::Ready(Ok(response)),
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    }
+}
+
+/// The message of a panic, which is a `&str` or a `String` unless the
+/// panic was raised with some other payload.
+fn panic_error(payload: Box<dyn Any + Send>) -> BoxError {
+    match payload.downcast::<String>() {
+        Ok(message) => (*message).into(),
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => (*message).into(),
+            Err(_) => "handler panicked".into(),
+        },
+    }
+}
+
+/// A route handler wrapped in middleware by `scope_with_middleware`.
+struct Scoped {
+    middleware: Arc<dyn Middleware>,
+    handler: Arc<dyn Handler>,
+}
+
+impl Handler for Scoped {
+    fn call(&self, request: Request) -> BoxFuture<Response> {
+        Next::scoped(self.middleware.clone(), self.handler.clone(), request)
+    }
+}
+
+struct Candidate<'a> {
+    route: &'a Route,
+    rank: Vec<u8>,
+    quality: f32,
+    params: HashMap<String, String>,
+    media_type: Option<String>,
+}
+
+/// Outcome of looking up a route for one method.
+enum Found<'a> {
+    Route(&'a Route, HashMap<String, String>, Option<String>),
+    /// Routes exist, but none produces a type the client accepts.
+    NotAcceptable(Vec<String>),
+    None,
+}
+
+/// Picks the produced type the client prefers, with its quality. Each
+/// type takes the quality of the most specific range covering it:
+/// `type/subtype` over `type/*` over `*/*`. Without an `Accept` header
+/// the first produced type is chosen.
+pub(crate) fn negotiate(produces: &[String], accept: &[(String, f32)]) -> Option<(String, f32)> {
+    if accept.is_empty() {
+        return produces.first().map(|media_type| (media_type.clone(), 1.0));
+    }
+    let mut best: Option<(&String, f32)> = None;
+    for media_type in produces {
+        let (kind, _) = media_type.split_once('/').unwrap_or((media_type, ""));
+        let mut quality = None;
+        let mut specificity = 0;
+        for (range, q) in accept {
+            let range = range.split(';').next().unwrap_or_default().trim();
+            let level = if range.eq_ignore_ascii_case(media_type) {
+                3
+            } else if range
+                .strip_suffix("/*")
+                .is_some_and(|range_kind| range_kind.eq_ignore_ascii_case(kind))
+            {
+                2
+            } else if range == "*/*" {
+                1
+            } else {
+                continue;
+            };
+            if level > specificity {
+                specificity = level;
+                quality = Some(*q);
+            }
+        }
+        let Some(quality) = quality.filter(|q| *q > 0.0) else {
+            continue;
+        };
+        if best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((media_type, quality));
+        }
+    }
+    best.map(|(media_type, quality)| (media_type.clone(), quality))
+}
@@ -0,0 +1,891 @@
+use std::fmt;
+use std::future::Future;
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::timeout;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn};
+
+use crate::access::AccessControl;
+use crate::connection::{serve_connection, Connection};
+use crate::error::{DefaultErrorHandler, ErrorHandler};
+use crate::expect::ContinueHandler;
+#[cfg(feature = "http2")]
+use crate::http2::{serve_h2, Peer};
+use crate::limit::RequestLimit;
+use crate::metrics::Metrics;
+use crate::middleware::{Middleware, MiddlewareStack};
+use crate::proxy::read_proxy_header;
+use crate::request::default_redacted_headers;
+use crate::router::Router;
+use crate::stats::{ServerStats, StatsMetrics};
+#[cfg(feature = "tls")]
+use crate::tls::{load_tls_config, TlsConfig};
+
+/// Tunables shared by every connection a `Server` accepts.
+#[derive(Clone)]
+pub struct ServerConfig {
+    /// Deadline for receiving a request's headers and body. A client that
+    /// misses it gets `408 Request Timeout` and is disconnected.
+    pub read_timeout: Duration,
+    /// Deadline for writing a response.
+    pub write_timeout: Duration,
+    /// How long a persistent connection may sit idle between requests.
+    /// `None` leaves idle connections to `read_timeout`.
+    pub keep_alive_timeout: Option<Duration>,
+    /// How long `Server::run` waits, once shut down, for open connections
+    /// to finish their current request. Connections still open then are
+    /// dropped.
+    pub shutdown_timeout: Duration,
+    /// Most requests served on one persistent connection. The response to
+    /// the last one carries `Connection: close`.
+    pub keep_alive_max: usize,
+    /// Largest request body accepted, in bytes. Larger bodies are answered
+    /// with `413 Content Too Large` and the connection is closed.
+    pub max_body_size: usize,
+    /// Most header fields accepted on one request. Requests with more are
+    /// answered with `431 Request Header Fields Too Large` and the
+    /// connection is closed.
+    pub max_header_count: usize,
+    /// Largest request head accepted, in bytes, counting the request line
+    /// and every header line. No single line may exceed 16 KiB either.
+    /// Larger heads are answered with `431 Request Header Fields Too
+    /// Large` and the connection is closed.
+    pub max_head_size: usize,
+    /// Whether `TRACE` requests are echoed back. When disabled they are
+    /// answered with `405 Method Not Allowed`.
+    pub trace_enabled: bool,
+    /// Builds the response for requests that fail before reaching the
+    /// router, e.g. malformed heads, timeouts and oversized bodies.
+    pub error_handler: Arc<dyn ErrorHandler>,
+    /// Expect every connection to start with a PROXY protocol v1 header,
+    /// as sent by HAProxy or Nginx, and take the client address from it;
+    /// `AccessControl` then checks that address instead of the proxy's.
+    /// Connections without a valid header are closed. Only enable this
+    /// behind such a proxy: clients could otherwise claim any address.
+    pub proxy_protocol: bool,
+    /// Consulted for requests sent with `Expect: 100-continue` before
+    /// `100 Continue` is sent, to refuse a body without receiving it.
+    /// `None` accepts every such request.
+    pub continue_handler: Option<Arc<dyn ContinueHandler>>,
+    /// How many pipelined HTTP/1.1 requests are read ahead on one
+    /// connection and handled concurrently, their responses still written
+    /// in order. The default, 1, handles one request at a time. Bodies of
+    /// requests read ahead are buffered, up to `max_body_size` each.
+    pub pipeline_depth: usize,
+    /// Believe the scheme a proxy in front reports in `X-Forwarded-Proto`
+    /// or `Forwarded: proto=`, for `Request::scheme`. Only enable this
+    /// behind a proxy that sets or strips these headers; clients could
+    /// otherwise claim any scheme.
+    pub trust_forwarded_proto: bool,
+    /// Let IPv6 listeners, e.g. on `[::]:4488`, accept IPv4 connections
+    /// too, which then show up as IPv4-mapped addresses like
+    /// `::ffff:192.0.2.1`. When false the OS default applies: Linux
+    /// usually accepts both, Windows and the BSDs only IPv6. Some systems
+    /// do not support dual-stack sockets at all; binding `0.0.0.0` and
+    /// `[::]` separately works everywhere.
+    pub dual_stack: bool,
+    /// Options set on listening and accepted sockets.
+    pub socket_options: SocketOptions,
+    /// Number of accept loops. Above 1, every address is bound once per
+    /// worker with `SO_REUSEPORT` and the kernel spreads new connections
+    /// among the sockets; each worker runs on its own thread with a
+    /// single-threaded runtime, which also serves the connections it
+    /// accepted. Tasks spawned by handlers run there too and are dropped
+    /// when the server stops. Unix only, like `SocketOptions::reuse_port`.
+    pub workers: usize,
+    /// Most requests handled at once, across all connections and workers.
+    /// A request holds its place from when it reaches the middleware until
+    /// its response has been produced. `None` handles every request as it
+    /// arrives.
+    pub max_concurrent_requests: Option<usize>,
+    /// Requests over `max_concurrent_requests` that wait for another to
+    /// finish, for up to `read_timeout`, so short bursts are absorbed.
+    /// Requests beyond these, or that wait too long, are answered with
+    /// `503 Service Unavailable`.
+    pub queue_depth: usize,
+    /// Most connections open at once, across all workers, however many
+    /// requests each makes. At the limit the server stops accepting, and
+    /// further connections wait in the kernel's listen backlog until one
+    /// closes. `None` accepts connections as fast as they arrive.
+    pub max_connections: Option<usize>,
+    /// Headers whose values a request's `Debug` output replaces with
+    /// `[REDACTED]`, matched case-insensitively. Defaults to
+    /// `Authorization`, `Proxy-Authorization`, `Cookie` and `Set-Cookie`.
+    pub redacted_headers: Arc<[String]>,
+    /// `host:port` targets that `CONNECT` requests may open a tunnel to,
+    /// matched case-insensitively, e.g. `"example.com:443"`. Empty, the
+    /// default, refuses `CONNECT` with `405 Method Not Allowed`; other
+    /// targets get `403 Forbidden`. Keep the list short: any target on it
+    /// can be reached by every client through the server.
+    pub connect_targets: Arc<[String]>,
+}
+
+impl fmt::Debug for ServerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerConfig")
+            .field("read_timeout", &self.read_timeout)
+            .field("write_timeout", &self.write_timeout)
+            .field("keep_alive_timeout", &self.keep_alive_timeout)
+            .field("shutdown_timeout", &self.shutdown_timeout)
+            .field("keep_alive_max", &self.keep_alive_max)
+            .field("max_body_size", &self.max_body_size)
+            .field("max_header_count", &self.max_header_count)
+            .field("max_head_size", &self.max_head_size)
+            .field("trace_enabled", &self.trace_enabled)
+            .field("proxy_protocol", &self.proxy_protocol)
+            .field("pipeline_depth", &self.pipeline_depth)
+            .field("trust_forwarded_proto", &self.trust_forwarded_proto)
+            .field("dual_stack", &self.dual_stack)
+            .field("socket_options", &self.socket_options)
+            .field("workers", &self.workers)
+            .field("max_concurrent_requests", &self.max_concurrent_requests)
+            .field("queue_depth", &self.queue_depth)
+            .field("max_connections", &self.max_connections)
+            .field("redacted_headers", &self.redacted_headers)
+            .field("connect_targets", &self.connect_targets)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            keep_alive_timeout: Some(Duration::from_secs(5)),
+            shutdown_timeout: Duration::from_secs(30),
+            keep_alive_max: 100,
+            max_body_size: 1024 * 1024,
+            max_header_count: 100,
+            max_head_size: 64 * 1024,
+            trace_enabled: true,
+            error_handler: Arc::new(DefaultErrorHandler),
+            proxy_protocol: false,
+            continue_handler: None,
+            pipeline_depth: 1,
+            trust_forwarded_proto: false,
+            dual_stack: false,
+            socket_options: SocketOptions::default(),
+            workers: 1,
+            max_concurrent_requests: None,
+            queue_depth: 0,
+            max_connections: None,
+            redacted_headers: default_redacted_headers(),
+            connect_targets: Arc::new([]),
+        }
+    }
+}
+
+/// Socket-level tuning, applied through `socket2` before binding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Set `SO_REUSEPORT`, so several processes can listen on the same
+    /// address and the kernel spreads connections among them. Unix only;
+    /// elsewhere binding fails.
+    pub reuse_port: bool,
+    /// Set `TCP_NODELAY` on accepted connections, so small responses on
+    /// keep-alive connections are not held back by Nagle's algorithm.
+    pub no_delay: bool,
+    /// `SO_RCVBUF` in bytes, `None` for the OS default.
+    pub recv_buffer: Option<usize>,
+    /// `SO_SNDBUF` in bytes, `None` for the OS default.
+    pub send_buffer: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            reuse_port: false,
+            no_delay: true,
+            recv_buffer: None,
+            send_buffer: None,
+        }
+    }
+}
+
+pub struct ServerBuilder {
+    binds: Vec<Bind>,
+    router: Router,
+    middleware: MiddlewareStack,
+    config: ServerConfig,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+    access: Option<AccessControl>,
+    metrics: Option<Arc<dyn Metrics>>,
+    stats: ServerStats,
+}
+
+impl ServerBuilder {
+    /// Address to listen on, e.g. `"0.0.0.0:8080"`. Call it again to
+    /// listen on several addresses at once, e.g. `"0.0.0.0:80"` and
+    /// `"[::]:80"`; all of them share the router and middleware. Without
+    /// any, the server listens on `127.0.0.1:4488`.
+    pub fn bind(mut self, addr: &str) -> Self {
+        self.binds.push(Bind::new(addr));
+        self
+    }
+
+    /// Listens on `addr` with HTTPS using `tls`, whatever `tls` is set to
+    /// for the other addresses. E.g. `.bind("0.0.0.0:80")` followed by
+    /// `.bind_tls("0.0.0.0:443", tls)` serves HTTP and HTTPS side by side.
+    #[cfg(feature = "tls")]
+    pub fn bind_tls(mut self, addr: &str, tls: TlsConfig) -> Self {
+        let mut bind = Bind::new(addr);
+        bind.tls = Some(tls);
+        self.binds.push(bind);
+        self
+    }
+
+    pub fn router(mut self, router: Router) -> Self {
+        self.router = router;
+        self
+    }
+
+    /// Replaces the middleware stack wrapped around the router.
+    pub fn middleware(mut self, middleware: MiddlewareStack) -> Self {
+        self.middleware = middleware;
+        self
+    }
+
+    /// Appends one layer to the middleware stack.
+    pub fn with<M: Middleware>(mut self, middleware: M) -> Self {
+        self.middleware.push(middleware);
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = timeout;
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.config.write_timeout = timeout;
+        self
+    }
+
+    pub fn keep_alive_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// See `ServerConfig::shutdown_timeout`.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.config.shutdown_timeout = timeout;
+        self
+    }
+
+    pub fn keep_alive_max(mut self, keep_alive_max: usize) -> Self {
+        self.config.keep_alive_max = keep_alive_max;
+        self
+    }
+
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.config.max_body_size = max_body_size;
+        self
+    }
+
+    pub fn max_header_count(mut self, max_header_count: usize) -> Self {
+        self.config.max_header_count = max_header_count;
+        self
+    }
+
+    pub fn max_head_size(mut self, max_head_size: usize) -> Self {
+        self.config.max_head_size = max_head_size;
+        self
+    }
+
+    pub fn trace_enabled(mut self, trace_enabled: bool) -> Self {
+        self.config.trace_enabled = trace_enabled;
+        self
+    }
+
+    pub fn error_handler<E: ErrorHandler>(mut self, error_handler: E) -> Self {
+        self.config.error_handler = Arc::new(error_handler);
+        self
+    }
+
+    pub fn proxy_protocol(mut self, proxy_protocol: bool) -> Self {
+        self.config.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    /// See `ServerConfig::trust_forwarded_proto`.
+    pub fn trust_forwarded_proto(mut self, trust_forwarded_proto: bool) -> Self {
+        self.config.trust_forwarded_proto = trust_forwarded_proto;
+        self
+    }
+
+    pub fn pipeline_depth(mut self, pipeline_depth: usize) -> Self {
+        self.config.pipeline_depth = pipeline_depth;
+        self
+    }
+
+    pub fn socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.config.socket_options = socket_options;
+        self
+    }
+
+    /// See `ServerConfig::max_concurrent_requests`.
+    pub fn max_concurrent_requests(mut self, max: usize) -> Self {
+        self.config.max_concurrent_requests = Some(max);
+        self
+    }
+
+    /// See `ServerConfig::queue_depth`.
+    pub fn queue_depth(mut self, queue_depth: usize) -> Self {
+        self.config.queue_depth = queue_depth;
+        self
+    }
+
+    /// See `ServerConfig::connect_targets`.
+    pub fn connect_targets(mut self, targets: &[&str]) -> Self {
+        self.config.connect_targets = targets.iter().map(|target| target.to_string()).collect();
+        self
+    }
+
+    /// See `ServerConfig::redacted_headers`.
+    pub fn redacted_headers(mut self, names: &[&str]) -> Self {
+        self.config.redacted_headers = names.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    /// See `ServerConfig::max_connections`.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.max_connections = Some(max);
+        self
+    }
+
+    /// See `ServerConfig::workers`.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config.workers = workers;
+        self
+    }
+
+    /// See `ServerConfig::dual_stack`.
+    pub fn dual_stack(mut self, dual_stack: bool) -> Self {
+        self.config.dual_stack = dual_stack;
+        self
+    }
+
+    pub fn continue_handler<C: ContinueHandler>(mut self, continue_handler: C) -> Self {
+        self.config.continue_handler = Some(Arc::new(continue_handler));
+        self
+    }
+
+    /// Serves HTTPS using the given certificate and key instead of plain
+    /// HTTP on the addresses given to `bind`.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Rejects connections from addresses `access` does not admit.
+    pub fn access_control(mut self, access: AccessControl) -> Self {
+        self.access = Some(access);
+        self
+    }
+
+    /// Reports connections and requests to `metrics`.
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Live connection and request figures of the server being built, to
+    /// be captured by handlers before the router is handed over.
+    pub fn stats(&self) -> ServerStats {
+        self.stats.clone()
+    }
+
+    /// Binds the listening sockets, so the addresses are known (and taken)
+    /// before the server runs. Binding to port 0 lets the OS pick a free
+    /// port; `Server::local_addrs` tells which one.
+    pub fn build(mut self) -> io::Result<Server> {
+        if self.binds.is_empty() {
+            self.binds.push(Bind::new("127.0.0.1:4488"));
+        }
+        let mut listeners = Vec::with_capacity(self.binds.len());
+        for bind in self.binds {
+            let listener = listen(&bind.addr, &self.config)?;
+            let local_addr = listener.local_addr()?;
+            // The other workers' sockets share the port the first one got.
+            let mut sockets = vec![listener];
+            for _ in 1..self.config.workers {
+                sockets.push(listen_on(local_addr, &self.config)?);
+            }
+            for socket in &sockets {
+                socket.set_nonblocking(true)?;
+            }
+            listeners.push(Listener {
+                local_addr,
+                sockets,
+                #[cfg(feature = "tls")]
+                tls: bind.tls.or_else(|| self.tls.clone()),
+            });
+        }
+        Ok(Server {
+            listeners,
+            metrics: self.metrics,
+            access: self.access.map(Arc::new),
+            router: Arc::new(self.router),
+            middleware: self.middleware,
+            config: Arc::new(self.config),
+            stats: self.stats,
+        })
+    }
+}
+
+/// Binds a listening socket to the first of `addr`'s addresses that
+/// works, with the configured socket options applied before binding.
+fn listen(addr: &str, config: &ServerConfig) -> io::Result<std::net::TcpListener> {
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match listen_on(addr, config) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+fn listen_on(addr: SocketAddr, config: &ServerConfig) -> io::Result<std::net::TcpListener> {
+    let options = &config.socket_options;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // IPv6 sockets accept IPv4 connections as well.
+    if addr.is_ipv6() && config.dual_stack {
+        socket.set_only_v6(false)?;
+    }
+    // Matches what `std::net::TcpListener::bind` does on Unix.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if options.reuse_port || config.workers > 1 {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_REUSEPORT is not supported on this platform",
+        ));
+    }
+    // Set before `listen`, so accepted connections inherit them and the
+    // TCP window scale is negotiated to match.
+    if let Some(size) = options.recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// An address to listen on, with its own TLS config or `None` to use the
+/// builder-wide one.
+struct Bind {
+    addr: String,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+}
+
+impl Bind {
+    fn new(addr: &str) -> Bind {
+        Bind {
+            addr: addr.to_string(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+/// The sockets bound to one address, one per worker, and the TLS config
+/// connections on them are served with.
+struct Listener {
+    sockets: Vec<std::net::TcpListener>,
+    local_addr: SocketAddr,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+}
+
+/// Performs the TLS handshake on connections to a listener.
+#[cfg(feature = "tls")]
+type Acceptor = TlsAcceptor;
+/// Without TLS support every listener serves plain HTTP.
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+enum Acceptor {}
+
+pub struct Server {
+    listeners: Vec<Listener>,
+    router: Arc<Router>,
+    middleware: MiddlewareStack,
+    config: Arc<ServerConfig>,
+    access: Option<Arc<AccessControl>>,
+    metrics: Option<Arc<dyn Metrics>>,
+    stats: ServerStats,
+}
+
+impl Server {
+    /// Starts building a server with an empty router, listening on
+    /// `127.0.0.1:4488` unless told otherwise.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> ServerBuilder {
+        ServerBuilder {
+            binds: Vec::new(),
+            router: Router::new(),
+            middleware: MiddlewareStack::new(),
+            config: ServerConfig::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            access: None,
+            metrics: None,
+            stats: ServerStats::default(),
+        }
+    }
+
+    /// The address the server is listening on; the first one bound when
+    /// there are several.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listeners[0].local_addr
+    }
+
+    /// Every address the server is listening on, in the order bound.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .map(|listener| listener.local_addr)
+            .collect()
+    }
+
+    /// Connections accepted and not yet closed.
+    pub fn connection_count(&self) -> usize {
+        self.stats.connection_count()
+    }
+
+    /// Requests per second, averaged over roughly the last minute.
+    pub fn request_rate(&self) -> f64 {
+        self.stats.request_rate()
+    }
+
+    /// A handle on the figures above that outlives `run` taking the server.
+    pub fn stats(&self) -> ServerStats {
+        self.stats.clone()
+    }
+
+    /// Accepts connections until `shutdown_signal` resolves, handling each
+    /// one in its own task, on `workers` accept loops. After the signal, no
+    /// new connections are accepted, open ones close after their current
+    /// response and `run` returns once all of them have finished, or once
+    /// `shutdown_timeout` has passed, dropping those still open.
+    pub async fn run<F>(self, shutdown_signal: F) -> io::Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let workers = self.config.workers.max(1);
+        let mut sockets: Vec<Vec<_>> = (0..workers).map(|_| Vec::new()).collect();
+        for listener in self.listeners {
+            #[cfg(feature = "tls")]
+            let tls = match &listener.tls {
+                Some(tls) => Some(TlsAcceptor::from(load_tls_config(
+                    &tls.cert_path,
+                    &tls.key_path,
+                )?)),
+                None => None,
+            };
+            #[cfg(not(feature = "tls"))]
+            let tls: Option<Acceptor> = None;
+            for (worker, socket) in sockets.iter_mut().zip(listener.sockets) {
+                worker.push((socket, tls.clone()));
+            }
+        }
+        let metrics: Arc<dyn Metrics> = Arc::new(StatsMetrics {
+            stats: self.stats.clone(),
+            inner: self.metrics.clone(),
+        });
+        let middleware = match self.config.max_concurrent_requests {
+            Some(max) => self.middleware.wrapped(RequestLimit::new(
+                max,
+                self.config.queue_depth,
+                self.config.read_timeout,
+            )),
+            None => self.middleware.clone(),
+        };
+        let connections = self
+            .config
+            .max_connections
+            .map(|max| Arc::new(Semaphore::new(max.min(Semaphore::MAX_PERMITS))));
+        let (shutdown_sender, shutdown) = watch::channel(false);
+        let mut running = JoinSet::new();
+        for listeners in sockets {
+            let worker = Worker {
+                listeners,
+                router: self.router.clone(),
+                middleware: middleware.clone(),
+                config: self.config.clone(),
+                access: self.access.clone(),
+                metrics: metrics.clone(),
+                stats: self.stats.clone(),
+                connections: connections.clone(),
+            };
+            let shutdown = shutdown.clone();
+            if workers == 1 {
+                running.spawn(worker.run(shutdown));
+            } else {
+                running.spawn_blocking(move || {
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()?
+                        .block_on(worker.run(shutdown))
+                });
+            }
+        }
+        tokio::pin!(shutdown_signal);
+
+        // Workers only stop on their own when their sockets cannot be
+        // registered with the runtime.
+        let mut result = Ok(());
+        tokio::select! {
+            Some(joined) = running.join_next() => result = stopped(joined),
+            () = &mut shutdown_signal => {}
+        }
+        let _ = shutdown_sender.send(true);
+        while let Some(joined) = running.join_next().await {
+            result = result.and(stopped(joined));
+        }
+        result
+    }
+}
+
+/// Flattens the outcome of a worker task.
+fn stopped(joined: Result<io::Result<()>, JoinError>) -> io::Result<()> {
+    joined.unwrap_or_else(|err| Err(io::Error::other(err)))
+}
+
+/// One accept loop with its own sockets, one per address the server
+/// listens on, and the connections it accepted.
+struct Worker {
+    listeners: Vec<(std::net::TcpListener, Option<Acceptor>)>,
+    router: Arc<Router>,
+    middleware: MiddlewareStack,
+    config: Arc<ServerConfig>,
+    access: Option<Arc<AccessControl>>,
+    metrics: Arc<dyn Metrics>,
+    stats: ServerStats,
+    /// Permits for `max_connections`, one held by every open connection.
+    connections: Option<Arc<Semaphore>>,
+}
+
+impl Worker {
+    /// Accepts connections until `shutdown` is set, then waits for the
+    /// open ones to finish.
+    async fn run(self, mut shutdown: watch::Receiver<bool>) -> io::Result<()> {
+        let mut listeners = Vec::with_capacity(self.listeners.len());
+        for (listener, tls) in self.listeners {
+            listeners.push((TcpListener::from_std(listener)?, tls));
+        }
+        let metrics = self.metrics;
+        let mut tasks = JoinSet::new();
+
+        let mut next = 0;
+        loop {
+            tokio::select! {
+                (index, accepted, connection_permit) = accept_next(&listeners, next, &self.connections) => {
+                    next = index + 1;
+                    let (mut stream, addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            warn!(error = %err, "failed to accept connection");
+                            // Accepting again right away would fail the
+                            // same way until descriptors are freed.
+                            if out_of_descriptors(&err) {
+                                tokio::select! {
+                                    () = tokio::time::sleep(ACCEPT_BACKOFF) => {}
+                                    _ = shutdown.changed() => break,
+                                }
+                            }
+                            continue;
+                        }
+                    };
+                    if self.config.socket_options.no_delay {
+                        if let Err(err) = stream.set_nodelay(true) {
+                            warn!(peer_addr = %addr, error = %err, "failed to set TCP_NODELAY");
+                        }
+                    }
+                    let tls = listeners[index].1.clone();
+                    // Behind a PROXY protocol balancer the peer is the
+                    // balancer; the client is only known once the header
+                    // has been read.
+                    if !self.config.proxy_protocol && !is_allowed(&self.access, addr) {
+                        warn!(peer_addr = %addr, "denied connection");
+                        reject(stream, tls.is_some(), FORBIDDEN);
+                        continue;
+                    }
+                    info!(peer_addr = %addr, "accepted connection");
+
+                    let shutdown = shutdown.clone();
+                    let router = self.router.clone();
+                    let middleware = self.middleware.clone();
+                    let config = self.config.clone();
+                    let metrics = Some(metrics.clone());
+                    let access = self.access.clone();
+                    let open = self.stats.connection_opened();
+                    tasks.spawn(async move {
+                        let _open = open;
+                        let _connection_permit = connection_permit;
+                        let mut remote_addr = addr;
+                        if config.proxy_protocol {
+                            let header = timeout(config.read_timeout, read_proxy_header(&mut stream));
+                            match header.await {
+                                Ok(Ok(Some(source))) => remote_addr = source,
+                                Ok(Ok(None)) => {}
+                                // Malformed or missing: close without a word.
+                                _ => return,
+                            }
+                            if !is_allowed(&access, remote_addr) {
+                                warn!(peer_addr = %addr, remote_addr = %remote_addr, "denied connection");
+                                reject(stream, tls.is_some(), FORBIDDEN);
+                                return;
+                            }
+                        }
+                        let connection = match tls {
+                            #[cfg(feature = "tls")]
+                            Some(tls) => {
+                                let handshake = timeout(config.read_timeout, tls.accept(stream));
+                                let Ok(Ok(stream)) = handshake.await else {
+                                    return;
+                                };
+                                #[cfg(feature = "http2")]
+                                if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+                                    let peer = Peer { addr, remote: remote_addr };
+                                    let h2 = serve_h2(stream, peer, router, middleware, config, shutdown, metrics);
+                                    h2.await;
+                                    return;
+                                }
+                                Connection::new(stream).with_tls()
+                            }
+                            #[cfg(not(feature = "tls"))]
+                            Some(never) => match never {},
+                            None => Connection::new(stream),
+                        };
+                        let mut connection = connection
+                            .with_peer_addr(addr)
+                            .with_remote_addr(remote_addr)
+                            .with_shutdown(shutdown);
+                        if let Some(metrics) = metrics {
+                            connection = connection.with_metrics(metrics);
+                        }
+                        serve_connection(connection, &router, &middleware, &config).await;
+                    });
+                }
+                // Reap finished connections so the set does not grow forever.
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => {}
+                _ = shutdown.changed() => break,
+            }
+        }
+
+        drop(listeners);
+        let drained = async { while tasks.join_next().await.is_some() {} };
+        if timeout(self.config.shutdown_timeout, drained)
+            .await
+            .is_err()
+        {
+            tasks.shutdown().await;
+        }
+        Ok(())
+    }
+}
+
+/// Waits for a permit from `connections`, if limited, and then for a
+/// connection on any of `listeners`. No connection is accepted while
+/// every permit is taken.
+async fn accept_next(
+    listeners: &[(TcpListener, Option<Acceptor>)],
+    first: usize,
+    connections: &Option<Arc<Semaphore>>,
+) -> (
+    usize,
+    io::Result<(TcpStream, SocketAddr)>,
+    Option<OwnedSemaphorePermit>,
+) {
+    let permit = match connections {
+        // The semaphore is never closed.
+        Some(connections) => connections.clone().acquire_owned().await.ok(),
+        None => None,
+    };
+    let (index, accepted) = accept_any(listeners, first).await;
+    (index, accepted, permit)
+}
+
+/// Waits for a connection on any of `listeners`, starting the search at
+/// `first` so a busy listener cannot starve the others, and tells which
+/// listener took it.
+async fn accept_any(
+    listeners: &[(TcpListener, Option<Acceptor>)],
+    first: usize,
+) -> (usize, io::Result<(TcpStream, SocketAddr)>) {
+    std::future::poll_fn(|cx| {
+        for offset in 0..listeners.len() {
+            let index = (first + offset) % listeners.len();
+            if let Poll::Ready(accepted) = listeners[index].0.poll_accept(cx) {
+                return Poll::Ready((index, accepted));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// How long the accept loop pauses after running out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Whether accepting failed for lack of file descriptors, which only
+/// closing connections fixes.
+#[cfg(unix)]
+fn out_of_descriptors(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EMFILE | libc::ENFILE))
+}
+
+#[cfg(not(unix))]
+fn out_of_descriptors(_err: &io::Error) -> bool {
+    false
+}
+
+fn is_allowed(access: &Option<Arc<AccessControl>>, addr: SocketAddr) -> bool {
+    access
+        .as_ref()
+        .is_none_or(|access| access.is_allowed(addr.ip()))
+}
+
+/// Sends `response`, e.g. `FORBIDDEN`, if the socket can take it right away
+/// and closes the connection. TLS clients could not read the response
+/// before a handshake, so they are simply disconnected.
+fn reject(stream: TcpStream, tls: bool, response: &[u8]) {
+    if tls {
+        return;
+    }
+    // A fresh socket has room in its send buffer, so this does not block
+    // even though the socket is non-blocking.
+    if let Ok(mut stream) = stream.into_std() {
+        let _ = stream.write(response);
+        let _ = stream.shutdown(Shutdown::Write);
+    }
+}
+
+const FORBIDDEN: &[u8] =
+    b"HTTP/1.1 403 Forbidden\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
@@ -0,0 +1,96 @@
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+
+use crate::codes::HTTPCodes;
+use crate::request::Request;
+use crate::response::Response;
+
+/// One Server-Sent Event. Unset fields are left out of the frame.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SseEvent {
+    pub id: Option<String>,
+    /// Event type; browsers dispatch untyped events as `message`.
+    pub event: Option<String>,
+    pub data: Option<String>,
+}
+
+impl SseEvent {
+    /// An untyped event carrying `data`.
+    pub fn new(data: &str) -> SseEvent {
+        SseEvent {
+            data: Some(data.to_string()),
+            ..SseEvent::default()
+        }
+    }
+
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    pub fn event(mut self, event: &str) -> Self {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    /// Serializes the event in the `text/event-stream` format. Multi-line
+    /// data is sent as one `data:` line per line.
+    fn to_frame(&self) -> String {
+        let mut frame = String::new();
+        if let Some(id) = &self.id {
+            frame.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(event) = &self.event {
+            frame.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(data) = &self.data {
+            for line in data.lines() {
+                frame.push_str(&format!("data: {line}\n"));
+            }
+            if data.is_empty() {
+                frame.push_str("data: \n");
+            }
+        }
+        frame.push('\n');
+        frame
+    }
+}
+
+/// A line break would end the field early and start an unintended one.
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+/// Sending half of an event stream. The response ends when it is dropped.
+#[derive(Debug)]
+pub struct SseStream {
+    sender: mpsc::Sender<Vec<u8>>,
+}
+
+impl SseStream {
+    /// Queues `event` for the client. Fails once the client has gone away.
+    pub async fn send(&mut self, event: SseEvent) -> io::Result<()> {
+        self.sender
+            .send(event.to_frame().into_bytes())
+            .await
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+/// Starts `handler` in its own task and answers with the event stream it
+/// feeds.
+pub(crate) fn event_stream<F, Fut>(request: Request, handler: Arc<F>) -> Response
+where
+    F: Fn(Request, SseStream) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(16);
+    tokio::spawn(handler(request, SseStream { sender }));
+    Response::new(HTTPCodes::OK)
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .chunked_channel(receiver)
+}
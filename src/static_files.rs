@@ -0,0 +1,320 @@
+use std::io::{self, Cursor, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use httpdate::HttpDate;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+use uuid::Uuid;
+
+use crate::codes::HTTPCodes;
+use crate::etag;
+use crate::mime::mime_type_for_extension;
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::{BoxFuture, Handler};
+
+/// Serves files from a directory.
+///
+/// The file is named by the route's trailing wildcard when there is one,
+/// e.g. `router.get("/static/*filepath", StaticFiles::new(root))`, and by
+/// the whole request path otherwise. Paths that leave `root`, through `..` or
+/// a symlink, are answered with `404 Not Found`.
+///
+/// `Range` requests are answered with `206 Partial Content`, using a
+/// `multipart/byteranges` body when several ranges are asked for, or with
+/// `416 Range Not Satisfiable` when none of them lie within the file.
+/// Overlapping ranges are merged, and the whole file is sent when they
+/// add up to more than it holds.
+///
+/// Files are sent with `Cache-Control: public, max-age=0` by default, so
+/// caches revalidate them with their `ETag` before every reuse.
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    root: PathBuf,
+    max_age: Duration,
+    immutable: bool,
+}
+
+impl StaticFiles {
+    pub fn new(root: &Path) -> StaticFiles {
+        StaticFiles {
+            root: root.to_path_buf(),
+            max_age: Duration::ZERO,
+            immutable: false,
+        }
+    }
+
+    /// How long clients and caches may reuse a file without revalidating.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Marks the files as never changing, for directories of fingerprinted
+    /// assets like `app.3f9a1c.js`. Pair it with a long `max_age`.
+    pub fn immutable(mut self) -> Self {
+        self.immutable = true;
+        self
+    }
+
+    fn cache_control(&self, response: Response) -> Response {
+        let response = response.cache_control_public(self.max_age);
+        if self.immutable {
+            response.immutable()
+        } else {
+            response
+        }
+    }
+}
+
+impl Handler for StaticFiles {
+    fn call(&self, request: Request) -> BoxFuture<Response> {
+        let files = self.clone();
+        Box::pin(async move {
+            match serve(&files, &request).await {
+                Ok(response) => response,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    Response::new(HTTPCodes::NotFound)
+                }
+                Err(_) => Response::new(HTTPCodes::InternalServerError),
+            }
+        })
+    }
+}
+
+async fn serve(files: &StaticFiles, request: &Request) -> io::Result<Response> {
+    let relative = request.wildcard.as_ref().unwrap_or(&request.path);
+    let path = resolve(&files.root, relative).await?;
+    let metadata = fs::metadata(&path).await?;
+    if !metadata.is_file() {
+        return Err(io::ErrorKind::NotFound.into());
+    }
+
+    let modified = metadata.modified().ok();
+    let etag = file_etag(metadata.len(), modified);
+    if not_modified(request, &etag, modified) {
+        let mut response =
+            files.cache_control(Response::new(HTTPCodes::NotModified).header("ETag", &etag));
+        if let Some(modified) = modified {
+            response = response.last_modified(modified);
+        }
+        return Ok(response);
+    }
+
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let content_type = mime_type_for_extension(extension.unwrap_or_default());
+    let size = metadata.len();
+    let ranges = match request.headers.get("Range") {
+        Some(range)
+            if matches!(request.method.as_str(), "GET" | "HEAD")
+                && if_range_matches(request, &etag, modified) =>
+        {
+            parse_ranges(range, size)
+        }
+        _ => Ranges::Full,
+    };
+
+    let code = match ranges {
+        Ranges::Full => HTTPCodes::OK,
+        Ranges::Partial(_) => HTTPCodes::PartialContent,
+        Ranges::Unsatisfiable => {
+            return Ok(Response::new(HTTPCodes::RangeNotSatisfiable)
+                .header("Content-Range", &format!("bytes */{size}")));
+        }
+    };
+    let mut response = files.cache_control(
+        Response::new(code)
+            .header("Accept-Ranges", "bytes")
+            .header("ETag", &etag),
+    );
+    if let Some(modified) = modified {
+        response = response.last_modified(modified);
+    }
+
+    match ranges {
+        Ranges::Partial(ranges) if ranges.len() > 1 => {
+            let boundary = Uuid::new_v4().simple().to_string();
+            let mut body: Box<dyn AsyncRead + Send + Unpin> = Box::new(tokio::io::empty());
+            let mut length = 0;
+            for (first, last) in ranges {
+                let head = format!(
+                    "\r\n--{boundary}\r\nContent-Type: {content_type}\r\n\
+                     Content-Range: bytes {first}-{last}/{size}\r\n\r\n"
+                );
+                length += head.len() as u64 + last - first + 1;
+                let part = open_range(&path, first, last).await?;
+                body = Box::new(body.chain(Cursor::new(head)).chain(part));
+            }
+            let tail = format!("\r\n--{boundary}--\r\n");
+            length += tail.len() as u64;
+            let body = body.chain(Cursor::new(tail));
+            let content_type = format!("multipart/byteranges; boundary={boundary}");
+            Ok(response
+                .header("Content-Type", &content_type)
+                .sized_reader(body, length))
+        }
+        Ranges::Partial(ranges) => {
+            let (first, last) = ranges[0];
+            let part = open_range(&path, first, last).await?;
+            Ok(response
+                .header("Content-Type", content_type)
+                .header("Content-Range", &format!("bytes {first}-{last}/{size}"))
+                .sized_reader(part, last - first + 1))
+        }
+        _ => {
+            let file = File::open(&path).await?;
+            Ok(response
+                .header("Content-Type", content_type)
+                .sized_reader(file, size))
+        }
+    }
+}
+
+/// Most ranges honoured in one request. Asking for more gets the whole
+/// file, rather than letting a client split it into tiny pieces.
+const MAX_RANGES: usize = 16;
+
+/// A `Range` header evaluated against the file size.
+enum Ranges {
+    /// No usable `Range` header: send the whole file.
+    Full,
+    /// Inclusive byte ranges, in the order they were asked for.
+    Partial(Vec<(u64, u64)>),
+    /// None of the ranges overlap the file.
+    Unsatisfiable,
+}
+
+/// Parses `bytes=0-1023`, `bytes=1024-` and `bytes=-500` style ranges.
+/// Headers that do not parse, or use another unit, are ignored as RFC 9110
+/// allows.
+fn parse_ranges(header: &str, size: u64) -> Ranges {
+    let Some(specs) = header.trim().strip_prefix("bytes=") else {
+        return Ranges::Full;
+    };
+    if specs.split(',').count() > MAX_RANGES {
+        return Ranges::Full;
+    }
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let Some((first, last)) = spec.trim().split_once('-') else {
+            return Ranges::Full;
+        };
+        let range = if first.is_empty() {
+            // The final `last` bytes.
+            let Ok(suffix) = last.parse::<u64>() else {
+                return Ranges::Full;
+            };
+            (suffix > 0 && size > 0).then(|| (size - suffix.min(size), size - 1))
+        } else {
+            let Ok(first) = first.parse::<u64>() else {
+                return Ranges::Full;
+            };
+            let last = match last.parse::<u64>() {
+                _ if last.is_empty() => u64::MAX,
+                Ok(last) if last >= first => last,
+                _ => return Ranges::Full,
+            };
+            (first < size).then(|| (first, last.min(size - 1)))
+        };
+        ranges.extend(range);
+    }
+    if ranges.is_empty() {
+        Ranges::Unsatisfiable
+    } else {
+        coalesce(ranges, size)
+    }
+}
+
+/// Merges overlapping and adjacent ranges, as RFC 9110 allows, so no byte
+/// is sent twice; ranges apart from each other keep their order. Ranges
+/// adding up to more than the file, which only overlapping ones can, get
+/// the whole file instead.
+fn coalesce(ranges: Vec<(u64, u64)>, size: u64) -> Ranges {
+    let requested: u64 = ranges.iter().map(|(first, last)| last - first + 1).sum();
+    if requested > size {
+        return Ranges::Full;
+    }
+    let mut sorted = ranges.clone();
+    sorted.sort_unstable();
+    if sorted.windows(2).all(|pair| pair[1].0 > pair[0].1 + 1) {
+        return Ranges::Partial(ranges);
+    }
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(sorted.len());
+    for (first, last) in sorted {
+        match merged.last_mut() {
+            Some((_, end)) if first <= *end + 1 => *end = (*end).max(last),
+            _ => merged.push((first, last)),
+        }
+    }
+    Ranges::Partial(merged)
+}
+
+/// Whether `If-Range`, when sent, still names the current file, so that a
+/// resumed download is not stitched together from two versions. An entity
+/// tag must match exactly; weak tags never do. A date must equal the
+/// file's modification time, to the second.
+fn if_range_matches(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    let Some(validator) = request.headers.get("If-Range").map(str::trim) else {
+        return true;
+    };
+    if validator.starts_with('"') || validator.starts_with("W/") {
+        return validator == etag;
+    }
+    match (httpdate::parse_http_date(validator), modified) {
+        (Ok(date), Some(modified)) => HttpDate::from(date) == HttpDate::from(modified),
+        _ => false,
+    }
+}
+
+/// Reads bytes `first..=last` of the file at `path`.
+async fn open_range(path: &Path, first: u64, last: u64) -> io::Result<impl AsyncRead> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(first)).await?;
+    Ok(file.take(last - first + 1))
+}
+
+/// Maps `relative` onto a path under `root`, following symlinks, and fails
+/// with `NotFound` if the result lies outside `root`.
+async fn resolve(root: &Path, relative: &str) -> io::Result<PathBuf> {
+    let mut path = root.to_path_buf();
+    for component in Path::new(relative.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => return Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    let root = fs::canonicalize(root).await?;
+    let path = fs::canonicalize(path).await?;
+    if !path.starts_with(&root) {
+        return Err(io::ErrorKind::NotFound.into());
+    }
+    Ok(path)
+}
+
+/// Hex encoded size and modification time, e.g. `"1a2b-65f0c3d1"`.
+fn file_etag(size: u64, modified: Option<SystemTime>) -> String {
+    let mtime = modified
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |mtime| mtime.as_secs());
+    format!("\"{size:x}-{mtime:x}\"")
+}
+
+/// Evaluates the conditional headers. `If-None-Match` takes precedence
+/// over `If-Modified-Since` when both are sent.
+fn not_modified(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = request.headers.get("If-None-Match") {
+        return etag::none_match(if_none_match, etag);
+    }
+    let since = request
+        .headers
+        .get("If-Modified-Since")
+        .and_then(|since| httpdate::parse_http_date(since).ok());
+    match (since, modified) {
+        // HTTP dates have one second resolution.
+        (Some(since), Some(modified)) => HttpDate::from(modified) <= HttpDate::from(since),
+        _ => false,
+    }
+}
@@ -0,0 +1,127 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::metrics::Metrics;
+
+/// How often the request rate average is updated.
+const TICK: Duration = Duration::from_secs(5);
+/// Period the request rate is averaged over.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Live load figures of a `Server`: open connections and an exponentially
+/// weighted moving average of requests per second over about a minute.
+///
+/// Cheap to clone; every clone reads the same counters. Get one from
+/// `ServerBuilder::stats` before the router is handed over to report them
+/// from a handler, e.g. for a `/health` endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct ServerStats {
+    connections: Arc<AtomicUsize>,
+    rate: Arc<Mutex<Ewma>>,
+}
+
+impl ServerStats {
+    /// Connections accepted and not yet closed.
+    pub fn connection_count(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Requests per second, averaged over roughly the last minute. Starts
+    /// at zero and is updated every five seconds.
+    pub fn request_rate(&self) -> f64 {
+        let mut rate = self.rate.lock().unwrap();
+        rate.tick(Instant::now());
+        rate.rate
+    }
+
+    /// Counts a connection as open until the returned guard is dropped.
+    pub(crate) fn connection_opened(&self) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.connections.clone())
+    }
+
+    fn request_started(&self) {
+        let mut rate = self.rate.lock().unwrap();
+        rate.tick(Instant::now());
+        rate.pending += 1;
+    }
+}
+
+pub(crate) struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A moving average advanced lazily: whenever it is touched, it catches up
+/// on the ticks that have passed since.
+#[derive(Debug)]
+struct Ewma {
+    rate: f64,
+    /// Requests since the last tick.
+    pending: u64,
+    last_tick: Instant,
+}
+
+impl Default for Ewma {
+    fn default() -> Self {
+        Ewma {
+            rate: 0.0,
+            pending: 0,
+            last_tick: Instant::now(),
+        }
+    }
+}
+
+impl Ewma {
+    fn tick(&mut self, now: Instant) {
+        let ticks = (now.duration_since(self.last_tick).as_secs_f64() / TICK.as_secs_f64()) as u32;
+        if ticks == 0 {
+            return;
+        }
+        let alpha = 1.0 - (-TICK.as_secs_f64() / WINDOW.as_secs_f64()).exp();
+        // The first tick saw the pending requests, any further ones none.
+        let current = self.pending as f64 / TICK.as_secs_f64();
+        self.rate += alpha * (current - self.rate);
+        self.rate *= (1.0 - alpha).powi(ticks as i32 - 1);
+        self.pending = 0;
+        self.last_tick += TICK * ticks;
+    }
+}
+
+/// Feeds requests into `stats` and forwards every event to the user's
+/// `Metrics`, if any.
+pub(crate) struct StatsMetrics {
+    pub(crate) stats: ServerStats,
+    pub(crate) inner: Option<Arc<dyn Metrics>>,
+}
+
+impl Metrics for StatsMetrics {
+    fn on_request_start(&self, method: &str) {
+        self.stats.request_started();
+        if let Some(inner) = &self.inner {
+            inner.on_request_start(method);
+        }
+    }
+
+    fn on_request_end(&self, method: &str, status: u16, duration: Duration, bytes_sent: usize) {
+        if let Some(inner) = &self.inner {
+            inner.on_request_end(method, status, duration, bytes_sent);
+        }
+    }
+
+    fn on_connection_open(&self) {
+        if let Some(inner) = &self.inner {
+            inner.on_connection_open();
+        }
+    }
+
+    fn on_connection_close(&self) {
+        if let Some(inner) = &self.inner {
+            inner.on_connection_close();
+        }
+    }
+}
@@ -0,0 +1,3181 @@
+use std::sync::Arc;
+
+use tokio::io::{duplex, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+use crate::codes::HTTPCodes;
+use crate::connection::{handle_connection, read_line, reject, serve_connection, Connection};
+use crate::headers::Headers;
+use crate::middleware::MiddlewareStack;
+use crate::response::Response;
+use crate::router::Router;
+use crate::server::ServerConfig;
+
+/// Drives requests through `handle_connection` over in-memory pipes, so
+/// tests need neither a socket nor a free port.
+pub(crate) struct TestClient {
+    router: Arc<Router>,
+    middleware: MiddlewareStack,
+    config: ServerConfig,
+}
+
+impl TestClient {
+    pub(crate) fn new(router: Router) -> TestClient {
+        TestClient {
+            router: Arc::new(router),
+            middleware: MiddlewareStack::new(),
+            config: ServerConfig::default(),
+        }
+    }
+
+    pub(crate) fn middleware(mut self, middleware: MiddlewareStack) -> Self {
+        self.middleware = middleware;
+        self
+    }
+
+    pub(crate) fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Sends one request on a fresh connection and parses the response.
+    /// `Host: test` is added unless `headers` has one, and `Content-Length`
+    /// for a non-empty `body` unless `headers` already frame it. Chunked
+    /// responses are decoded into `body`.
+    pub(crate) async fn send(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Response {
+        let (client, server) = duplex(64 * 1024);
+        let router = self.router.clone();
+        let middleware = self.middleware.clone();
+        let config = self.config.clone();
+        let served = tokio::spawn(async move {
+            let mut connection = Connection::new(server);
+            if let Err(err) =
+                handle_connection(&mut connection, &router, &middleware, &config).await
+            {
+                reject(&mut connection, &config, &err).await;
+            }
+        });
+
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut request = format!("{method} {path} HTTP/1.1\r\n");
+        if !headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("host"))
+        {
+            request.push_str("host: test\r\n");
+        }
+        for (name, value) in headers {
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        let framed = headers.iter().any(|(name, _)| {
+            name.eq_ignore_ascii_case("content-length")
+                || name.eq_ignore_ascii_case("transfer-encoding")
+        });
+        if !framed && !body.is_empty() {
+            request.push_str(&format!("content-length: {}\r\n", body.len()));
+        }
+        request.push_str("\r\n");
+        writer.write_all(request.as_bytes()).await.unwrap();
+        writer.write_all(body).await.unwrap();
+
+        let mut reader = BufReader::new(reader);
+        let response = read_response(&mut reader, method == "HEAD").await;
+        served.await.unwrap();
+        response
+    }
+
+    pub(crate) async fn get(&self, path: &str) -> Response {
+        self.send("GET", path, &[], &[]).await
+    }
+}
+
+async fn read_response<R>(reader: &mut R, head_only: bool) -> Response
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let status = read_line(reader).await.unwrap().expect("no status line");
+    let code = status
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .and_then(HTTPCodes::from_u16)
+        .unwrap_or_else(|| panic!("bad status line {status:?}"));
+
+    let mut headers = Headers::new();
+    loop {
+        let line = read_line(reader).await.unwrap().expect("unterminated head");
+        if line.is_empty() {
+            break;
+        }
+        headers.parse_line(&line).unwrap();
+    }
+
+    let mut body = Vec::new();
+    let has_body = !head_only && !matches!(code.code(), 100..=199 | 204 | 304);
+    if !has_body {
+        // Nothing follows the head.
+    } else if headers
+        .get("Transfer-Encoding")
+        .is_some_and(|value| value.eq_ignore_ascii_case("chunked"))
+    {
+        loop {
+            let size = read_line(reader).await.unwrap().expect("truncated chunk");
+            let size = usize::from_str_radix(size.split(';').next().unwrap().trim(), 16).unwrap();
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).await.unwrap();
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(length) = headers.get("Content-Length") {
+        body.resize(length.parse().unwrap(), 0);
+        reader.read_exact(&mut body).await.unwrap();
+    } else {
+        reader.read_to_end(&mut body).await.unwrap();
+    }
+
+    let mut response = Response::new(code);
+    response.headers = headers;
+    response.body = body;
+    response
+}
+
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::error::{DefaultErrorHandler, ErrorHandler, HTTPError};
+    use crate::etag;
+    use crate::method::HttpMethod;
+    use crate::middleware::Next;
+    use crate::proxy::read_proxy_header;
+    use crate::request::Request;
+    use crate::request_id::RequestIdMiddleware;
+    use crate::server::{Server, SocketOptions};
+    use crate::static_files::StaticFiles;
+    use crate::vhost::VHostRouter;
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router
+            .get("/", |_request| async {
+                Response::new(HTTPCodes::OK).body("Hello world")
+            })
+            .unwrap()
+            .get("/users/:id", |request: Request| async move {
+                Response::new(HTTPCodes::OK).body(request.params["id"].clone())
+            })
+            .unwrap()
+            .post("/echo", |mut request: Request| async move {
+                let body = request.body.read_to_end().await.unwrap_or_default();
+                Response::new(HTTPCodes::OK).body(body)
+            })
+            .unwrap()
+            .get("/ordered", |_request| async {
+                Response::new(HTTPCodes::OK)
+                    .header("X-Second", "2")
+                    .header("X-First", "1")
+                    .body("ordered")
+            })
+            .unwrap()
+            .get("/data", |request: Request| async move {
+                Response::new(HTTPCodes::OK).body(request.media_type.unwrap_or_default())
+            })
+            .unwrap()
+            .produces(["application/json", "text/csv"])
+            .get("/chunked", |_request| async {
+                Response::new(HTTPCodes::OK).chunked_reader(&b"streamed body"[..])
+            })
+            .unwrap();
+        router
+    }
+
+    #[tokio::test]
+    async fn routes_request() {
+        let response = TestClient::new(router()).get("/").await;
+        assert_eq!(response.code, HTTPCodes::OK);
+        assert_eq!(response.body, b"Hello world");
+        assert_eq!(response.headers.get("content-length"), Some("11"));
+    }
+
+    #[tokio::test]
+    async fn head_omits_body() {
+        let response = TestClient::new(router()).send("HEAD", "/", &[], &[]).await;
+        assert_eq!(response.code, HTTPCodes::OK);
+        assert_eq!(response.headers.get("content-length"), Some("11"));
+        assert!(response.body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn wrong_method_is_not_allowed() {
+        let response = TestClient::new(router())
+            .send("DELETE", "/echo", &[], &[])
+            .await;
+        assert_eq!(response.code, HTTPCodes::MethodNotAllowed);
+        assert_eq!(response.headers.get("allow"), Some("POST, OPTIONS"));
+    }
+
+    #[test]
+    fn percent_decodes() {
+        assert_eq!(crate::percent_decode("a%20b%2fc").as_deref(), Some("a b/c"));
+        assert_eq!(crate::percent_decode("caf%C3%A9").as_deref(), Some("café"));
+        assert_eq!(crate::percent_decode("a+b").as_deref(), Some("a+b"));
+        // Bad and truncated escapes, and bytes that are not UTF-8.
+        for input in ["%GG", "%2", "abc%", "%C3", "%FF"] {
+            assert_eq!(crate::percent_decode(input), None, "{input}");
+        }
+    }
+
+    #[tokio::test]
+    async fn decodes_path_and_query() {
+        let mut router = router();
+        router
+            .get("/search/:term", |request: Request| async move {
+                let query = request.query_multi["q"].join(",");
+                Response::new(HTTPCodes::OK).body(format!("{} {query}", request.params["term"]))
+            })
+            .unwrap();
+        let client = TestClient::new(router);
+        // `+` is a space in the query only.
+        let response = client.get("/search/a+b%20c?q=a+b%2Bc&q=%C3%A9").await;
+        assert_eq!(response.body, "a+b c a b+c,é".as_bytes());
+        // An escaped slash stays inside its segment.
+        let response = client.get("/users/a%2Fb").await;
+        assert_eq!(response.code, HTTPCodes::OK);
+        assert_eq!(response.body, b"a/b");
+
+        for target in [
+            "/users/%GG",
+            "/users/%2",
+            "/users/%FF",
+            "/?q=%E2%82",
+            "/?q=%zz",
+        ] {
+            let response = client.get(target).await;
+            assert_eq!(response.code, HTTPCodes::BadRequest, "{target}");
+        }
+    }
+
+    #[tokio::test]
+    async fn negotiates_media_type() {
+        let client = TestClient::new(router());
+        let accept = [("Accept", "application/json;q=0.5, text/*")];
+        let response = client.send("GET", "/data", &accept, &[]).await;
+        assert_eq!(response.body, b"text/csv");
+        assert_eq!(response.headers.get("vary"), Some("Accept"));
+
+        let response = client.get("/data").await;
+        assert_eq!(response.body, b"application/json");
+    }
+
+    #[tokio::test]
+    async fn unacceptable_media_type() {
+        let response = TestClient::new(router())
+            .send("GET", "/data", &[("Accept", "image/png")], &[])
+            .await;
+        assert_eq!(response.code, HTTPCodes::NotAcceptable);
+        assert_eq!(response.body, b"application/json\ntext/csv");
+    }
+
+    #[tokio::test]
+    async fn options_lists_path_methods() {
+        let response = TestClient::new(router())
+            .send("OPTIONS", "/users/7", &[], &[])
+            .await;
+        assert_eq!(response.code, HTTPCodes::OK);
+        assert_eq!(response.headers.get("allow"), Some("GET, HEAD, OPTIONS"));
+    }
+
+    #[tokio::test]
+    async fn options_star_lists_all_methods() {
+        let response = TestClient::new(router())
+            .send("OPTIONS", "*", &[], &[])
+            .await;
+        assert_eq!(
+            response.headers.get("allow"),
+            Some("GET, HEAD, POST, OPTIONS")
+        );
+    }
+
+    #[tokio::test]
+    async fn trace_echoes_head_without_credentials() {
+        let response = TestClient::new(router())
+            .send("TRACE", "/x", &[("X-Probe", "1"), ("Cookie", "id=2")], &[])
+            .await;
+        assert_eq!(response.headers.get("content-type"), Some("message/http"));
+        assert_eq!(
+            response.body,
+            b"TRACE /x HTTP/1.1\r\nhost: test\r\nX-Probe: 1\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn trace_can_be_disabled() {
+        let config = ServerConfig {
+            trace_enabled: false,
+            ..ServerConfig::default()
+        };
+        let response = TestClient::new(router())
+            .config(config)
+            .send("TRACE", "/", &[], &[])
+            .await;
+        assert_eq!(response.code, HTTPCodes::MethodNotAllowed);
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD, OPTIONS"));
+    }
+
+    #[tokio::test]
+    async fn routes_by_host() {
+        let mut api = Router::new();
+        api.get("/", |_request| async {
+            Response::new(HTTPCodes::OK).body("api")
+        })
+        .unwrap();
+        let vhost = VHostRouter::new()
+            .host("API.example.com", api)
+            .host("www.example.com", router());
+        let client = TestClient::new(vhost.into());
+
+        let response = client
+            .send("GET", "/", &[("Host", "api.example.com:4488")], &[])
+            .await;
+        assert_eq!(response.body, b"api");
+        let response = client
+            .send("GET", "/users/3", &[("Host", "www.example.com")], &[])
+            .await;
+        assert_eq!(response.body, b"3");
+        let response = client
+            .send("GET", "/", &[("Host", "other.example.com")], &[])
+            .await;
+        assert_eq!(response.code, HTTPCodes::MisdirectedRequest);
+    }
+
+    #[tokio::test]
+    async fn binds_path_params() {
+        let response = TestClient::new(router()).get("/users/42").await;
+        assert_eq!(response.body, b"42");
+    }
+
+    #[tokio::test]
+    async fn unknown_route_is_not_found() {
+        let response = TestClient::new(router()).get("/missing").await;
+        assert_eq!(response.code, HTTPCodes::NotFound);
+        assert_eq!(response.body, b"Not Found");
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+    }
+
+    #[tokio::test]
+    async fn uses_custom_not_found_handler() {
+        let mut router = router();
+        router.not_found(|request: Request| async move {
+            Response::new(HTTPCodes::NotFound)
+                .header("Content-Type", "application/json")
+                .body(format!(
+                    "{{\"error\":\"not found\",\"path\":\"{}\"}}",
+                    request.path
+                ))
+        });
+        let client = TestClient::new(router);
+
+        let response = client.get("/missing").await;
+        assert_eq!(response.code, HTTPCodes::NotFound);
+        assert_eq!(response.body, br#"{"error":"not found","path":"/missing"}"#);
+        let response = client.send("DELETE", "/echo", &[], &[]).await;
+        assert_eq!(response.code, HTTPCodes::MethodNotAllowed);
+    }
+
+    #[tokio::test]
+    async fn echoes_request_body() {
+        let response = TestClient::new(router())
+            .send("POST", "/echo", &[], b"ping")
+            .await;
+        assert_eq!(response.body, b"ping");
+    }
+
+    #[tokio::test]
+    async fn decodes_chunked_response() {
+        let response = TestClient::new(router()).get("/chunked").await;
+        assert_eq!(response.headers.get("transfer-encoding"), Some("chunked"));
+        assert_eq!(response.body, b"streamed body");
+    }
+
+    #[tokio::test]
+    async fn writes_headers_in_insertion_order() {
+        let response = TestClient::new(router()).get("/ordered").await;
+        let names: Vec<&str> = response.headers.iter().map(|(name, _)| name).collect();
+        assert_eq!(
+            names,
+            [
+                "x-second",
+                "x-first",
+                "keep-alive",
+                "content-type",
+                "content-length",
+                "connection"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn advertises_keep_alive_limits() {
+        let response = TestClient::new(router()).get("/").await;
+        assert_eq!(
+            response.headers.get("keep-alive"),
+            Some("timeout=5, max=99")
+        );
+        assert_eq!(response.headers.get("connection"), Some("keep-alive"));
+    }
+
+    #[tokio::test]
+    async fn honors_client_keep_alive() {
+        let response = TestClient::new(router())
+            .send("GET", "/", &[("Keep-Alive", "timeout=2, max=3")], &[])
+            .await;
+        assert_eq!(response.headers.get("keep-alive"), Some("timeout=2, max=2"));
+    }
+
+    #[tokio::test]
+    async fn closes_after_keep_alive_max() {
+        let config = ServerConfig {
+            keep_alive_max: 1,
+            ..ServerConfig::default()
+        };
+        let response = TestClient::new(router()).config(config).get("/").await;
+        assert_eq!(response.headers.get("connection"), Some("close"));
+        assert!(!response.headers.contains("keep-alive"));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_header_name() {
+        let response = TestClient::new(router())
+            .send("GET", "/", &[("bad name", "x")], &[])
+            .await;
+        assert_eq!(response.code, HTTPCodes::BadRequest);
+    }
+
+    #[test]
+    fn rejects_line_breaks_in_header_values() {
+        let mut headers = Headers::new();
+        for value in ["a\r\nset-cookie: x=1", "a\nb", "a\0b"] {
+            let name = crate::headers::HeaderName::new("X-Test").unwrap();
+            let err = headers.append(name, value).unwrap_err();
+            assert!(matches!(err, HTTPError::InvalidHeaderValue { .. }));
+        }
+        assert!(headers.is_empty());
+        assert!(headers.parse_line("x-test: a\0b").is_err());
+
+        assert!(Response::redirect("/next\r\nset-cookie: x=1", false).is_err());
+        assert!(Response::see_other("/done\n").is_err());
+        assert!(Response::new(HTTPCodes::OK)
+            .try_header("X-Test", "a\r\n\r\n<html>")
+            .is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid value for header x-test")]
+    fn panics_on_line_breaks_in_trusted_header_values() {
+        let _ = Response::new(HTTPCodes::OK).header("X-Test", "a\r\nb");
+    }
+
+    #[tokio::test]
+    async fn refuses_cookies_that_inject_attributes() {
+        use crate::cookie::Cookie;
+        let response = Response::new(HTTPCodes::OK);
+        assert!(response
+            .clone()
+            .try_set_cookie(Cookie::new("id", "1\r\nx-evil: 1"))
+            .is_err());
+        assert!(response
+            .clone()
+            .try_set_cookie(Cookie::new("id", "1; Domain=evil.test"))
+            .is_err());
+        assert!(response
+            .clone()
+            .try_set_cookie(Cookie::new("bad name", "1"))
+            .is_err());
+        assert!(response
+            .clone()
+            .try_set_cookie(Cookie::new("id", "1").path("/\n"))
+            .is_err());
+
+        let mut response = response;
+        response.cookies.push(Cookie::new("id", "1\r\nx-evil: 1"));
+        let mut written = Vec::new();
+        response.write_to(&mut written).await.unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert!(!written.contains("x-evil"));
+    }
+
+    #[tokio::test]
+    async fn reads_and_sets_cookies() {
+        use crate::cookie::Cookie;
+
+        let mut router = Router::new();
+        router
+            .get("/cookies", |request: Request| async move {
+                let mut cookies: Vec<String> = request
+                    .cookies
+                    .iter()
+                    .map(|(name, value)| format!("{name}={value}"))
+                    .collect();
+                cookies.sort();
+                Response::new(HTTPCodes::OK)
+                    .set_cookie(
+                        Cookie::new("session", "abc")
+                            .path("/")
+                            .domain("example.test")
+                            .max_age(Duration::from_secs(60))
+                            .http_only(true)
+                            .secure(true),
+                    )
+                    .set_cookie(Cookie::new("theme", "dark"))
+                    .body(cookies.join(","))
+            })
+            .unwrap();
+        let cookie = [("Cookie", "id=7; theme=\"light\" ;flag; =x")];
+        let response = TestClient::new(router)
+            .send("GET", "/cookies", &cookie, &[])
+            .await;
+        assert_eq!(response.body, b"id=7,theme=light");
+        assert_eq!(
+            response.headers.get_all("Set-Cookie"),
+            [
+                "session=abc; Path=/; Domain=example.test; Max-Age=60; HttpOnly; Secure",
+                "theme=dark",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_non_utf8_header() {
+        let router = Arc::new(router());
+        let (client, server) = duplex(64 * 1024);
+        let served = tokio::spawn(async move {
+            let connection = Connection::new(server);
+            let config = ServerConfig::default();
+            serve_connection(connection, &router, &MiddlewareStack::new(), &config).await;
+        });
+
+        let (reader, mut writer) = tokio::io::split(client);
+        writer
+            .write_all(b"GET / HTTP/1.1\r\nhost: test\r\nx-name: caf\xe9\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_response(&mut BufReader::new(reader), false).await;
+        assert_eq!(response.code, HTTPCodes::BadRequest);
+        served.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn runs_middleware() {
+        let mut middleware = MiddlewareStack::new();
+        middleware.push(|request: Request, next: Next| async move {
+            next.run(request).await.header("X-Seen", "yes")
+        });
+        let response = TestClient::new(router())
+            .middleware(middleware)
+            .get("/")
+            .await;
+        assert_eq!(response.headers.get("x-seen"), Some("yes"));
+    }
+
+    #[tokio::test]
+    async fn assigns_request_ids() {
+        let mut middleware = MiddlewareStack::new();
+        middleware.push(RequestIdMiddleware::new());
+        let client = TestClient::new(router()).middleware(middleware);
+
+        let response = client
+            .send("GET", "/", &[("X-Request-ID", "abc-123")], &[])
+            .await;
+        assert_eq!(response.headers.get("x-request-id"), Some("abc-123"));
+        let response = client.get("/").await;
+        let generated = response.headers.get("x-request-id").unwrap();
+        assert_eq!(generated.len(), 36);
+    }
+
+    #[tokio::test]
+    async fn passes_extensions_to_handlers() {
+        struct User(&'static str);
+
+        let mut router = Router::new();
+        router
+            .get("/me", |request: Request| async move {
+                let name = request.extract::<User>().map_or("nobody", |user| user.0);
+                Response::new(HTTPCodes::OK).body(name)
+            })
+            .unwrap();
+        let mut middleware = MiddlewareStack::new();
+        middleware.push(|mut request: Request, next: Next| {
+            request.extensions.insert(User("ada"));
+            next.run(request)
+        });
+        let response = TestClient::new(router)
+            .middleware(middleware)
+            .get("/me")
+            .await;
+        assert_eq!(response.body, b"ada");
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_body() {
+        let config = ServerConfig {
+            max_body_size: 2,
+            ..ServerConfig::default()
+        };
+        let response = TestClient::new(router())
+            .config(config)
+            .send("POST", "/echo", &[], b"too long")
+            .await;
+        assert_eq!(response.code, HTTPCodes::ContentTooLarge);
+    }
+
+    #[tokio::test]
+    async fn uses_configured_error_handler() {
+        let config = ServerConfig {
+            max_body_size: 2,
+            error_handler: Arc::new(|err: &HTTPError| {
+                Response::new(HTTPCodes::ContentTooLarge).body(err.to_string())
+            }),
+            ..ServerConfig::default()
+        };
+        let response = TestClient::new(router())
+            .config(config)
+            .send("POST", "/echo", &[], b"too long")
+            .await;
+        assert_eq!(response.code, HTTPCodes::ContentTooLarge);
+        assert_eq!(response.body, b"body too large");
+    }
+
+    #[tokio::test]
+    async fn reads_proxy_protocol_header() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 192.0.2.2 12345 80\r\nGET /";
+        let source = read_proxy_header(&mut stream).await.unwrap();
+        assert_eq!(source, Some("192.0.2.1:12345".parse().unwrap()));
+        assert_eq!(stream, b"GET /");
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_proxy_header(&mut stream).await.unwrap(), None);
+
+        for header in [
+            &b"PROXY TCP4 2001:db8::1 192.0.2.2 1 80\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 123456 80\r\n",
+            b"GET / HTTP/1.1\r\n",
+        ] {
+            let mut stream = header;
+            assert!(read_proxy_header(&mut stream).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_100_continue_before_reading_body() {
+        let (client, server) = duplex(64 * 1024);
+        let served = tokio::spawn(async move {
+            let mut connection = Connection::new(server);
+            let router = Arc::new(router());
+            let config = ServerConfig::default();
+            handle_connection(&mut connection, &router, &MiddlewareStack::new(), &config)
+                .await
+                .unwrap();
+        });
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut reader = BufReader::new(reader);
+        writer
+            .write_all(b"POST /echo HTTP/1.1\r\nhost: test\r\nexpect: 100-continue\r\ncontent-length: 5\r\n\r\n")
+            .await
+            .unwrap();
+
+        let interim = read_response(&mut reader, false).await;
+        assert_eq!(interim.code, HTTPCodes::Continue);
+        writer.write_all(b"hello").await.unwrap();
+        let response = read_response(&mut reader, false).await;
+        assert_eq!(response.code, HTTPCodes::OK);
+        assert_eq!(response.body, b"hello");
+        served.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn continue_handler_rejects_before_body() {
+        let config = ServerConfig {
+            continue_handler: Some(Arc::new(|request: &Request| {
+                (request.headers.get("Content-Type") != Some("text/plain"))
+                    .then(|| Response::new(HTTPCodes::UnsupportedMediaType))
+            })),
+            ..ServerConfig::default()
+        };
+        let client = TestClient::new(router()).config(config);
+        let expect = ("Expect", "100-continue");
+
+        let response = client
+            .send(
+                "POST",
+                "/echo",
+                &[expect, ("Content-Type", "image/png")],
+                b"png",
+            )
+            .await;
+        assert_eq!(response.code, HTTPCodes::UnsupportedMediaType);
+        assert_eq!(response.headers.get("Connection"), Some("close"));
+
+        let response = client
+            .send("POST", "/echo", &[("Expect", "something-else")], b"x")
+            .await;
+        assert_eq!(response.code, HTTPCodes::ExpectationFailed);
+    }
+
+    fn static_router() -> Router {
+        let root = std::env::temp_dir().join(format!("socket-static-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("digits.txt"), "0123456789").unwrap();
+        let mut router = Router::new();
+        router.get("/files/*path", StaticFiles::new(&root)).unwrap();
+        router
+            .get("/static/*filepath", StaticFiles::new(&root))
+            .unwrap();
+        router
+    }
+
+    #[tokio::test]
+    async fn serves_files_under_any_wildcard_name() {
+        let client = TestClient::new(static_router());
+        let response = client.get("/static/digits.txt").await;
+        assert_eq!(response.code, HTTPCodes::OK);
+        assert_eq!(response.body, b"0123456789");
+        let response = client.get("/static/missing.txt").await;
+        assert_eq!(response.code, HTTPCodes::NotFound);
+    }
+
+    #[tokio::test]
+    async fn serves_byte_ranges() {
+        let client = &TestClient::new(static_router());
+        let range = |range| async move {
+            client
+                .send("GET", "/files/digits.txt", &[("Range", range)], &[])
+                .await
+        };
+
+        let response = range("bytes=2-4").await;
+        assert_eq!(response.code, HTTPCodes::PartialContent);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(response.body, b"234");
+
+        let response = range("bytes=-3").await;
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 7-9/10"));
+        assert_eq!(response.body, b"789");
+
+        let response = range("bytes=10-").await;
+        assert_eq!(response.code, HTTPCodes::RangeNotSatisfiable);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes */10"));
+
+        let response = range("lines=1-2").await;
+        assert_eq!(response.code, HTTPCodes::OK);
+        assert_eq!(response.body, b"0123456789");
+
+        // Overlapping and adjacent ranges are merged into one.
+        let response = range("bytes=4-5, 0-2, 1-3").await;
+        assert_eq!(response.code, HTTPCodes::PartialContent);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 0-5/10"));
+        assert_eq!(response.body, b"012345");
+
+        let response = range("bytes=0-, 1-, 2-").await;
+        assert_eq!(response.code, HTTPCodes::OK);
+        assert_eq!(response.body, b"0123456789");
+    }
+
+    #[tokio::test]
+    async fn serves_multiple_byte_ranges() {
+        let response = TestClient::new(static_router())
+            .send(
+                "GET",
+                "/files/digits.txt",
+                &[("Range", "bytes=0-1, 8-")],
+                &[],
+            )
+            .await;
+        assert_eq!(response.code, HTTPCodes::PartialContent);
+        let content_type = response.headers.get("Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let expected = format!(
+            "\r\n--{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 8-9/10\r\n\r\n89\
+             \r\n--{boundary}--\r\n"
+        );
+        assert_eq!(String::from_utf8(response.body).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn ignores_range_when_if_range_is_stale() {
+        let client = &TestClient::new(static_router());
+        let current = client.get("/files/digits.txt").await;
+        let etag = current.headers.get("ETag").unwrap().to_string();
+        let last_modified = current.headers.get("Last-Modified").unwrap().to_string();
+        let resume = |validator| async move {
+            client
+                .send(
+                    "GET",
+                    "/files/digits.txt",
+                    &[("Range", "bytes=5-"), ("If-Range", validator)],
+                    &[],
+                )
+                .await
+        };
+
+        assert_eq!(resume(&etag).await.body, b"56789");
+        assert_eq!(resume(&last_modified).await.body, b"56789");
+
+        let stale = resume("\"0-0\"").await;
+        assert_eq!(stale.code, HTTPCodes::OK);
+        assert_eq!(stale.body, b"0123456789");
+        let stale = resume("Thu, 01 Jan 1970 00:00:00 GMT").await;
+        assert_eq!(stale.code, HTTPCodes::OK);
+        assert_eq!(resume(&format!("W/{etag}")).await.code, HTTPCodes::OK);
+    }
+
+    #[tokio::test]
+    async fn sends_redirects() {
+        let mut router = Router::new();
+        router
+            .get("/old", |_request| async {
+                Response::redirect("/new", true).unwrap()
+            })
+            .unwrap();
+        router
+            .post("/form", |_request| async {
+                Response::see_other("/done").unwrap()
+            })
+            .unwrap();
+        let client = TestClient::new(router);
+
+        let response = client.get("/old").await;
+        assert_eq!(response.code, HTTPCodes::MovedPermanently);
+        assert_eq!(response.headers.get("Location"), Some("/new"));
+        assert_eq!(response.headers.get("Content-Length"), Some("0"));
+
+        let response = client.send("POST", "/form", &[], b"a=1").await;
+        assert_eq!(response.code, HTTPCodes::SeeOther);
+        assert_eq!(response.headers.get("Location"), Some("/done"));
+        assert!(response.body.is_empty());
+    }
+
+    #[test]
+    fn merges_vary_headers() {
+        let mut response = Response::new(HTTPCodes::OK);
+        response
+            .vary(&["Accept-Encoding"])
+            .vary(&["accept-encoding", "Origin"]);
+        assert_eq!(
+            response.headers.get("Vary"),
+            Some("Accept-Encoding, Origin")
+        );
+        assert_eq!(response.headers.len(), 1);
+
+        response.vary(&["*"]).vary(&["Accept"]);
+        assert_eq!(response.headers.get("Vary"), Some("*"));
+    }
+
+    #[test]
+    fn composes_cache_control_directives() {
+        let response = Response::new(HTTPCodes::OK)
+            .cache_control_private()
+            .no_transform()
+            .cache_control_public(Duration::from_secs(60))
+            .immutable();
+        assert_eq!(
+            response.headers.get("Cache-Control"),
+            Some("no-transform, public, max-age=60, immutable")
+        );
+        assert_eq!(
+            Response::new(HTTPCodes::OK)
+                .no_store()
+                .headers
+                .get("Cache-Control"),
+            Some("no-store")
+        );
+    }
+
+    #[tokio::test]
+    async fn static_files_set_cache_control() {
+        let response = TestClient::new(static_router())
+            .get("/files/digits.txt")
+            .await;
+        assert_eq!(
+            response.headers.get("Cache-Control"),
+            Some("public, max-age=0")
+        );
+    }
+
+    #[test]
+    fn builds_etags() {
+        let tag = etag::strong(b"hello world");
+        assert_eq!(tag, "\"b-0d4a1185\"");
+        assert_eq!(etag::weak(&tag), "W/\"b-0d4a1185\"");
+        assert_eq!(etag::weak("W/\"x\""), "W/\"x\"");
+        assert_eq!(etag::weak("x"), "W/\"x\"");
+    }
+
+    #[tokio::test]
+    async fn answers_matching_if_none_match_with_304() {
+        let mut router = Router::new();
+        router
+            .get("/tagged", |_request| async {
+                Response::new(HTTPCodes::OK)
+                    .header("ETag", &etag::strong(b"body"))
+                    .no_cache()
+                    .body("body")
+            })
+            .unwrap();
+        let client = TestClient::new(router);
+        let tag = etag::strong(b"body");
+
+        let response = client
+            .send(
+                "GET",
+                "/tagged",
+                &[("If-None-Match", &etag::weak(&tag))],
+                &[],
+            )
+            .await;
+        assert_eq!(response.code, HTTPCodes::NotModified);
+        assert_eq!(response.headers.get("ETag"), Some(tag.as_str()));
+        assert_eq!(response.headers.get("Cache-Control"), Some("no-cache"));
+        assert!(response.body.is_empty());
+
+        let response = client
+            .send("GET", "/tagged", &[("If-None-Match", "\"other\"")], &[])
+            .await;
+        assert_eq!(response.code, HTTPCodes::OK);
+        assert_eq!(response.body, b"body");
+    }
+
+    #[tokio::test]
+    async fn answers_if_modified_since_with_304() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut router = Router::new();
+        router
+            .get("/dated", move |_request| async move {
+                Response::new(HTTPCodes::OK)
+                    .last_modified(modified)
+                    .body("body")
+            })
+            .unwrap();
+        let client = &TestClient::new(router);
+        let since = |date| async move {
+            client
+                .send("GET", "/dated", &[("If-Modified-Since", date)], &[])
+                .await
+        };
+
+        let response = since("Tue, 14 Nov 2023 22:13:20 GMT").await;
+        assert_eq!(response.code, HTTPCodes::NotModified);
+        assert_eq!(
+            response.headers.get("Last-Modified"),
+            Some("Tue, 14 Nov 2023 22:13:20 GMT")
+        );
+        assert_eq!(
+            since("Tue, 14 Nov 2023 22:13:19 GMT").await.code,
+            HTTPCodes::OK
+        );
+        assert_eq!(since("yesterday").await.code, HTTPCodes::OK);
+    }
+
+    #[tokio::test]
+    async fn keeps_handler_content_type_and_length() {
+        let mut router = Router::new();
+        router
+            .get("/stream", |_request| async {
+                Response::new(HTTPCodes::OK)
+                    .header("Content-Type", "application/json")
+                    .header("Content-Length", "2")
+                    .chunked_reader(&b"{}"[..])
+            })
+            .unwrap();
+        let response = TestClient::new(router).get("/stream").await;
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("application/json")
+        );
+        assert_eq!(response.headers.get("Content-Length"), Some("2"));
+        assert!(!response.headers.contains("Transfer-Encoding"));
+        assert_eq!(response.body, b"{}");
+    }
+
+    #[tokio::test]
+    async fn detects_upgrade_requests() {
+        let mut router = Router::new();
+        router
+            .get("/upgrade", |request: Request| async move {
+                Response::new(HTTPCodes::OK).body(request.upgrade().unwrap_or_default())
+            })
+            .unwrap();
+        let client = &TestClient::new(router);
+        let upgrade =
+            |headers| async move { client.send("GET", "/upgrade", headers, &[]).await.body };
+
+        let headers = [
+            ("Connection", "keep-alive, Upgrade"),
+            ("Upgrade", "websocket"),
+        ];
+        assert_eq!(upgrade(&headers).await, b"websocket");
+        assert_eq!(upgrade(&[("Upgrade", "websocket")]).await, b"");
+    }
+
+    /// A `WebSocket` on one end of a pipe, and the client's end.
+    #[cfg(feature = "websocket")]
+    fn websocket(max_message_size: usize) -> (crate::WebSocket, tokio::io::DuplexStream) {
+        let (client, server) = duplex(64 * 1024);
+        let (reader, writer) = tokio::io::split(server);
+        let reader: Box<dyn tokio::io::AsyncRead + Send + Unpin> = Box::new(reader);
+        let socket =
+            crate::WebSocket::new(BufReader::new(reader), Box::new(writer), max_message_size);
+        (socket, client)
+    }
+
+    /// A masked client frame with the given first byte.
+    #[cfg(feature = "websocket")]
+    fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![first];
+        match payload.len() {
+            length @ 0..=125 => frame.push(0x80 | length as u8),
+            length => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+        }
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        frame
+    }
+
+    /// Reads one short, unmasked server frame as its first byte and payload.
+    #[cfg(feature = "websocket")]
+    async fn server_frame(client: &mut tokio::io::DuplexStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        client.read_exact(&mut head).await.unwrap();
+        let mut payload = vec![0; usize::from(head[1])];
+        client.read_exact(&mut payload).await.unwrap();
+        (head[0], payload)
+    }
+
+    /// The close frame a server sends with `code`.
+    #[cfg(feature = "websocket")]
+    fn close_frame(code: u16) -> (u8, Vec<u8>) {
+        (0x88, code.to_be_bytes().to_vec())
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn websocket_requires_masked_frames() {
+        let (mut socket, mut client) = websocket(1024);
+        client.write_all(&[0x81, 0x02, b'h', b'i']).await.unwrap();
+        assert_eq!(socket.next_message().await, None);
+        assert_eq!(server_frame(&mut client).await, close_frame(1002));
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn websocket_reassembles_fragments() {
+        use crate::WsMessage;
+
+        let (mut socket, mut client) = websocket(1024);
+        let mut frames = client_frame(0x01, b"Hel");
+        // Control frames may arrive between fragments.
+        frames.extend(client_frame(0x89, b"p"));
+        frames.extend(client_frame(0x00, b"l"));
+        frames.extend(client_frame(0x80, b"o"));
+        client.write_all(&frames).await.unwrap();
+        assert_eq!(
+            socket.next_message().await,
+            Some(WsMessage::Text("Hello".to_string()))
+        );
+        assert_eq!(server_frame(&mut client).await, (0x8A, b"p".to_vec()));
+
+        // A continuation with nothing to continue, and a new message
+        // before the last one is finished.
+        let out_of_order = [
+            client_frame(0x80, b"x"),
+            [client_frame(0x02, b"a"), client_frame(0x81, b"b")].concat(),
+        ];
+        for frames in out_of_order {
+            let (mut socket, mut client) = websocket(1024);
+            client.write_all(&frames).await.unwrap();
+            assert_eq!(socket.next_message().await, None);
+            assert_eq!(server_frame(&mut client).await, close_frame(1002));
+        }
+
+        // The limit applies to the whole message, not each fragment.
+        let (mut socket, mut client) = websocket(4);
+        let frames = [client_frame(0x02, b"abc"), client_frame(0x80, b"de")].concat();
+        client.write_all(&frames).await.unwrap();
+        assert_eq!(socket.next_message().await, None);
+        assert_eq!(server_frame(&mut client).await, close_frame(1009));
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn websocket_rejects_large_or_fragmented_control_frames() {
+        for frame in [client_frame(0x89, &[0; 126]), client_frame(0x09, b"p")] {
+            let (mut socket, mut client) = websocket(1024);
+            client.write_all(&frame).await.unwrap();
+            assert_eq!(socket.next_message().await, None);
+            assert_eq!(server_frame(&mut client).await, close_frame(1002));
+        }
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn websocket_echoes_valid_close_codes() {
+        let cases: [(&[u8], u16); 9] = [
+            (b"", 1000),
+            (b"\x03\xE9", 1001),
+            (b"\x0F\xA0bye", 4000),
+            (b"\x03", 1002),
+            (b"\x03\xE7", 1002),
+            (b"\x03\xED", 1002),
+            (b"\x03\xF7", 1002),
+            (b"\x07\xD0", 1002),
+            (b"\x03\xE8\xFF", 1002),
+        ];
+        for (payload, code) in cases {
+            let (mut socket, mut client) = websocket(1024);
+            client
+                .write_all(&client_frame(0x88, payload))
+                .await
+                .unwrap();
+            assert_eq!(socket.next_message().await, None, "{payload:?}");
+            assert_eq!(
+                server_frame(&mut client).await,
+                close_frame(code),
+                "{payload:?}"
+            );
+        }
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn websocket_reads_64_bit_lengths() {
+        use crate::WsMessage;
+
+        let frame = |length: u64, payload: &[u8]| {
+            let mut frame = vec![0x82, 0x80 | 127];
+            frame.extend_from_slice(&length.to_be_bytes());
+            frame.extend_from_slice(&[0; 4]);
+            frame.extend_from_slice(payload);
+            frame
+        };
+        let (mut socket, mut client) = websocket(1024);
+        client.write_all(&frame(3, b"abc")).await.unwrap();
+        assert_eq!(
+            socket.next_message().await,
+            Some(WsMessage::Binary(b"abc".to_vec()))
+        );
+
+        for (length, code) in [(1 << 40, 1009), (1 << 63, 1002)] {
+            let (mut socket, mut client) = websocket(1024);
+            client.write_all(&frame(length, b"")).await.unwrap();
+            assert_eq!(socket.next_message().await, None);
+            assert_eq!(server_frame(&mut client).await, close_frame(code));
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_files() {
+        let path =
+            std::env::temp_dir().join(format!("socket-send-file-{}.json", std::process::id()));
+        std::fs::write(&path, "[1, 2]").unwrap();
+        let mut router = Router::new();
+        router
+            .get("/file", move |_request| {
+                let path = path.clone();
+                async move { Response::send_file(&path).await.unwrap() }
+            })
+            .unwrap();
+
+        let response = TestClient::new(router).get("/file").await;
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("application/json")
+        );
+        assert_eq!(response.headers.get("Content-Length"), Some("6"));
+        assert!(response.headers.contains("Last-Modified"));
+        assert_eq!(response.body, b"[1, 2]");
+    }
+
+    #[cfg(feature = "mmap")]
+    #[tokio::test]
+    async fn maps_files_above_the_threshold() {
+        let path = std::env::temp_dir().join(format!("socket-mmap-{}.txt", std::process::id()));
+        let contents: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &contents).unwrap();
+
+        // SAFETY: nothing writes to the file while it is mapped.
+        let small = unsafe { Response::send_file_mapped_above(&path, u64::MAX) }
+            .await
+            .unwrap();
+        assert_eq!(format!("{:?}", small.chunked), "Some(ChunkedBody::Reader)");
+        let mut mapped = unsafe { Response::send_file_mapped_above(&path, 1024) }
+            .await
+            .unwrap();
+        assert_eq!(format!("{:?}", mapped.chunked), "Some(ChunkedBody::Mapped)");
+        let mut written = Vec::new();
+        mapped.write_to(&mut written).await.unwrap();
+        assert!(written.ends_with(&contents));
+        let head = String::from_utf8_lossy(&written[..written.len() - contents.len()]);
+        assert!(head.contains("content-length: 100000\r\n"));
+
+        let mut mapped = unsafe { Response::send_file_mapped_above(&path, 1024) }
+            .await
+            .unwrap();
+        let mut body = Vec::new();
+        while let Some(chunk) = mapped.chunked.as_mut().unwrap().next_chunk().await.unwrap() {
+            body.extend_from_slice(&chunk);
+        }
+        assert_eq!(body, contents);
+    }
+
+    #[tokio::test]
+    async fn reports_open_connections() {
+        let builder = Server::new().bind("127.0.0.1:0");
+        let stats = builder.stats();
+        let mut router = Router::new();
+        router
+            .get("/health", move |_request| {
+                let connections = stats.connection_count();
+                async move { Response::new(HTTPCodes::OK).body(connections.to_string()) }
+            })
+            .unwrap();
+        let server = builder.router(router).build().unwrap();
+        let addr = server.local_addr();
+        let stats = server.stats();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(server.run(async {
+            let _ = stopped.await;
+        }));
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer
+            .write_all(b"GET /health HTTP/1.1\r\nhost: test\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_response(&mut BufReader::new(reader), false).await;
+        assert_eq!(response.body, b"1");
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+        assert_eq!(stats.connection_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn listens_on_several_addresses() {
+        let server = Server::new()
+            .bind("127.0.0.1:0")
+            .bind("127.0.0.1:0")
+            .router(router())
+            .build()
+            .unwrap();
+        let addrs = server.local_addrs();
+        assert_eq!(addrs.len(), 2);
+        assert_ne!(addrs[0], addrs[1]);
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(server.run(async {
+            let _ = stopped.await;
+        }));
+
+        for addr in addrs {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            writer
+                .write_all(b"GET / HTTP/1.1\r\nhost: test\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let response = read_response(&mut BufReader::new(reader), false).await;
+            assert_eq!(response.code, HTTPCodes::OK);
+        }
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn answers_pipelined_requests_in_order() {
+        let mut router = Router::new();
+        router
+            .post("/slow", |mut request: Request| async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Response::new(HTTPCodes::OK).body(request.body.read_to_end().await.unwrap())
+            })
+            .unwrap()
+            .get("/fast", |_request| async {
+                Response::new(HTTPCodes::OK).body("fast")
+            })
+            .unwrap();
+        let router = Arc::new(router);
+        let config = ServerConfig {
+            pipeline_depth: 4,
+            ..ServerConfig::default()
+        };
+        let (client, server) = duplex(64 * 1024);
+        let served = tokio::spawn(async move {
+            let connection = Connection::new(server);
+            serve_connection(connection, &router, &MiddlewareStack::new(), &config).await;
+        });
+
+        let (reader, mut writer) = tokio::io::split(client);
+        writer
+            .write_all(
+                b"POST /slow HTTP/1.1\r\nhost: test\r\ncontent-length: 4\r\n\r\nslow\
+                  GET /fast HTTP/1.1\r\nhost: test\r\n\r\n\
+                  GET /fast HTTP/1.1\r\nhost: test\r\nconnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut reader = BufReader::new(reader);
+        assert_eq!(read_response(&mut reader, false).await.body, b"slow");
+        assert_eq!(read_response(&mut reader, false).await.body, b"fast");
+        let last = read_response(&mut reader, false).await;
+        assert_eq!(last.body, b"fast");
+        assert_eq!(last.headers.get("Connection"), Some("close"));
+        served.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn detects_script_requests() {
+        let mut router = Router::new();
+        router
+            .get("/page", |request: Request| async move {
+                let kind = format!("{} {:?}", request.is_xhr(), request.fetch_mode());
+                Response::new(HTTPCodes::OK).body(kind)
+            })
+            .unwrap();
+        let client = TestClient::new(router);
+
+        let response = client
+            .send(
+                "GET",
+                "/page",
+                &[("X-Requested-With", "XMLHttpRequest")],
+                &[],
+            )
+            .await;
+        assert_eq!(response.body, b"true None");
+        let response = client
+            .send("GET", "/page", &[("Sec-Fetch-Mode", "navigate")], &[])
+            .await;
+        assert_eq!(response.body, b"false Some(\"navigate\")");
+    }
+
+    #[tokio::test]
+    async fn reads_forwarded_client_addresses() {
+        let mut router = Router::new();
+        router
+            .get("/ip", |request: Request| async move {
+                let ips = format!(
+                    "{:?} {:?} {:?}",
+                    request.forwarded_for(),
+                    request.real_ip(true),
+                    request.real_ip(false)
+                );
+                Response::new(HTTPCodes::OK).body(ips)
+            })
+            .unwrap();
+        let client = &TestClient::new(router);
+        let ips = |headers| async move { client.send("GET", "/ip", headers, &[]).await.body };
+
+        let forwarded = [(
+            "Forwarded",
+            "for=192.0.2.43;proto=https, for=\"[2001:db8:cafe::17]:4711\", for=unknown",
+        )];
+        assert_eq!(
+            ips(&forwarded).await,
+            b"[192.0.2.43, 2001:db8:cafe::17] Some(192.0.2.43) None"
+        );
+        let x_forwarded_for = [("X-Forwarded-For", "203.0.113.7, bogus, 10.0.0.1")];
+        assert_eq!(
+            ips(&x_forwarded_for).await,
+            b"[203.0.113.7, 10.0.0.1] Some(203.0.113.7) None"
+        );
+        assert_eq!(ips(&[]).await, b"[] None None");
+    }
+
+    #[tokio::test]
+    async fn checks_accepted_types_and_encodings() {
+        let mut router = Router::new();
+        router
+            .get("/accepts", |request: Request| async move {
+                let accepted = format!(
+                    "{} {} {} {:?}",
+                    request.accepts("text/html; charset=utf-8"),
+                    request.accepts("text/plain"),
+                    request.accepts("image/png"),
+                    request.preferred_encoding()
+                );
+                Response::new(HTTPCodes::OK).body(accepted)
+            })
+            .unwrap();
+        let client = &TestClient::new(router);
+        let accepted =
+            |headers| async move { client.send("GET", "/accepts", headers, &[]).await.body };
+
+        let headers = [
+            ("Accept", "text/*, text/plain;q=0, */*;q=0.1"),
+            ("Accept-Encoding", "gzip;q=0.8, br, identity;q=0"),
+        ];
+        assert_eq!(accepted(&headers).await, b"true false true Some(\"br\")");
+        let headers = [("Accept", "text/html"), ("Accept-Encoding", "*;q=0")];
+        assert_eq!(accepted(&headers).await, b"true false false None");
+        let headers = [("Accept-Encoding", "gzip;q=0.5, identity")];
+        assert_eq!(
+            accepted(&headers).await,
+            b"true true true Some(\"identity\")"
+        );
+        assert_eq!(accepted(&[]).await, b"true true true None");
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn negotiates_response_compression() {
+        let mut middleware = MiddlewareStack::new();
+        middleware.push(crate::CompressionMiddleware::new().threshold(0));
+        let client = &TestClient::new(router()).middleware(middleware);
+        let encoding = |accept_encoding| async move {
+            let headers = [("Accept-Encoding", accept_encoding)];
+            let response = client.send("GET", "/", &headers, &[]).await;
+            response.headers.get("Content-Encoding").map(str::to_string)
+        };
+
+        assert_eq!(encoding("deflate, gzip").await.as_deref(), Some("gzip"));
+        assert_eq!(encoding("gzip;q=0.5, *").await.as_deref(), Some("deflate"));
+        assert_eq!(encoding("GZIP;q=0, deflate;q=0").await, None);
+        assert_eq!(encoding("identity, gzip;q=0.5").await, None);
+        assert_eq!(encoding("*;q=0").await, None);
+    }
+
+    #[tokio::test]
+    async fn mounts_scoped_routes() {
+        let mut api = Router::new();
+        api.get("/users/:id", |request: Request| async move {
+            Response::new(HTTPCodes::OK).body(request.params["id"].clone())
+        })
+        .unwrap();
+        let mut admin = Router::new();
+        admin
+            .get("/", |_request| async {
+                Response::new(HTTPCodes::OK).body("admin")
+            })
+            .unwrap();
+        let mut router = router();
+        router
+            .scope("/api/", api)
+            .unwrap()
+            .scope_with_middleware(
+                "admin",
+                |request: Request, next: Next| async move {
+                    next.run(request).await.header("X-Scope", "admin")
+                },
+                admin,
+            )
+            .unwrap();
+        let client = TestClient::new(router);
+
+        assert_eq!(client.get("/api/users/7").await.body, b"7");
+        assert_eq!(client.get("/users/7").await.body, b"7");
+        let response = client.get("/admin").await;
+        assert_eq!(response.body, b"admin");
+        assert_eq!(response.headers.get("X-Scope"), Some("admin"));
+        assert!(!client.get("/").await.headers.contains("X-Scope"));
+        assert_eq!(client.get("/api").await.code, HTTPCodes::NotFound);
+    }
+
+    fn boom() -> Response {
+        panic!("boom")
+    }
+
+    #[tokio::test]
+    async fn answers_handler_panics_with_500() {
+        let mut router = Router::new();
+        router
+            .get("/panic", |request: Request| async move {
+                if request.query.contains_key("boom") {
+                    panic!("boom");
+                }
+                Response::new(HTTPCodes::OK)
+            })
+            .unwrap();
+        let response = TestClient::new(router).get("/panic?boom").await;
+        assert_eq!(response.code, HTTPCodes::InternalServerError);
+        assert_eq!(response.body, b"Internal Server Error");
+
+        let mut router = Router::new();
+        router
+            .get("/panic", |_request| async { boom() })
+            .unwrap()
+            .internal_error(|request: Request, err| async move {
+                let body = format!("{} failed: {err}", request.path);
+                Response::new(HTTPCodes::InternalServerError).body(body)
+            });
+        let response = TestClient::new(router).get("/panic").await;
+        assert_eq!(response.code, HTTPCodes::InternalServerError);
+        assert_eq!(response.body, b"/panic failed: boom");
+    }
+
+    #[tokio::test]
+    async fn answers_handler_errors_with_internal_error() {
+        let fallible = |mut router: Router| {
+            router
+                .get("/fail", |_request| async {
+                    Err::<Response, _>(HTTPError::Unknown)
+                })
+                .unwrap()
+                .get("/ok", |_request| async {
+                    Ok::<_, HTTPError>(Response::new(HTTPCodes::OK).body("fine"))
+                })
+                .unwrap()
+                .get("/panic", |_request| async { boom() })
+                .unwrap();
+            router
+        };
+
+        let client = TestClient::new(fallible(Router::new()));
+        let response = client.get("/fail").await;
+        assert_eq!(response.code, HTTPCodes::InternalServerError);
+        assert_eq!(response.body, b"Internal Server Error");
+        assert_eq!(client.get("/ok").await.body, b"fine");
+
+        let mut router = fallible(Router::new());
+        router.internal_error(|request: Request, err| async move {
+            let body = format!("{} failed: {err}", request.path);
+            Response::new(HTTPCodes::ServiceUnavailable).body(body)
+        });
+        let client = TestClient::new(router);
+        let response = client.get("/fail").await;
+        assert_eq!(response.code, HTTPCodes::ServiceUnavailable);
+        assert_eq!(response.body, b"/fail failed: unknown error");
+        let response = client.get("/panic").await;
+        assert_eq!(response.code, HTTPCodes::ServiceUnavailable);
+        assert_eq!(response.body, b"/panic failed: boom");
+        assert_eq!(client.get("/ok").await.code, HTTPCodes::OK);
+    }
+
+    #[test]
+    fn rejects_shadowed_routes() {
+        let handler = |_request| async { Response::new(HTTPCodes::OK) };
+        let mut router = Router::new();
+        router.get("/users/:id", handler).unwrap();
+        let err = router.get("/users/:name", handler).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid route /users/:name: shadowed by GET /users/:id"
+        );
+        router
+            .post("/users/:name", handler)
+            .unwrap()
+            .get("/users/me", handler)
+            .unwrap()
+            .get("/data", handler)
+            .unwrap()
+            .produces(["application/json"])
+            .get("/data", handler)
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_duplicate_static_routes() {
+        let handler = |_request| async { Response::new(HTTPCodes::OK) };
+        let mut router = Router::new();
+        router.get("/about", handler).unwrap();
+        let err = router.get("/about/", handler).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid route /about: shadowed by GET /about"
+        );
+
+        // A route for any method shadows later ones for a single method,
+        // but not the other way round.
+        router
+            .any("/x", handler)
+            .unwrap()
+            .get("/y", handler)
+            .unwrap();
+        let err = router.get("/x", handler).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid route /x: shadowed by any method /x"
+        );
+        router.any("/y", handler).unwrap();
+    }
+
+    #[tokio::test]
+    async fn splits_host_and_port() {
+        let mut router = Router::new();
+        router
+            .get("/host", |request: Request| async move {
+                let host = format!("{:?} {:?}", request.host(), request.host_port());
+                Response::new(HTTPCodes::OK).body(host)
+            })
+            .unwrap();
+        let client = &TestClient::new(router);
+        let host = |value| async move {
+            client
+                .send("GET", "/host", &[("Host", value)], &[])
+                .await
+                .body
+        };
+
+        assert_eq!(host("example.com").await, b"Some(\"example.com\") Some(80)");
+        assert_eq!(
+            host("example.com:8080").await,
+            b"Some(\"example.com\") Some(8080)"
+        );
+        assert_eq!(host("[::1]:4488").await, b"Some(\"[::1]\") Some(4488)");
+        assert_eq!(
+            host("[2001:db8::1]").await,
+            b"Some(\"[2001:db8::1]\") Some(80)"
+        );
+        assert_eq!(
+            host("example.com:http").await,
+            b"Some(\"example.com\") None"
+        );
+    }
+
+    fn scheme_router() -> Router {
+        let mut router = Router::new();
+        router
+            .get("/base", |request: Request| async move {
+                let base = format!("{} {:?}", request.scheme(), request.base_url());
+                Response::new(HTTPCodes::OK).body(base)
+            })
+            .unwrap();
+        router
+    }
+
+    #[tokio::test]
+    async fn builds_base_url_from_scheme_and_host() {
+        let client = &TestClient::new(scheme_router());
+        let base = |headers| async move { client.send("GET", "/base", headers, &[]).await.body };
+        let proxied = [("Host", "example.com"), ("X-Forwarded-Proto", "https")];
+        assert_eq!(base(&proxied).await, b"http Some(\"http://example.com\")");
+        let ported = [("Host", "example.com:8080")];
+        assert_eq!(
+            base(&ported).await,
+            b"http Some(\"http://example.com:8080\")"
+        );
+
+        let config = ServerConfig {
+            trust_forwarded_proto: true,
+            ..ServerConfig::default()
+        };
+        let client = &TestClient::new(scheme_router()).config(config);
+        let base = |headers| async move { client.send("GET", "/base", headers, &[]).await.body };
+        assert_eq!(base(&proxied).await, b"https Some(\"https://example.com\")");
+        let forwarded = [
+            ("Host", "example.com:443"),
+            ("Forwarded", "for=192.0.2.1;proto=https"),
+        ];
+        assert_eq!(
+            base(&forwarded).await,
+            b"https Some(\"https://example.com\")"
+        );
+    }
+
+    #[tokio::test]
+    async fn trusts_forwarded_proto_when_configured() {
+        let server = Server::new()
+            .bind("127.0.0.1:0")
+            .router(scheme_router())
+            .trust_forwarded_proto(true)
+            .build()
+            .unwrap();
+        let addr = server.local_addr();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(server.run(async {
+            let _ = stopped.await;
+        }));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"GET /base HTTP/1.1\r\nhost: example.com\r\nx-forwarded-proto: https\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let response = read_response(&mut BufReader::new(&mut stream), false).await;
+        assert_eq!(response.body, b"https Some(\"https://example.com\")");
+        drop(stream);
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn drops_connections_still_open_after_the_shutdown_timeout() {
+        let started = Arc::new(tokio::sync::Notify::new());
+        let mut router = Router::new();
+        let handler_started = started.clone();
+        router
+            .get("/hang", move |_request| {
+                let started = handler_started.clone();
+                async move {
+                    started.notify_one();
+                    std::future::pending::<Response>().await
+                }
+            })
+            .unwrap();
+        let server = Server::new()
+            .bind("127.0.0.1:0")
+            .router(router)
+            .shutdown_timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let addr = server.local_addr();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(server.run(async {
+            let _ = stopped.await;
+        }));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /hang HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        started.notified().await;
+        stop.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .expect("run outlived the shutdown timeout")
+            .unwrap()
+            .unwrap();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reports_https_for_tls_connections() {
+        let router = Arc::new(scheme_router());
+        let (client, server) = duplex(64 * 1024);
+        let served = tokio::spawn(async move {
+            let mut connection = Connection::new(server).with_tls();
+            let config = ServerConfig::default();
+            handle_connection(&mut connection, &router, &MiddlewareStack::new(), &config)
+                .await
+                .unwrap();
+        });
+
+        let (reader, mut writer) = tokio::io::split(client);
+        writer
+            .write_all(b"GET /base HTTP/1.1\r\nhost: example.com:4488\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_response(&mut BufReader::new(reader), false).await;
+        assert_eq!(response.body, b"https Some(\"https://example.com:4488\")");
+        served.await.unwrap();
+    }
+
+    #[test]
+    fn builds_html_and_text_responses() {
+        let html = Response::html("<h1>Hi</h1>");
+        assert_eq!(html.code, HTTPCodes::OK);
+        assert_eq!(
+            html.headers.get("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(html.content_length(), Some(11));
+        let text = Response::text("Hi");
+        assert_eq!(
+            text.headers.get("Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(text.body, b"Hi");
+    }
+
+    #[tokio::test]
+    async fn returns_unreadable_requests_as_errors() {
+        let (mut client, server) = duplex(64 * 1024);
+        client
+            .write_all(b"GET / HTTP/1.1\r\nbad header\r\n\r\n")
+            .await
+            .unwrap();
+        let mut connection = Connection::new(server);
+        let router = Arc::new(router());
+        let config = ServerConfig::default();
+        let err = handle_connection(&mut connection, &router, &MiddlewareStack::new(), &config)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HTTPError::ParsingError { .. }));
+    }
+
+    #[test]
+    fn converts_io_errors() {
+        fn read() -> Result<(), HTTPError> {
+            Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe))?
+        }
+        let err = read().unwrap_err();
+        assert!(matches!(err, HTTPError::Io(_)));
+        assert!(std::error::Error::source(&err).is_some());
+        let response = DefaultErrorHandler.handle(&err);
+        assert_eq!(response.code, HTTPCodes::InternalServerError);
+    }
+
+    #[tokio::test]
+    async fn accepts_ipv4_on_dual_stack_listener() {
+        let server = Server::new()
+            .bind("[::]:0")
+            .dual_stack(true)
+            .router(router())
+            .build()
+            .unwrap();
+        let port = server.local_addrs()[0].port();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(server.run(async {
+            let _ = stopped.await;
+        }));
+
+        let stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer
+            .write_all(b"GET / HTTP/1.1\r\nhost: test\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_response(&mut BufReader::new(reader), false).await;
+        assert_eq!(response.code, HTTPCodes::OK);
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn shares_port_with_reuse_port() {
+        let options = SocketOptions {
+            reuse_port: true,
+            ..SocketOptions::default()
+        };
+        let first = Server::new()
+            .bind("127.0.0.1:0")
+            .socket_options(options)
+            .build()
+            .unwrap();
+        let addr = first.local_addrs()[0].to_string();
+        let second = Server::new()
+            .bind(&addr)
+            .socket_options(options)
+            .build()
+            .unwrap();
+        assert_eq!(second.local_addrs(), first.local_addrs());
+        assert!(Server::new().bind(&addr).build().is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_on_several_workers() {
+        let server = Server::new()
+            .bind("127.0.0.1:0")
+            .workers(4)
+            .router(router())
+            .build()
+            .unwrap();
+        let addr = server.local_addr();
+        let stats = server.stats();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(server.run(async {
+            let _ = stopped.await;
+        }));
+
+        for _ in 0..8 {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            writer
+                .write_all(b"GET / HTTP/1.1\r\nhost: test\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let response = read_response(&mut BufReader::new(reader), false).await;
+            assert_eq!(response.code, HTTPCodes::OK);
+        }
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+        assert_eq!(stats.connection_count(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_accepting_after_accept_errors() {
+        // The descriptor limit is shared by the whole process, so the test
+        // lowers it in a child process running nothing else.
+        const CHILD: &str = "SOCKET_ACCEPT_ERRORS_CHILD";
+        if std::env::var_os(CHILD).is_none() {
+            let name = concat!(module_path!(), "::keeps_accepting_after_accept_errors");
+            let (_, name) = name.split_once("::").unwrap();
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", name, "--test-threads=1"])
+                .env(CHILD, "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let server = Server::new()
+                .bind("127.0.0.1:0")
+                .router(router())
+                .build()
+                .unwrap();
+            let addr = server.local_addr();
+            let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+            let running = tokio::spawn(server.run(async {
+                let _ = stopped.await;
+            }));
+
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            // Every descriptor below the lowest free one is taken, so a
+            // limit at that number leaves the server none to accept with.
+            let mut limit = unsafe { std::mem::zeroed::<libc::rlimit>() };
+            assert_eq!(
+                unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) },
+                0
+            );
+            let free = unsafe { libc::dup(0) };
+            assert!(free >= 0);
+            unsafe { libc::close(free) };
+            let lowered = libc::rlimit {
+                rlim_cur: free as libc::rlim_t,
+                ..limit
+            };
+            assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &lowered) }, 0);
+
+            let mut reader = BufReader::new(stream);
+            let mut byte = [0; 1];
+            let starved =
+                tokio::time::timeout(Duration::from_millis(300), reader.read(&mut byte)).await;
+            assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+            assert!(starved.is_err(), "accepted without a free descriptor");
+
+            let response = read_response(&mut reader, false).await;
+            assert_eq!(response.code, HTTPCodes::OK);
+            stop.send(()).unwrap();
+            running.await.unwrap().unwrap();
+        });
+    }
+
+    #[tokio::test]
+    async fn limits_requests_per_client() {
+        use crate::RateLimitMiddleware;
+
+        let mut middleware = MiddlewareStack::new();
+        middleware.push(RateLimitMiddleware::new(2, 0.5));
+        let router = Arc::new(router());
+        let get = |remote_addr: Option<&str>| {
+            let mut request = Request::from_head("GET", "/", "HTTP/1.1", Headers::new()).unwrap();
+            request.remote_addr = remote_addr.map(|addr| addr.parse().unwrap());
+            middleware.run(router.clone(), request)
+        };
+
+        assert_eq!(get(Some("192.0.2.1:1000")).await.code, HTTPCodes::OK);
+        assert_eq!(get(Some("192.0.2.1:1001")).await.code, HTTPCodes::OK);
+        let limited = get(Some("192.0.2.1:1002")).await;
+        assert_eq!(limited.code, HTTPCodes::TooManyRequests);
+        assert_eq!(limited.headers.get("Retry-After"), Some("2"));
+        // Other clients have their own bucket, and unknown ones none.
+        assert_eq!(get(Some("192.0.2.2:1000")).await.code, HTTPCodes::OK);
+        for _ in 0..3 {
+            assert_eq!(get(None).await.code, HTTPCodes::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn filters_connections_by_address() {
+        use crate::access::AccessControl;
+
+        let access = AccessControl::new()
+            .allow("127.0.0.0/8".parse().unwrap())
+            .deny("127.0.0.2/32".parse().unwrap());
+        assert!(!access.is_allowed("::ffff:127.0.0.2".parse().unwrap()));
+        assert!(!access.is_allowed("192.0.2.1".parse().unwrap()));
+
+        let server = Server::new()
+            .bind("127.0.0.1:0")
+            .router(router())
+            .access_control(access)
+            .build()
+            .unwrap();
+        let addr = server.local_addr();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(server.run(async {
+            let _ = stopped.await;
+        }));
+
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+        let mut denied = socket.connect(addr).await.unwrap();
+        let response = read_response(&mut BufReader::new(&mut denied), false).await;
+        assert_eq!(response.code, HTTPCodes::Forbidden);
+
+        let (_, response) = get_root(addr).await;
+        assert_eq!(response.code, HTTPCodes::OK);
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    /// Sends `GET /` on a new connection to `addr` and reads the response,
+    /// leaving the connection open.
+    async fn get_root(addr: std::net::SocketAddr) -> (tokio::net::TcpStream, Response) {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: test\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_response(&mut BufReader::new(&mut stream), false).await;
+        (stream, response)
+    }
+
+    /// `router()` plus `/wait`, which answers once `gate` is notified.
+    fn gated_router(gate: Arc<tokio::sync::Notify>) -> Router {
+        let mut router = router();
+        router
+            .get("/wait", move |_request| {
+                let gate = gate.clone();
+                async move {
+                    gate.notified().await;
+                    Response::new(HTTPCodes::OK).body("released")
+                }
+            })
+            .unwrap();
+        router
+    }
+
+    /// Sends `GET /wait` on a new connection to `addr`, see `gated_router`.
+    async fn get_wait(addr: std::net::SocketAddr) -> Response {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /wait HTTP/1.1\r\nhost: test\r\n\r\n")
+            .await
+            .unwrap();
+        read_response(&mut BufReader::new(&mut stream), false).await
+    }
+
+    #[tokio::test]
+    async fn limits_requests_not_connections() {
+        let server = Server::new()
+            .bind("127.0.0.1:0")
+            .max_concurrent_requests(1)
+            .router(router())
+            .build()
+            .unwrap();
+        let addr = server.local_addr();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(server.run(async {
+            let _ = stopped.await;
+        }));
+
+        // An idle keep-alive connection holds no permit.
+        let (mut idle, response) = get_root(addr).await;
+        assert_eq!(response.code, HTTPCodes::OK);
+        let (_, response) = get_root(addr).await;
+        assert_eq!(response.code, HTTPCodes::OK);
+        for _ in 0..3 {
+            idle.write_all(b"GET / HTTP/1.1\r\nhost: test\r\n\r\n")
+                .await
+                .unwrap();
+            let response = read_response(&mut BufReader::new(&mut idle), false).await;
+            assert_eq!(response.code, HTTPCodes::OK);
+        }
+
+        stop.send(()).unwrap();
+        drop(idle);
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_over_the_limit() {
+        let gate = Arc::new(tokio::sync::Notify::new());
+        let server = Server::new()
+            .bind("127.0.0.1:0")
+            .max_concurrent_requests(1)
+            .router(gated_router(gate.clone()))
+            .build()
+            .unwrap();
+        let addr = server.local_addr();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(server.run(async {
+            let _ = stopped.await;
+        }));
+
+        let waiting = tokio::spawn(get_wait(addr));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let (_, response) = get_root(addr).await;
+        assert_eq!(response.code, HTTPCodes::ServiceUnavailable);
+        gate.notify_waiters();
+        assert_eq!(waiting.await.unwrap().body, b"released");
+        let (_, response) = get_root(addr).await;
+        assert_eq!(response.code, HTTPCodes::OK);
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn queues_requests_over_the_limit() {
+        let gate = Arc::new(tokio::sync::Notify::new());
+        let server = Server::new()
+            .bind("127.0.0.1:0")
+            .max_concurrent_requests(1)
+            .queue_depth(1)
+            .router(gated_router(gate.clone()))
+            .build()
+            .unwrap();
+        let addr = server.local_addr();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(server.run(async {
+            let _ = stopped.await;
+        }));
+
+        let waiting = tokio::spawn(get_wait(addr));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let queued = tokio::spawn(get_root(addr));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let (_, response) = get_root(addr).await;
+        assert_eq!(response.code, HTTPCodes::ServiceUnavailable);
+        gate.notify_waiters();
+        assert_eq!(waiting.await.unwrap().code, HTTPCodes::OK);
+        let (_, response) = queued.await.unwrap();
+        assert_eq!(response.code, HTTPCodes::OK);
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn stops_accepting_at_max_connections() {
+        let server = Server::new()
+            .bind("127.0.0.1:0")
+            .max_connections(1)
+            .router(router())
+            .build()
+            .unwrap();
+        let addr = server.local_addr();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(server.run(async {
+            let _ = stopped.await;
+        }));
+
+        let (first, response) = get_root(addr).await;
+        assert_eq!(response.code, HTTPCodes::OK);
+        // Left in the backlog rather than refused.
+        let mut waiting = tokio::spawn(get_root(addr));
+        let early = tokio::time::timeout(Duration::from_millis(100), &mut waiting).await;
+        assert!(early.is_err());
+        drop(first);
+        let (_, response) = waiting.await.unwrap();
+        assert_eq!(response.code, HTTPCodes::OK);
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    #[cfg(feature = "tower")]
+    #[tokio::test]
+    async fn serves_router_as_tower_service() {
+        use tower_service::Service;
+
+        let mut service = crate::tower::TowerAdapter::new(router());
+        std::future::poll_fn(|cx| service.poll_ready(cx))
+            .await
+            .unwrap();
+        let request = Request::from_head("GET", "/", "HTTP/1.1", Headers::new()).unwrap();
+        let response = service.call(request).await.unwrap();
+        assert_eq!(response.code, HTTPCodes::OK);
+    }
+
+    #[cfg(feature = "tower")]
+    #[tokio::test]
+    async fn routes_to_tower_services() {
+        use std::task::{Context, Poll};
+
+        use tower_service::Service;
+
+        /// Echoes the path, or fails for `/fail`.
+        #[derive(Clone)]
+        struct Echo;
+
+        impl Service<Request> for Echo {
+            type Response = Response;
+            type Error = std::io::Error;
+            type Future = std::future::Ready<std::io::Result<Response>>;
+
+            fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, request: Request) -> Self::Future {
+                std::future::ready(match request.path.as_str() {
+                    "/fail" => Err(std::io::ErrorKind::Other.into()),
+                    path => Ok(Response::new(HTTPCodes::OK).body(path.to_string())),
+                })
+            }
+        }
+
+        let mut router = Router::new();
+        router
+            .get("/*rest", crate::tower::from_tower_service(Echo))
+            .unwrap();
+        let client = TestClient::new(router);
+        let response = client.get("/echo").await;
+        assert_eq!(response.body, b"/echo");
+        let response = client.get("/fail").await;
+        assert_eq!(response.code, HTTPCodes::InternalServerError);
+    }
+
+    #[tokio::test]
+    async fn parses_request_methods() {
+        assert_eq!(HttpMethod::parse("DELETE"), HttpMethod::Delete);
+        assert_eq!(
+            HttpMethod::parse("get"),
+            HttpMethod::Other("get".to_string())
+        );
+        assert_eq!(HttpMethod::parse("PROPFIND").as_str(), "PROPFIND");
+        assert_eq!(HttpMethod::Options.to_string(), "OPTIONS");
+
+        let mut router = Router::new();
+        router
+            .route("PROPFIND", "/dav", |request: Request| async move {
+                let method = request.method_enum();
+                Response::new(HTTPCodes::OK).body(method.to_string())
+            })
+            .unwrap();
+        let client = TestClient::new(router);
+        let response = client.send("PROPFIND", "/dav", &[], &[]).await;
+        assert_eq!(response.body, b"PROPFIND");
+        let response = client.send("propfind", "/dav", &[], &[]).await;
+        assert_eq!(response.code, HTTPCodes::MethodNotAllowed);
+        let response = client.send("GET", "/dav", &[], &[]).await;
+        assert_eq!(response.code, HTTPCodes::MethodNotAllowed);
+        assert_eq!(response.headers.get("Allow"), Some("PROPFIND, OPTIONS"));
+    }
+
+    #[test]
+    fn redacts_credentials_in_debug_output() {
+        let mut headers = Headers::new();
+        headers.add("Authorization", "Bearer secret-token");
+        headers.add("Proxy-Authorization", "Basic secret-proxy");
+        headers.add("Cookie", "session=secret-session");
+        headers.add("Content-Length", "42");
+        let mut request = Request::from_head("POST", "/login", "HTTP/1.1", headers).unwrap();
+
+        let debug = format!("{request:?}");
+        assert!(!debug.contains("secret"), "{debug}");
+        assert!(
+            debug.contains("\"authorization\": \"[REDACTED]\""),
+            "{debug}"
+        );
+        assert!(
+            debug.contains("\"proxy-authorization\": \"[REDACTED]\""),
+            "{debug}"
+        );
+        assert!(debug.contains("\"session\": \"[REDACTED]\""), "{debug}");
+        assert!(debug.contains("\"content-length\": \"42\""), "{debug}");
+        assert_eq!(
+            request.to_string(),
+            "POST /login HTTP/1.1 (4 headers, 42 bytes)"
+        );
+
+        request.redacted_headers = vec!["Content-Length".to_string()].into();
+        let debug = format!("{request:?}");
+        assert!(debug.contains("secret-token"), "{debug}");
+        assert!(
+            debug.contains("\"content-length\": \"[REDACTED]\""),
+            "{debug}"
+        );
+    }
+
+    #[tokio::test]
+    async fn clones_buffered_requests() {
+        let mut router = Router::new();
+        router
+            .post("/twice", |mut request: Request| async move {
+                assert!(request.body.try_clone().is_none());
+                let mut unbuffered = request.clone();
+                assert!(unbuffered.body.read_to_end().await.unwrap().is_empty());
+                let mut copy = request.buffered_clone().await.unwrap();
+                assert!(request.body.try_clone().is_some());
+                let mut body = copy.body.read_to_end().await.unwrap();
+                body.extend(request.body.read_to_end().await.unwrap());
+                Response::new(HTTPCodes::OK).body(body)
+            })
+            .unwrap();
+        let response = TestClient::new(router)
+            .send("POST", "/twice", &[], b"abc")
+            .await;
+        assert_eq!(response.body, b"abcabc");
+
+        let response = Response::text("hi").header("X-Test", "1");
+        let copy = response.clone();
+        assert_eq!(copy.body, b"hi");
+        assert_eq!(copy.headers.get("X-Test"), Some("1"));
+    }
+
+    #[test]
+    fn builds_responses_by_chaining() {
+        let response = Response::default()
+            .status(HTTPCodes::Created)
+            .header("X-Foo", "bar")
+            .body_str("hello")
+            .keep_alive(false);
+        assert_eq!(response.code, HTTPCodes::Created);
+        assert_eq!(response.headers.get("X-Foo"), Some("bar"));
+        assert_eq!(response.body, b"hello");
+        assert!(!response.keep_alive);
+
+        let response = Response::default()
+            .body_bytes(vec![1, 2, 3])
+            .status(HTTPCodes::NoContent);
+        assert!(response.body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reads_body_as_string() {
+        let mut router = Router::new();
+        router
+            .post("/text", |mut request: Request| async move {
+                let text = match request.body_string().await {
+                    Ok(text) => text,
+                    Err(err) => return Response::new(HTTPCodes::BadRequest).body(err.to_string()),
+                };
+                // The body is still there afterwards.
+                let bytes = request.body_bytes().await.unwrap().len();
+                Response::new(HTTPCodes::OK).body(format!("{text} {bytes}"))
+            })
+            .unwrap();
+        let client = TestClient::new(router);
+        let response = client.send("POST", "/text", &[], "héllo".as_bytes()).await;
+        assert_eq!(response.body, "héllo 6".as_bytes());
+        let response = client.send("POST", "/text", &[], b"ab\xff").await;
+        assert_eq!(response.code, HTTPCodes::BadRequest);
+        assert_eq!(
+            response.body,
+            b"failed to parse body: invalid UTF-8 after 2 bytes"
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_body_pieces() {
+        use futures_core::Stream;
+
+        let mut router = Router::new();
+        router
+            .post("/pieces", |mut request: Request| async move {
+                let mut stream = request.body.stream();
+                let mut pieces = Vec::new();
+                while let Some(piece) =
+                    std::future::poll_fn(|cx| std::pin::Pin::new(&mut stream).poll_next(cx)).await
+                {
+                    pieces.push(String::from_utf8(piece.unwrap().to_vec()).unwrap());
+                }
+                Response::new(HTTPCodes::OK).body(pieces.join(","))
+            })
+            .unwrap()
+            .post("/collect", |mut request: Request| async move {
+                Response::new(HTTPCodes::OK).body(request.body.collect().await.unwrap().to_vec())
+            })
+            .unwrap();
+        let client = TestClient::new(router);
+        let chunked = [("Transfer-Encoding", "chunked")];
+        let body = b"3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        let response = client.send("POST", "/pieces", &chunked, body).await;
+        assert_eq!(response.body, b"abc,de");
+        let response = client.send("POST", "/collect", &chunked, body).await;
+        assert_eq!(response.body, b"abcde");
+    }
+
+    /// Echoes the request body, or answers 400 with the error reading it.
+    fn echo_router() -> Router {
+        let mut router = Router::new();
+        router
+            .post("/echo", |mut request: Request| async move {
+                match request.body_bytes().await {
+                    Ok(body) => Response::new(HTTPCodes::OK).body(body.to_vec()),
+                    Err(err) => Response::new(HTTPCodes::BadRequest).body(err.to_string()),
+                }
+            })
+            .unwrap();
+        router
+    }
+
+    #[tokio::test]
+    async fn rejects_signed_chunk_sizes() {
+        let client = TestClient::new(echo_router());
+        let chunked = [("Transfer-Encoding", "chunked")];
+        let response = client
+            .send("POST", "/echo", &chunked, b"+3\r\nabc\r\n0\r\n\r\n")
+            .await;
+        assert_eq!(response.code, HTTPCodes::BadRequest);
+        assert_eq!(response.body, b"failed to parse chunked body");
+    }
+
+    #[tokio::test]
+    async fn skips_chunk_extensions_and_trailers() {
+        let router = Arc::new(echo_router());
+        let (client, server) = duplex(64 * 1024);
+        let served = tokio::spawn(async move {
+            let connection = Connection::new(server);
+            let config = ServerConfig::default();
+            serve_connection(connection, &router, &MiddlewareStack::new(), &config).await;
+        });
+
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut reader = BufReader::new(reader);
+        let head = "POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let body = "3;name=value\r\nabc\r\n2 ; flag\r\nde\r\n0;last\r\n\
+                    Expires: never\r\nX-Checksum: 5\r\n\r\n";
+        writer.write_all(head.as_bytes()).await.unwrap();
+        writer.write_all(body.as_bytes()).await.unwrap();
+        let response = read_response(&mut reader, false).await;
+        assert_eq!(response.code, HTTPCodes::OK);
+        assert_eq!(response.body, b"abcde");
+        // The trailers were consumed, so the next request parses.
+        writer
+            .write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 2\r\n\r\nfg")
+            .await
+            .unwrap();
+        assert_eq!(read_response(&mut reader, false).await.body, b"fg");
+        drop((reader, writer));
+        served.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_chunks() {
+        let client = TestClient::new(echo_router());
+        let chunked = [("Transfer-Encoding", "chunked")];
+        let bodies: [&[u8]; 5] = [
+            b"zz\r\nabc\r\n0\r\n\r\n",
+            b"\r\nabc\r\n0\r\n\r\n",
+            b"0x3\r\nabc\r\n0\r\n\r\n",
+            b"3\r\nabcX\r\n0\r\n\r\n",
+            b"10000000000000000\r\nabc\r\n0\r\n\r\n",
+        ];
+        for body in bodies {
+            let response = client.send("POST", "/echo", &chunked, body).await;
+            assert_eq!(response.code, HTTPCodes::BadRequest, "{body:?}");
+            assert_eq!(response.body, b"failed to parse chunked body");
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_chunked_bodies() {
+        let config = ServerConfig {
+            max_body_size: 4,
+            ..ServerConfig::default()
+        };
+        let client = TestClient::new(echo_router()).config(config);
+        let chunked = [("Transfer-Encoding", "chunked")];
+        let response = client
+            .send("POST", "/echo", &chunked, b"4\r\nabcd\r\n0\r\n\r\n")
+            .await;
+        assert_eq!(response.body, b"abcd");
+        // One chunk over the limit, and several that add up past it.
+        let response = client
+            .send("POST", "/echo", &chunked, b"a\r\n0123456789\r\n0\r\n\r\n")
+            .await;
+        assert_eq!(response.code, HTTPCodes::ContentTooLarge);
+        let response = client
+            .send(
+                "POST",
+                "/echo",
+                &chunked,
+                b"3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n",
+            )
+            .await;
+        assert_eq!(response.code, HTTPCodes::ContentTooLarge);
+    }
+
+    #[tokio::test]
+    async fn limits_chunk_lines_and_trailers() {
+        let config = ServerConfig {
+            max_head_size: 64,
+            max_header_count: 2,
+            ..ServerConfig::default()
+        };
+        let client = TestClient::new(echo_router()).config(config);
+        let chunked = [("Transfer-Encoding", "chunked")];
+        let long_size = format!("{}3\r\nabc\r\n0\r\n\r\n", "0".repeat(20_000));
+        let response = client
+            .send("POST", "/echo", &chunked, long_size.as_bytes())
+            .await;
+        assert_eq!(response.code, HTTPCodes::BadRequest);
+        assert_eq!(response.body, b"failed to parse chunked body");
+
+        let send = |trailers: String| {
+            let client = &client;
+            async move {
+                let body = format!("3\r\nabc\r\n0\r\n{trailers}\r\n");
+                client
+                    .send("POST", "/echo", &chunked, body.as_bytes())
+                    .await
+                    .code
+            }
+        };
+        assert_eq!(send("A: 1\r\nB: 2\r\n".to_string()).await, HTTPCodes::OK);
+        let too_many = "A: 1\r\nB: 2\r\nC: 3\r\n".to_string();
+        assert_eq!(send(too_many).await, HTTPCodes::ContentTooLarge);
+        let too_long = format!("A: {}\r\n", "x".repeat(100));
+        assert_eq!(send(too_long).await, HTTPCodes::ContentTooLarge);
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_chunked_bodies() {
+        let router = Arc::new(echo_router());
+        let (client, server) = duplex(64 * 1024);
+        let served = tokio::spawn(async move {
+            let connection = Connection::new(server);
+            let config = ServerConfig::default();
+            serve_connection(connection, &router, &MiddlewareStack::new(), &config).await;
+        });
+
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut reader = BufReader::new(reader);
+        writer
+            .write_all(b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nab")
+            .await
+            .unwrap();
+        writer.shutdown().await.unwrap();
+        let response = read_response(&mut reader, false).await;
+        assert_eq!(response.code, HTTPCodes::BadRequest);
+        assert_eq!(response.body, b"failed to parse truncated body");
+        served.await.unwrap();
+    }
+
+    #[cfg(feature = "multipart")]
+    #[test]
+    fn parses_multipart_boundaries() {
+        use crate::MultipartParser;
+
+        let parser = MultipartParser::from_content_type(
+            "Multipart/Form-Data; charset=utf-8; boundary=\"a b\"",
+        )
+        .unwrap();
+        let body = b"preamble\r\n--a b \t\r\n\
+            Content-Disposition: form-data; name=\"note\"\r\n\r\n\
+            keeps --a b and \r\n-- inside\r\n\
+            --a b\r\n\
+            Content-Disposition: form-data; name=upload; filename=\"x\\\"y.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            \r\n--a b--\r\nepilogue";
+        let fields = parser.parse(body).unwrap();
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0].name, "note");
+        assert_eq!(fields[0].data, b"keeps --a b and \r\n-- inside");
+        assert_eq!(fields[1].name, "upload");
+        assert_eq!(fields[1].filename.as_deref(), Some("x\"y.txt"));
+        assert_eq!(fields[1].content_type.as_deref(), Some("text/plain"));
+        assert_eq!(fields[1].data, b"");
+
+        let long = format!("multipart/form-data; boundary={}", "b".repeat(71));
+        for content_type in [
+            "text/plain; boundary=b",
+            "multipart/form-data",
+            "multipart/form-data; boundary=",
+            long.as_str(),
+        ] {
+            assert!(
+                MultipartParser::from_content_type(content_type).is_err(),
+                "{content_type}"
+            );
+        }
+    }
+
+    /// Answers `name=data` for every multipart field, or 400 with the error.
+    #[cfg(feature = "multipart")]
+    fn multipart_router() -> Router {
+        let mut router = Router::new();
+        router
+            .post("/form", |mut request: Request| async move {
+                match request.multipart().await {
+                    Ok(fields) => {
+                        let fields: Vec<String> = fields
+                            .iter()
+                            .map(|field| {
+                                format!("{}={}", field.name, String::from_utf8_lossy(&field.data))
+                            })
+                            .collect();
+                        Response::new(HTTPCodes::OK).body(fields.join("&"))
+                    }
+                    Err(err) => Response::new(HTTPCodes::BadRequest).body(err.to_string()),
+                }
+            })
+            .unwrap();
+        router
+    }
+
+    #[cfg(feature = "multipart")]
+    #[tokio::test]
+    async fn parses_multipart_split_across_reads() {
+        let body = "--xyz\r\nContent-Disposition: form-data; name=a\r\n\r\n1\r\n\
+                    --xyz\r\nContent-Disposition: form-data; name=b\r\n\r\n22\r\n--xyz--\r\n";
+        // Chunks of 7 bytes split delimiters, headers and data alike.
+        let mut chunked = Vec::new();
+        for piece in body.as_bytes().chunks(7) {
+            chunked.extend_from_slice(format!("{:x}\r\n", piece.len()).as_bytes());
+            chunked.extend_from_slice(piece);
+            chunked.extend_from_slice(b"\r\n");
+        }
+        chunked.extend_from_slice(b"0\r\n\r\n");
+        let headers = [
+            ("Content-Type", "multipart/form-data; boundary=xyz"),
+            ("Transfer-Encoding", "chunked"),
+        ];
+        let response = TestClient::new(multipart_router())
+            .send("POST", "/form", &headers, &chunked)
+            .await;
+        assert_eq!(response.body, b"a=1&b=22");
+    }
+
+    #[cfg(feature = "multipart")]
+    #[tokio::test]
+    async fn rejects_unterminated_or_oversized_multipart() {
+        let config = ServerConfig {
+            max_body_size: 80,
+            ..ServerConfig::default()
+        };
+        let client = TestClient::new(multipart_router()).config(config);
+        let headers = [("Content-Type", "multipart/form-data; boundary=xyz")];
+        let unterminated = b"--xyz\r\nContent-Disposition: form-data; name=a\r\n\r\n1";
+        let response = client.send("POST", "/form", &headers, unterminated).await;
+        assert_eq!(response.code, HTTPCodes::BadRequest);
+        assert_eq!(
+            response.body,
+            b"failed to parse unterminated multipart body"
+        );
+        let response = client
+            .send("POST", "/form", &headers, b"no delimiter")
+            .await;
+        assert_eq!(response.body, b"failed to parse multipart body");
+
+        let oversized = format!(
+            "--xyz\r\nContent-Disposition: form-data; name=a\r\n\r\n{}\r\n--xyz--",
+            "x".repeat(80)
+        );
+        let response = client
+            .send("POST", "/form", &headers, oversized.as_bytes())
+            .await;
+        assert_eq!(response.code, HTTPCodes::ContentTooLarge);
+    }
+
+    /// Serves one connection with `connect_targets` and sends `CONNECT
+    /// target` on it.
+    async fn connect(
+        target: &str,
+        targets: &[&str],
+    ) -> (
+        BufReader<tokio::io::ReadHalf<tokio::io::DuplexStream>>,
+        tokio::io::WriteHalf<tokio::io::DuplexStream>,
+        Response,
+    ) {
+        let (client, server) = duplex(64 * 1024);
+        let config = ServerConfig {
+            connect_targets: targets.iter().map(|target| target.to_string()).collect(),
+            ..ServerConfig::default()
+        };
+        tokio::spawn(async move {
+            let router = Arc::new(router());
+            serve_connection(
+                Connection::new(server),
+                &router,
+                &MiddlewareStack::new(),
+                &config,
+            )
+            .await;
+        });
+        let (reader, mut writer) = tokio::io::split(client);
+        let request = format!("CONNECT {target} HTTP/1.1\r\nhost: {target}\r\n\r\n");
+        writer.write_all(request.as_bytes()).await.unwrap();
+        let mut reader = BufReader::new(reader);
+        let response = read_response(&mut reader, true).await;
+        (reader, writer, response)
+    }
+
+    #[tokio::test]
+    async fn tunnels_connect_to_allowed_targets() {
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = upstream.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        let allowed = std::slice::from_ref(&target);
+        let wait = Duration::from_secs(1);
+        let mut established = crate::tunnel::connect_tunnel(&target, allowed, wait).await;
+        let mut head = Vec::new();
+        established.write_to(&mut head).await.unwrap();
+        assert!(head.starts_with(b"HTTP/1.1 200 Connection Established\r\n"));
+
+        let (mut reader, mut writer, response) = connect(&target, &[&target]).await;
+        assert_eq!(response.code, HTTPCodes::OK);
+        assert!(!response.headers.contains("Content-Length"));
+        writer.write_all(b"raw bytes").await.unwrap();
+        let mut echoed = [0; 9];
+        reader.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"raw bytes");
+    }
+
+    #[tokio::test]
+    async fn refuses_connect_to_other_targets() {
+        let (_, _, response) = connect("example.com:443", &[]).await;
+        assert_eq!(response.code, HTTPCodes::MethodNotAllowed);
+        let (_, _, response) = connect("example.com:443", &["example.com:80"]).await;
+        assert_eq!(response.code, HTTPCodes::Forbidden);
+        let (_, _, response) = connect("example.com", &["example.com:80"]).await;
+        assert_eq!(response.code, HTTPCodes::BadRequest);
+    }
+
+    /// An HTTP/2 client for `router` served with `config`.
+    #[cfg(feature = "http2")]
+    async fn h2_client(
+        router: Router,
+        config: ServerConfig,
+    ) -> h2::client::SendRequest<bytes::Bytes> {
+        let (client, server) = duplex(64 * 1024);
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let peer = crate::http2::Peer { addr, remote: addr };
+        let (stop, shutdown) = tokio::sync::watch::channel(false);
+        tokio::spawn(async move {
+            crate::http2::serve_h2(
+                server,
+                peer,
+                Arc::new(router),
+                MiddlewareStack::new(),
+                Arc::new(config),
+                shutdown,
+                None,
+            )
+            .await;
+            drop(stop);
+        });
+        let (send, connection) = h2::client::handshake(client).await.unwrap();
+        tokio::spawn(connection);
+        send.ready().await.unwrap()
+    }
+
+    #[cfg(feature = "http2")]
+    fn h2_request(method: &str, uri: &str) -> http::Request<()> {
+        http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(())
+            .unwrap()
+    }
+
+    #[cfg(feature = "http2")]
+    #[tokio::test]
+    async fn streams_http2_bodies_past_the_write_timeout() {
+        let mut router = Router::new();
+        router
+            .get("/slow", |_request| async {
+                let (sender, receiver) = tokio::sync::mpsc::channel(1);
+                tokio::spawn(async move {
+                    for piece in ["a", "b", "c", "d"] {
+                        tokio::time::sleep(Duration::from_millis(40)).await;
+                        let _ = sender.send(piece.as_bytes().to_vec()).await;
+                    }
+                });
+                Response::new(HTTPCodes::OK).chunked_channel(receiver)
+            })
+            .unwrap();
+        let config = ServerConfig {
+            write_timeout: Duration::from_millis(50),
+            ..ServerConfig::default()
+        };
+        let mut send = h2_client(router, config).await;
+        let (response, _) = send
+            .send_request(h2_request("GET", "https://test/slow"), true)
+            .unwrap();
+        let mut body = response.await.unwrap().into_body();
+        let mut received = Vec::new();
+        while let Some(data) = body.data().await {
+            let data = data.unwrap();
+            let _ = body.flow_control().release_capacity(data.len());
+            received.extend_from_slice(&data);
+        }
+        assert_eq!(received, b"abcd");
+    }
+
+    #[cfg(feature = "http2")]
+    #[tokio::test]
+    async fn refuses_connect_over_http2() {
+        let mut send = h2_client(router(), ServerConfig::default()).await;
+        let (response, _) = send
+            .send_request(h2_request("CONNECT", "example.com:443"), true)
+            .unwrap();
+        assert_eq!(response.await.unwrap().status(), 501);
+        let (response, _) = send
+            .send_request(h2_request("GET", "https://test/"), true)
+            .unwrap();
+        assert_eq!(response.await.unwrap().status(), 200);
+    }
+
+    #[tokio::test]
+    async fn recovers_from_malformed_request_line() {
+        // Pipelined connections recover the same way.
+        for pipeline_depth in [1, 4] {
+            let router = Arc::new(router());
+            let (client, server) = duplex(64 * 1024);
+            let served = tokio::spawn(async move {
+                let connection = Connection::new(server);
+                let config = ServerConfig {
+                    pipeline_depth,
+                    ..ServerConfig::default()
+                };
+                serve_connection(connection, &router, &MiddlewareStack::new(), &config).await;
+            });
+
+            let (reader, mut writer) = tokio::io::split(client);
+            let mut reader = BufReader::new(reader);
+            writer
+                .write_all(b"GET / HTTP/1.1\r\nhost: test\r\n\r\n")
+                .await
+                .unwrap();
+            assert_eq!(read_response(&mut reader, false).await.code, HTTPCodes::OK);
+            writer
+                .write_all(b"NOT A VALID LINE\r\nhost: test\r\n\r\nGET / HTTP/1.1\r\n\r\n")
+                .await
+                .unwrap();
+            let response = read_response(&mut reader, false).await;
+            assert_eq!(response.code, HTTPCodes::BadRequest);
+            assert_eq!(response.headers.get("Connection"), Some("keep-alive"));
+            assert_eq!(read_response(&mut reader, false).await.code, HTTPCodes::OK);
+
+            // Of two bad requests in a row only the first is skipped.
+            writer
+                .write_all(b"garbage\r\n\r\nmore garbage\r\n\r\n")
+                .await
+                .unwrap();
+            let skipped = read_response(&mut reader, false).await;
+            assert_eq!(skipped.headers.get("Connection"), Some("keep-alive"));
+            let closed = read_response(&mut reader, false).await;
+            assert_eq!(closed.code, HTTPCodes::BadRequest);
+            assert_eq!(closed.headers.get("Connection"), Some("close"));
+            served.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_pipelined_connections_on_body_errors() {
+        let router = Arc::new(echo_router());
+        let (client, server) = duplex(64 * 1024);
+        let served = tokio::spawn(async move {
+            let connection = Connection::new(server);
+            let config = ServerConfig {
+                pipeline_depth: 4,
+                max_body_size: 4,
+                ..ServerConfig::default()
+            };
+            serve_connection(connection, &router, &MiddlewareStack::new(), &config).await;
+        });
+
+        let (reader, mut writer) = tokio::io::split(client);
+        writer
+            .write_all(
+                b"POST /echo HTTP/1.1\r\ncontent-length: 8\r\n\r\ntoo long\
+                  GET / HTTP/1.1\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut reader = BufReader::new(reader);
+        let response = read_response(&mut reader, false).await;
+        assert_eq!(response.code, HTTPCodes::ContentTooLarge);
+        assert_eq!(response.headers.get("Connection"), Some("close"));
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        served.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ignores_empty_lines_before_request_line() {
+        let router = Arc::new(router());
+        let (client, server) = duplex(64 * 1024);
+        let served = tokio::spawn(async move {
+            let connection = Connection::new(server);
+            let config = ServerConfig::default();
+            serve_connection(connection, &router, &MiddlewareStack::new(), &config).await;
+        });
+
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut reader = BufReader::new(reader);
+        writer
+            .write_all(b"\r\nGET / HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(read_response(&mut reader, false).await.code, HTTPCodes::OK);
+        writer
+            .write_all(b"\r\nGET / HTTP/1.1\r\n\r\n\r\n\r\nGET /users/7 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(read_response(&mut reader, false).await.code, HTTPCodes::OK);
+        let response = read_response(&mut reader, false).await;
+        assert_eq!(response.code, HTTPCodes::OK);
+        assert_eq!(response.body, b"7");
+        drop((reader, writer));
+        served.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn closes_on_malformed_first_request() {
+        let router = Arc::new(router());
+        let (client, server) = duplex(64 * 1024);
+        let served = tokio::spawn(async move {
+            let connection = Connection::new(server);
+            let config = ServerConfig::default();
+            serve_connection(connection, &router, &MiddlewareStack::new(), &config).await;
+        });
+
+        let (reader, mut writer) = tokio::io::split(client);
+        writer
+            .write_all(b"NOT A VALID LINE\r\n\r\nGET / HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_response(&mut BufReader::new(reader), false).await;
+        assert_eq!(response.code, HTTPCodes::BadRequest);
+        assert_eq!(response.headers.get("Connection"), Some("close"));
+        served.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_too_many_headers() {
+        let config = ServerConfig {
+            max_header_count: 4,
+            ..ServerConfig::default()
+        };
+        let client = TestClient::new(router()).config(config);
+        let response = client.send("GET", "/", &[("a", "1")], &[]).await;
+        assert_eq!(response.code, HTTPCodes::OK);
+
+        let headers = [("a", "1"), ("b", "2"), ("c", "3"), ("d", "4"), ("e", "5")];
+        let response = client.send("GET", "/", &headers, &[]).await;
+        assert_eq!(response.code, HTTPCodes::RequestHeaderFieldsTooLarge);
+    }
+
+    #[tokio::test]
+    async fn rejects_header_flood_after_reading_head() {
+        let router = Arc::new(router());
+        let (client, server) = duplex(64 * 1024);
+        let served = tokio::spawn(async move {
+            let connection = Connection::new(server);
+            let config = ServerConfig::default();
+            serve_connection(connection, &router, &MiddlewareStack::new(), &config).await;
+        });
+
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut head = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..200 {
+            head.extend_from_slice(format!("x-flood-{i}: {i}\r\n").as_bytes());
+        }
+        head.extend_from_slice(b"\r\n");
+        writer.write_all(&head).await.unwrap();
+        let response = read_response(&mut BufReader::new(reader), false).await;
+        assert_eq!(response.code, HTTPCodes::RequestHeaderFieldsTooLarge);
+        assert_eq!(response.headers.get("Connection"), Some("close"));
+        served.await.unwrap();
+    }
+
+    fn cors_client(cors: crate::cors::CorsConfig) -> TestClient {
+        let mut middleware = MiddlewareStack::new();
+        middleware.push(cors);
+        TestClient::new(router()).middleware(middleware)
+    }
+
+    #[tokio::test]
+    async fn never_allows_any_origin_with_credentials() {
+        let client = cors_client(crate::cors::CorsConfig {
+            allowed_origins: vec!["*".to_string(), "https://app.test".to_string()],
+            allow_credentials: true,
+            ..Default::default()
+        });
+        let response = client
+            .send("GET", "/", &[("Origin", "https://evil.test")], &[])
+            .await;
+        assert_eq!(response.headers.get("Access-Control-Allow-Origin"), None);
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Credentials"),
+            None
+        );
+        assert_eq!(response.headers.get("Vary"), Some("Origin"));
+
+        let response = client
+            .send("GET", "/", &[("Origin", "https://app.test")], &[])
+            .await;
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Origin"),
+            Some("https://app.test")
+        );
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Credentials"),
+            Some("true")
+        );
+        assert_eq!(response.headers.get("Vary"), Some("Origin"));
+    }
+
+    #[tokio::test]
+    async fn checks_preflight_methods() {
+        let client = cors_client(crate::cors::CorsConfig {
+            allowed_origins: vec!["https://app.test".to_string()],
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            ..Default::default()
+        });
+        let preflight = |method: &'static str| {
+            let client = &client;
+            async move {
+                client
+                    .send(
+                        "OPTIONS",
+                        "/",
+                        &[
+                            ("Origin", "https://app.test"),
+                            ("Access-Control-Request-Method", method),
+                        ],
+                        &[],
+                    )
+                    .await
+            }
+        };
+
+        let allowed = preflight("POST").await;
+        assert_eq!(allowed.code, HTTPCodes::NoContent);
+        assert_eq!(
+            allowed.headers.get("Access-Control-Allow-Methods"),
+            Some("GET, POST")
+        );
+        let refused = preflight("DELETE").await;
+        assert_eq!(refused.code, HTTPCodes::NoContent);
+        assert_eq!(refused.headers.get("Access-Control-Allow-Origin"), None);
+        assert_eq!(refused.headers.get("Access-Control-Allow-Methods"), None);
+        assert_eq!(refused.headers.get("Vary"), Some("Origin"));
+    }
+
+    #[tokio::test]
+    async fn answers_any_origin_with_wildcard() {
+        let client = cors_client(crate::cors::CorsConfig::default());
+        let response = client
+            .send("GET", "/", &[("Origin", "https://any.test")], &[])
+            .await;
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Origin"),
+            Some("*")
+        );
+        assert_eq!(response.headers.get("Vary"), None);
+    }
+
+    #[tokio::test]
+    async fn answers_preflight_with_allowed_headers() {
+        let preflight = [
+            ("Origin", "https://app.test"),
+            ("Access-Control-Request-Method", "PUT"),
+            ("Access-Control-Request-Headers", "x-token, content-type"),
+        ];
+        let client = cors_client(crate::cors::CorsConfig {
+            allowed_origins: vec!["https://app.test".to_string()],
+            max_age: Some(Duration::from_secs(600)),
+            allow_credentials: true,
+            ..Default::default()
+        });
+        let response = client.send("OPTIONS", "/", &preflight, &[]).await;
+        assert_eq!(response.code, HTTPCodes::NoContent);
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Origin"),
+            Some("https://app.test")
+        );
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Credentials"),
+            Some("true")
+        );
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Headers"),
+            Some("x-token, content-type")
+        );
+        assert_eq!(response.headers.get("Access-Control-Max-Age"), Some("600"));
+
+        let client = cors_client(crate::cors::CorsConfig {
+            allowed_headers: vec!["x-token".to_string()],
+            ..Default::default()
+        });
+        let response = client.send("OPTIONS", "/", &preflight, &[]).await;
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Headers"),
+            Some("x-token")
+        );
+        // Without Access-Control-Request-Method it is a plain OPTIONS.
+        let response = client
+            .send("OPTIONS", "/", &[("Origin", "https://app.test")], &[])
+            .await;
+        assert!(response.headers.contains("Allow"));
+        assert_eq!(response.headers.get("Access-Control-Allow-Methods"), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_heads() {
+        let config = ServerConfig {
+            max_head_size: 1024,
+            ..ServerConfig::default()
+        };
+        let client = TestClient::new(router()).config(config);
+        let long = "x".repeat(2000);
+        let response = client.send("GET", "/", &[("X-Long", &long)], &[]).await;
+        assert_eq!(response.code, HTTPCodes::RequestHeaderFieldsTooLarge);
+
+        let value = "x".repeat(100);
+        let headers = [("X-A", value.as_str()); 20];
+        let response = client.send("GET", "/", &headers, &[]).await;
+        assert_eq!(response.code, HTTPCodes::RequestHeaderFieldsTooLarge);
+
+        let response = client.send("GET", "/", &[("X-Short", "1")], &[]).await;
+        assert_eq!(response.code, HTTPCodes::OK);
+    }
+
+    #[tokio::test]
+    async fn stops_reading_lines_past_the_limit() {
+        let (mut client, server) = duplex(64 * 1024);
+        let mut connection = Connection::new(server);
+        let router = Arc::new(router());
+        let config = ServerConfig::default();
+        let sent = tokio::spawn(async move {
+            client
+                .write_all(b"GET / HTTP/1.1\r\nx-endless: ")
+                .await
+                .unwrap();
+            // Never ends the line; the server gives up after 16 KiB.
+            let filler = vec![b'x'; 4096];
+            while client.write_all(&filler).await.is_ok() {}
+        });
+        let err = handle_connection(&mut connection, &router, &MiddlewareStack::new(), &config)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HTTPError::HeadTooLarge));
+        drop(connection);
+        sent.await.unwrap();
+    }
+}
@@ -0,0 +1,45 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+
+/// PEM files used to serve HTTPS.
+///
+/// Self-signed certificates work for development, e.g.
+/// `openssl req -x509 -newkey rsa:2048 -nodes -keyout key.pem -out cert.pem
+/// -days 365 -subj /CN=localhost`; clients then need to be told to trust
+/// `cert.pem` (`curl --cacert cert.pem`).
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Builds a rustls server config from a PEM certificate chain and a PEM
+/// private key. ALPN advertises `h2` as well when the `http2` feature is
+/// enabled.
+pub fn load_tls_config(cert_pem: &Path, key_pem: &Path) -> Result<Arc<ServerConfig>, io::Error> {
+    let invalid =
+        |err: &dyn std::fmt::Display| io::Error::new(io::ErrorKind::InvalidData, err.to_string());
+
+    let certs = CertificateDer::pem_file_iter(cert_pem)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| invalid(&err))?;
+    let key = PrivateKeyDer::from_pem_file(key_pem).map_err(|err| invalid(&err))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| invalid(&err))?;
+    // Clients that negotiate `h2` are served by the HTTP/2 layer; everyone
+    // else falls back to HTTP/1.1.
+    config.alpn_protocols = if cfg!(feature = "http2") {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+    Ok(Arc::new(config))
+}
@@ -0,0 +1,88 @@
+use std::future::poll_fn;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tower_service::Service;
+use tracing::error;
+
+use crate::codes::HTTPCodes;
+use crate::error::HTTPError;
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::{BoxError, BoxFuture, Handler, Router};
+
+/// Serves a `Router` as a `tower::Service`, to be wrapped in tower
+/// middleware such as timeouts or concurrency limits. Always ready; the
+/// router answers every request, so the service never fails.
+#[derive(Clone)]
+pub struct TowerAdapter {
+    router: Arc<Router>,
+}
+
+impl TowerAdapter {
+    pub fn new(router: Router) -> TowerAdapter {
+        TowerAdapter::from(Arc::new(router))
+    }
+}
+
+impl From<Arc<Router>> for TowerAdapter {
+    fn from(router: Arc<Router>) -> TowerAdapter {
+        TowerAdapter { router }
+    }
+}
+
+impl Service<Request> for TowerAdapter {
+    type Response = Response;
+    type Error = HTTPError;
+    type Future = BoxFuture<Result<Response, HTTPError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), HTTPError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let router = self.router.clone();
+        Box::pin(async move { Ok(router.handle(request).await) })
+    }
+}
+
+/// Route handler calling a `tower::Service`, see `from_tower_service`.
+pub struct TowerHandler<S> {
+    service: S,
+}
+
+/// Wraps `service` as a route handler. Every request is served by a clone
+/// of `service`, once it is ready. Errors are logged and answered with
+/// `500 Internal Server Error`.
+pub fn from_tower_service<S>(service: S) -> TowerHandler<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + Sync + 'static,
+    S::Error: Into<BoxError> + Send,
+    S::Future: Send,
+{
+    TowerHandler { service }
+}
+
+impl<S> Handler for TowerHandler<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + Sync + 'static,
+    S::Error: Into<BoxError> + Send,
+    S::Future: Send,
+{
+    fn call(&self, request: Request) -> BoxFuture<Response> {
+        let mut service = self.service.clone();
+        Box::pin(async move {
+            let response = match poll_fn(|cx| service.poll_ready(cx)).await {
+                Ok(()) => service.call(request).await,
+                Err(err) => Err(err),
+            };
+            response.unwrap_or_else(|err| {
+                let err: BoxError = err.into();
+                error!(error = %err, "tower service failed");
+                Response::new(HTTPCodes::InternalServerError)
+                    .header("Content-Type", "text/plain; charset=utf-8")
+                    .body("Internal Server Error")
+            })
+        })
+    }
+}
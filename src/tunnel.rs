@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use tokio::io::copy_bidirectional;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+use crate::codes::HTTPCodes;
+use crate::response::{OnUpgrade, Response};
+
+/// Answers a `CONNECT` request for `target`, a `host:port` authority.
+///
+/// Only targets listed in `allowed` are dialled, so the server cannot be
+/// used as an open proxy; with none listed `CONNECT` is refused with
+/// `405 Method Not Allowed`. Once the target accepts within `wait`, the
+/// response is `200 Connection Established` and the connection becomes a
+/// tunnel to it, piping bytes both ways until either side closes.
+pub(crate) async fn connect_tunnel(target: &str, allowed: &[String], wait: Duration) -> Response {
+    if allowed.is_empty() {
+        return Response::new(HTTPCodes::MethodNotAllowed).keep_alive(false);
+    }
+    let valid = target
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+    if !valid {
+        return Response::new(HTTPCodes::BadRequest).keep_alive(false);
+    }
+    if !allowed
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(target))
+    {
+        warn!(connect_target = target, "refused CONNECT target");
+        return Response::new(HTTPCodes::Forbidden).keep_alive(false);
+    }
+
+    let mut upstream = match timeout(wait, TcpStream::connect(target)).await {
+        Ok(Ok(upstream)) => upstream,
+        Ok(Err(err)) => {
+            warn!(connect_target = target, error = %err, "CONNECT target unreachable");
+            return Response::new(HTTPCodes::BadGateway).keep_alive(false);
+        }
+        Err(_) => return Response::new(HTTPCodes::GatewayTimeout).keep_alive(false),
+    };
+    let mut response = Response::new(HTTPCodes::OK);
+    response.reason = Some("Connection Established");
+    response.upgrade = Some(OnUpgrade::new(move |reader, writer, _limit| {
+        Box::pin(async move {
+            let mut client = tokio::io::join(reader, writer);
+            let copied = copy_bidirectional(&mut client, &mut upstream).await;
+            if let Ok((sent, received)) = copied {
+                debug!(sent, received, "tunnel closed");
+            }
+        })
+    }));
+    response
+}
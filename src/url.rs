@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+
+/// Decodes `%XX` escape sequences. Returns `None` when a `%` is not
+/// followed by two hex digits or the result is not valid UTF-8.
+pub fn percent_decode(input: &str) -> Option<String> {
+    decode(input, &[])
+}
+
+/// Decodes a request path. `%2F` stays encoded so that an escaped slash is
+/// not mistaken for a segment separator when routing.
+pub(crate) fn decode_path(path: &str) -> Option<String> {
+    decode(path, b"/")
+}
+
+fn decode(input: &str, keep_encoded: &[u8]) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            decoded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        let hex = bytes.get(i + 1..i + 3)?;
+        if !hex.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+        let byte = u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?;
+        if keep_encoded.contains(&byte) {
+            decoded.extend_from_slice(format!("%{byte:02X}").as_bytes());
+        } else {
+            decoded.push(byte);
+        }
+        i += 3;
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Like `percent_decode`, but also decodes `+` as a space, as used by
+/// query strings and `application/x-www-form-urlencoded` bodies.
+pub(crate) fn form_decode(input: &str) -> Option<String> {
+    percent_decode(&input.replace('+', " "))
+}
+
+/// Splits `a=1&b=2&b=3` into decoded pairs, keeping every value of a
+/// repeated key. Pairs without `=` get an empty value.
+pub(crate) fn parse_query(query: &str) -> Option<HashMap<String, Vec<String>>> {
+    let mut pairs: HashMap<String, Vec<String>> = HashMap::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        pairs
+            .entry(form_decode(key)?)
+            .or_default()
+            .push(form_decode(value)?);
+    }
+    Some(pairs)
+}
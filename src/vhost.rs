@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::codes::HTTPCodes;
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::{BoxFuture, Handler, Router};
+
+/// Dispatches requests to a sub-router by their `Host` header, for serving
+/// several domains from one listener. Host names match case-insensitively
+/// and any port is ignored. Requests for unknown hosts, or without a
+/// `Host`, get `421 Misdirected Request`.
+///
+/// Turn it into a `Router` to hand it to `ServerBuilder::router`.
+#[derive(Default)]
+pub struct VHostRouter {
+    hosts: HashMap<String, Arc<Router>>,
+}
+
+impl VHostRouter {
+    pub fn new() -> VHostRouter {
+        VHostRouter::default()
+    }
+
+    /// Routes requests for `host`, e.g. `"api.example.com"`, to `router`.
+    pub fn host(mut self, host: &str, router: Router) -> Self {
+        self.hosts
+            .insert(host.to_ascii_lowercase(), Arc::new(router));
+        self
+    }
+}
+
+impl Handler for VHostRouter {
+    fn call(&self, request: Request) -> BoxFuture<Response> {
+        let router = request
+            .host()
+            .and_then(|host| self.hosts.get(&host.to_ascii_lowercase()).cloned());
+        Box::pin(async move {
+            match router {
+                Some(router) => router.handle(request).await,
+                None => Response::new(HTTPCodes::MisdirectedRequest),
+            }
+        })
+    }
+}
+
+impl From<VHostRouter> for Router {
+    /// A router sending every request, whatever its method and path, to
+    /// the virtual hosts.
+    fn from(vhost: VHostRouter) -> Router {
+        let mut router = Router::new();
+        router
+            .any("/*path", vhost)
+            .expect("a trailing wildcard is a valid pattern");
+        router
+    }
+}
@@ -0,0 +1,294 @@
+use std::future::Future;
+use std::io;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::body::Reader;
+use crate::codes::HTTPCodes;
+use crate::request::Request;
+use crate::response::{OnUpgrade, Response};
+
+/// Appended to `Sec-WebSocket-Key` before hashing, see RFC 6455 section 4.2.2.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// Answers a WebSocket handshake request.
+///
+/// When `request` is a valid HTTP/1.1 upgrade request, returns the
+/// `101 Switching Protocols` response; once the connection has written it,
+/// `on_upgrade` runs with the connection wrapped in a `WebSocket`. Invalid
+/// handshakes get `400 Bad Request`, or `426 Upgrade Required` for an
+/// unsupported `Sec-WebSocket-Version`.
+pub fn upgrade_websocket<F, Fut>(request: &Request, on_upgrade: F) -> Response
+where
+    F: FnOnce(WebSocket) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let headers = &request.headers;
+    let upgrade = request.upgrade().is_some_and(|upgrade| {
+        upgrade
+            .split(',')
+            .any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket"))
+    });
+    if request.method != "GET" || request.version != "HTTP/1.1" || !upgrade {
+        return Response::new(HTTPCodes::BadRequest);
+    }
+    if headers.get("Sec-WebSocket-Version") != Some("13") {
+        return Response::new(HTTPCodes::UpgradeRequired).header("Sec-WebSocket-Version", "13");
+    }
+    // The key must be 16 random bytes, base64 encoded.
+    let Some(key) = headers
+        .get("Sec-WebSocket-Key")
+        .map(str::trim)
+        .filter(|key| BASE64.decode(key).is_ok_and(|key| key.len() == 16))
+    else {
+        return Response::new(HTTPCodes::BadRequest);
+    };
+
+    let accept = BASE64.encode(Sha1::digest(format!("{key}{ACCEPT_GUID}")));
+    let mut response = Response::new(HTTPCodes::SwitchingProtocols)
+        .header("Upgrade", "websocket")
+        .header("Sec-WebSocket-Accept", &accept);
+    response.upgrade = Some(OnUpgrade::new(move |reader, writer, limit| {
+        Box::pin(on_upgrade(WebSocket::new(reader, writer, limit)))
+    }));
+    response
+}
+
+/// A complete data message received from the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WsMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Why reading a message stopped.
+enum ReadError {
+    /// The connection failed or closed without a close frame.
+    Io,
+    /// The client violated the protocol and is sent a close frame with this
+    /// status code.
+    Close(u16),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(_: io::Error) -> ReadError {
+        ReadError::Io
+    }
+}
+
+/// The status code of a received close frame, `CLOSE_NORMAL` when it has
+/// none. Codes reserved for local use (1005, 1006, 1015) or not assigned,
+/// and reasons that are not UTF-8, are protocol errors.
+fn close_code(payload: &[u8]) -> Result<u16, ReadError> {
+    let code = match payload {
+        [] => return Ok(CLOSE_NORMAL),
+        [high, low, reason @ ..] if std::str::from_utf8(reason).is_ok() => {
+            u16::from_be_bytes([*high, *low])
+        }
+        _ => return Err(ReadError::Close(CLOSE_PROTOCOL_ERROR)),
+    };
+    match code {
+        1000..=1003 | 1007..=1014 | 3000..=4999 => Ok(code),
+        _ => Err(ReadError::Close(CLOSE_PROTOCOL_ERROR)),
+    }
+}
+
+/// Server side of an established WebSocket connection.
+pub struct WebSocket {
+    reader: Reader,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+    /// Largest message accepted, in bytes, summed over all fragments.
+    max_message_size: usize,
+    closed: bool,
+}
+
+impl WebSocket {
+    pub(crate) fn new(
+        reader: Reader,
+        writer: Box<dyn AsyncWrite + Send + Unpin>,
+        max_message_size: usize,
+    ) -> WebSocket {
+        WebSocket {
+            reader,
+            writer,
+            max_message_size,
+            closed: false,
+        }
+    }
+
+    pub async fn send_text(&mut self, msg: &str) -> io::Result<()> {
+        self.write_frame(OP_TEXT, msg.as_bytes()).await
+    }
+
+    pub async fn send_binary(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_frame(OP_BINARY, data).await
+    }
+
+    /// Starts the closing handshake. `next_message` returns `None` once the
+    /// client has answered.
+    pub async fn close(&mut self) -> io::Result<()> {
+        self.send_close(CLOSE_NORMAL).await
+    }
+
+    /// Waits for the next text or binary message, reassembling fragmented
+    /// ones. Pings are answered automatically. Returns `None` once the
+    /// connection is closed, either by the client or after a protocol error.
+    pub async fn next_message(&mut self) -> Option<WsMessage> {
+        match self.read_message().await {
+            Ok(message) => message,
+            Err(ReadError::Io) => {
+                self.closed = true;
+                None
+            }
+            Err(ReadError::Close(code)) => {
+                let _ = self.send_close(code).await;
+                None
+            }
+        }
+    }
+
+    async fn read_message(&mut self) -> Result<Option<WsMessage>, ReadError> {
+        // Opcode and payload so far of a fragmented message.
+        let mut partial: Option<(u8, Vec<u8>)> = None;
+        loop {
+            let frame = self.read_frame().await?;
+            match frame.opcode {
+                OP_PING => {
+                    self.write_frame(OP_PONG, &frame.payload).await?;
+                    continue;
+                }
+                OP_PONG => continue,
+                OP_CLOSE => {
+                    let code = close_code(&frame.payload)?;
+                    if !self.closed {
+                        // Echo the client's status code back.
+                        self.send_close(code).await?;
+                    }
+                    return Ok(None);
+                }
+                _ => {}
+            }
+
+            let (opcode, payload) = match (frame.opcode, partial.take()) {
+                (OP_CONTINUATION, Some((opcode, mut payload))) => {
+                    payload.extend_from_slice(&frame.payload);
+                    (opcode, payload)
+                }
+                (OP_TEXT | OP_BINARY, None) => (frame.opcode, frame.payload),
+                _ => return Err(ReadError::Close(CLOSE_PROTOCOL_ERROR)),
+            };
+            if payload.len() > self.max_message_size {
+                return Err(ReadError::Close(CLOSE_TOO_BIG));
+            }
+            if !frame.fin {
+                partial = Some((opcode, payload));
+                continue;
+            }
+
+            if opcode == OP_BINARY {
+                return Ok(Some(WsMessage::Binary(payload)));
+            }
+            return match String::from_utf8(payload) {
+                Ok(text) => Ok(Some(WsMessage::Text(text))),
+                Err(_) => Err(ReadError::Close(CLOSE_INVALID_DATA)),
+            };
+        }
+    }
+
+    async fn read_frame(&mut self) -> Result<Frame, ReadError> {
+        let mut head = [0; 2];
+        self.reader.read_exact(&mut head).await?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        let masked = head[1] & 0x80 != 0;
+
+        // No extensions are negotiated, so the reserved bits must be clear,
+        // and clients must mask every frame.
+        if head[0] & 0x70 != 0 || !masked {
+            return Err(ReadError::Close(CLOSE_PROTOCOL_ERROR));
+        }
+
+        let length = match head[1] & 0x7F {
+            126 => u64::from(self.reader.read_u16().await?),
+            // The most significant bit of a 64-bit length must be clear.
+            127 => match self.reader.read_u64().await? {
+                length if length >> 63 == 0 => length,
+                _ => return Err(ReadError::Close(CLOSE_PROTOCOL_ERROR)),
+            },
+            length => u64::from(length),
+        };
+        if opcode & 0x8 != 0 && (length > 125 || !fin) {
+            // Control frames are small and never fragmented.
+            return Err(ReadError::Close(CLOSE_PROTOCOL_ERROR));
+        }
+        let length = usize::try_from(length)
+            .ok()
+            .filter(|length| *length <= self.max_message_size)
+            .ok_or(ReadError::Close(CLOSE_TOO_BIG))?;
+
+        let mut mask = [0; 4];
+        self.reader.read_exact(&mut mask).await?;
+        let mut payload = vec![0; length];
+        self.reader.read_exact(&mut payload).await?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        })
+    }
+
+    async fn send_close(&mut self, code: u16) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.write_frame(OP_CLOSE, &code.to_be_bytes()).await?;
+        self.closed = true;
+        Ok(())
+    }
+
+    /// Writes one unfragmented, unmasked frame.
+    async fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if self.closed {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        let mut head = vec![0x80 | opcode];
+        match payload.len() {
+            length @ 0..=125 => head.push(length as u8),
+            length @ 126..=0xFFFF => {
+                head.push(126);
+                head.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                head.push(127);
+                head.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        self.writer.write_all(&head).await?;
+        self.writer.write_all(payload).await?;
+        self.writer.flush().await
+    }
+}